@@ -1,8 +1,7 @@
-use core::num;
 use std::collections::HashMap;
 
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use hamster::HAMT;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use hamster::{Config, HamtConfig, NodePool, SyncHAMT, HAMT};
 
 fn setup_big_map() -> (i32, HAMT<i32, i32>) {
     let num_keys = 10000;
@@ -48,10 +47,112 @@ fn big_remove_std() {
     }
 }
 
+fn big_insert_get_config<C: HamtConfig>() {
+    let num_keys = 10000;
+    let mut map: HAMT<i32, i32, C> = HAMT::with_config();
+    for k in 1..num_keys {
+        map = map.insert(k, -k);
+    }
+    for k in 1..num_keys {
+        assert_eq!(map.get(k), Some(&-k));
+    }
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("big remove", |b| b.iter(|| black_box(big_remove())));
-    c.bench_function("big remove std", |b| b.iter(|| black_box(big_remove_std())));
+    c.bench_function("big remove", |b| b.iter(big_remove));
+    c.bench_function("big remove std", |b| b.iter(big_remove_std));
+}
+
+fn config_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("config insert get");
+    group.bench_function("4 bits u64", |b| b.iter(big_insert_get_config::<Config<4>>));
+    group.bench_function("5 bits u64", |b| b.iter(big_insert_get_config::<Config<5>>));
+    group.bench_function("6 bits u64", |b| b.iter(big_insert_get_config::<Config<6>>));
+    group.bench_function("4 bits u128", |b| b.iter(big_insert_get_config::<Config<4, u128>>));
+    group.bench_function("5 bits u128", |b| b.iter(big_insert_get_config::<Config<5, u128>>));
+    group.bench_function("6 bits u128", |b| b.iter(big_insert_get_config::<Config<6, u128>>));
+    group.finish();
+}
+
+fn get_many_benchmark(c: &mut Criterion) {
+    let num_keys = 100000;
+    let mut map = HAMT::new();
+    for k in 0..num_keys {
+        map = map.insert(k, -k);
+    }
+    // Look up keys spread across the whole map, so most lookups miss the cache.
+    let keys: Vec<i32> = (0..500).map(|i| (i * 7919) % num_keys).collect();
+    let key_refs: Vec<&i32> = keys.iter().collect();
+
+    let mut group = c.benchmark_group("500 lookups");
+    group.bench_function("get", |b| {
+        b.iter(|| keys.iter().map(|k| map.get(*k)).collect::<Vec<_>>())
+    });
+    group.bench_function("get_many", |b| b.iter(|| map.get_many(black_box(&key_refs))));
+    group.bench_function("get_many_iter", |b| {
+        b.iter(|| map.get_many_iter(black_box(&keys)).collect::<Vec<_>>())
+    });
+    group.finish();
+}
+
+/// Overwrite keys of a map, dropping each previous version right away.
+fn churn() {
+    let mut map = HAMT::new();
+    for k in 0..20000 {
+        map = map.insert(k % 2000, k);
+    }
+}
+
+fn pool_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert churn");
+    group.bench_function("global allocator", |b| b.iter(churn));
+    group.bench_function("node pool", |b| {
+        let _pool = NodePool::install(256);
+        b.iter(churn);
+    });
+    group.finish();
+
+    // Dropping a map frees its nodes, which checks for a pool even when none is installed.
+    let mut group = c.benchmark_group("drop 10000 entries");
+    group.bench_function("global allocator", |b| {
+        b.iter_batched(|| setup_big_map().1, drop, BatchSize::LargeInput)
+    });
+    group.bench_function("node pool", |b| {
+        let _pool = NodePool::install(256);
+        b.iter_batched(|| setup_big_map().1, drop, BatchSize::LargeInput)
+    });
+    group.finish();
+}
+
+fn parallel_benchmark(c: &mut Criterion) {
+    let items: Vec<(i32, i32)> = (0..200000).map(|k| (k, -k)).collect();
+    let map = SyncHAMT::par_from_iter(items.iter().copied());
+
+    let mut group = c.benchmark_group("200000 entries");
+    group.sample_size(10);
+    group.bench_function("insert", |b| {
+        b.iter(|| {
+            items
+                .iter()
+                .fold(SyncHAMT::with_config(), |map, (k, v)| map.insert(*k, *v))
+        })
+    });
+    group.bench_function("par_from_iter", |b| {
+        b.iter(|| SyncHAMT::par_from_iter(items.iter().copied()))
+    });
+    group.bench_function("map values", |b| {
+        b.iter(|| map.iter().fold(SyncHAMT::with_config(), |new, (k, v)| new.insert(*k, v * 2)))
+    });
+    group.bench_function("par_map_values", |b| b.iter(|| map.par_map_values(|_, v| v * 2)));
+    group.finish();
 }
 
-criterion_group!(benches, criterion_benchmark);
+criterion_group!(
+    benches,
+    criterion_benchmark,
+    config_benchmark,
+    get_many_benchmark,
+    pool_benchmark,
+    parallel_benchmark
+);
 criterion_main!(benches);
@@ -0,0 +1,199 @@
+//! Batched lookups.
+//!
+//! Looking up many keys one at a time walks the trie once per key, and each step down
+//! the trie is likely to stall on a cache miss. Here all the keys are hashed first, and then
+//! the lookups are advanced together one level at a time, prefetching the next node of
+//! every lookup before any of them is visited.
+
+use std::borrow::Borrow;
+use std::collections::VecDeque;
+use std::hash::Hash;
+
+use crate::{fragment, get_entries_index, hash_key, next_hash, Bitmap, HAMTNode, HAMTNodeEntry, HamtConfig, HAMT};
+
+/// Number of keys resolved together by [`GetMany`](GetMany).
+const GET_MANY_BATCH: usize = 32;
+
+/// Hint to the CPU that the memory at `ptr` will be read soon.
+/// On architectures without a stable prefetch instruction this does nothing.
+#[inline(always)]
+fn prefetch<T>(ptr: *const T) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        _mm_prefetch::<_MM_HINT_T0>(ptr as *const i8);
+    }
+    #[cfg(target_arch = "x86")]
+    unsafe {
+        use std::arch::x86::{_mm_prefetch, _MM_HINT_T0};
+        _mm_prefetch::<_MM_HINT_T0>(ptr as *const i8);
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+    let _ = ptr;
+}
+
+/// An in-progress lookup: the node it has reached, and its hash shifted to that node's level.
+struct Lookup<'a, K, V, C: HamtConfig> {
+    index: usize,
+    node: &'a HAMTNode<K, V, C>,
+    cur_hashed_key: C::Hash,
+}
+
+/// Resolve all of `keys` against the trie rooted at `root`, writing the result for `keys[i]` to `results[i]`.
+fn resolve_batch<'a, K, V, C, Q>(root: &'a HAMTNode<K, V, C>, keys: &[&Q], results: &mut [Option<&'a V>])
+where
+    K: Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+    C: HamtConfig,
+{
+    // Hash every key before touching the trie.
+    let mut active: Vec<Lookup<'a, K, V, C>> = keys
+        .iter()
+        .enumerate()
+        .map(|(index, key)| Lookup {
+            index,
+            node: root,
+            cur_hashed_key: hash_key::<C, Q>(key),
+        })
+        .collect();
+
+    // Each round advances every unresolved lookup by one level.
+    while !active.is_empty() {
+        active.retain_mut(|lookup| {
+            let node = lookup.node;
+            let frag = fragment::<C>(lookup.cur_hashed_key);
+            if !node.presence_map.contains(frag) {
+                return false;
+            }
+            let key = keys[lookup.index];
+            match &node.entries[get_entries_index(node.presence_map, frag)] {
+                HAMTNodeEntry::Value(k, v, _) => {
+                    if k.borrow() == key {
+                        results[lookup.index] = Some(v);
+                    }
+                    false
+                }
+                HAMTNodeEntry::Chained(vec) => {
+                    results[lookup.index] = vec.iter().find(|(k, _)| k.borrow() == key).map(|(_, v)| v);
+                    false
+                }
+                HAMTNodeEntry::Node(child) => {
+                    // Start loading the child now; it will be read in the next round,
+                    // after the other lookups have been advanced.
+                    prefetch(&**child);
+                    lookup.node = child;
+                    lookup.cur_hashed_key = next_hash::<C>(lookup.cur_hashed_key);
+                    true
+                }
+            }
+        });
+    }
+}
+
+impl<K, V, C> HAMT<K, V, C>
+where
+    K: Eq + Hash,
+    C: HamtConfig,
+{
+    /// Get the values stored at each of the given keys, in the same order.
+    /// This gives the same results as calling [`get`](HAMT::get) for each key,
+    /// but the lookups are interleaved to hide memory latency.
+    pub fn get_many<Q>(&self, keys: &[&Q]) -> Vec<Option<&V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut results = vec![None; keys.len()];
+        resolve_batch(&self.root, keys, &mut results);
+        results
+    }
+
+    /// Lazily get the values stored at each key of an iterator, in the same order.
+    /// Keys are taken from the iterator and resolved in batches, like [`get_many`](HAMT::get_many).
+    pub fn get_many_iter<'a, 'q, Q, I>(&'a self, keys: I) -> GetMany<'a, 'q, K, V, C, Q, I::IntoIter>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized + 'q,
+        I: IntoIterator<Item = &'q Q>,
+    {
+        GetMany {
+            map: self,
+            keys: keys.into_iter(),
+            batch: Vec::with_capacity(GET_MANY_BATCH),
+            results: VecDeque::with_capacity(GET_MANY_BATCH),
+        }
+    }
+}
+
+/// Iterator returned by [`HAMT::get_many_iter`](HAMT::get_many_iter).
+pub struct GetMany<'a, 'q, K, V, C: HamtConfig, Q: ?Sized, I> {
+    map: &'a HAMT<K, V, C>,
+    keys: I,
+    batch: Vec<&'q Q>,
+    results: VecDeque<Option<&'a V>>,
+}
+
+impl<'a, 'q, K, V, C, Q, I> Iterator for GetMany<'a, 'q, K, V, C, Q, I>
+where
+    K: Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+    C: HamtConfig,
+    I: Iterator<Item = &'q Q>,
+{
+    type Item = Option<&'a V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.results.is_empty() {
+            self.batch.clear();
+            self.batch.extend(self.keys.by_ref().take(GET_MANY_BATCH));
+            if self.batch.is_empty() {
+                return None;
+            }
+            let mut results = vec![None; self.batch.len()];
+            resolve_batch(&self.map.root, &self.batch, &mut results);
+            self.results.extend(results);
+        }
+        self.results.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, HAMT};
+
+    #[test]
+    fn get_many_matches_get() {
+        let mut map = HAMT::new();
+        for k in 0..5000 {
+            map = map.insert(k, k * 2);
+        }
+        let keys: Vec<i32> = (-100..5100).collect();
+        let key_refs: Vec<&i32> = keys.iter().collect();
+        let results = map.get_many(&key_refs);
+        assert_eq!(results.len(), keys.len());
+        for (k, result) in keys.iter().zip(results) {
+            assert_eq!(result, map.get(*k));
+        }
+    }
+
+    #[test]
+    fn get_many_iter_matches_get() {
+        let mut map: HAMT<i32, i32, Config<4, u128>> = HAMT::with_config();
+        for k in 0..1000 {
+            map = map.insert(k, -k);
+        }
+        let keys: Vec<i32> = (0..1100).rev().collect();
+        let results: Vec<Option<&i32>> = map.get_many_iter(&keys).collect();
+        assert_eq!(results.len(), keys.len());
+        for (k, result) in keys.iter().zip(results) {
+            assert_eq!(result, map.get(*k));
+        }
+    }
+
+    #[test]
+    fn get_many_borrowed() {
+        let map = HAMT::from([(String::from("a"), 1), (String::from("b"), 2)]);
+        assert_eq!(map.get_many(&["b", "c", "a"]), vec![Some(&2), None, Some(&1)]);
+        assert_eq!(map.get_many::<str>(&[]), Vec::<Option<&i32>>::new());
+    }
+}
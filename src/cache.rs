@@ -0,0 +1,88 @@
+//! Values computed for nodes, remembered for as long as the nodes live.
+//!
+//! Nodes never change, so anything computed from a node stays valid for its whole life, and can be
+//! kept next to the trie instead of in it. A [`NodeCache`] keys values by node address, and holds a
+//! weak pointer to each node, so it does not keep nodes alive.
+
+use std::collections::HashMap;
+
+use crate::config::WeakNode;
+use crate::{HamtConfig, NodePtr, SharedPointer};
+
+/// Values remembered for nodes, by node address.
+pub(crate) struct NodeCache<K, V, C: HamtConfig, T> {
+    entries: HashMap<usize, (WeakNode<K, V, C>, T)>,
+    /// Number of entries after dropped nodes were last forgotten.
+    live: usize,
+}
+
+impl<K, V, C: HamtConfig, T> NodeCache<K, V, C, T> {
+    pub(crate) fn new() -> Self {
+        NodeCache {
+            entries: HashMap::new(),
+            live: 0,
+        }
+    }
+
+    /// The value remembered for `node`, if any.
+    pub(crate) fn get(&self, node: &NodePtr<K, V, C>) -> Option<&T> {
+        // The weak pointer keeps the allocation, so a remembered address is never reused by another node.
+        self.entries.get(&(node.as_ptr() as usize)).map(|(_, value)| value)
+    }
+
+    /// Remember `value` for `node`. Dropped nodes are forgotten once they make up half of the cache,
+    /// so the cache stays proportional to the nodes alive.
+    pub(crate) fn insert(&mut self, node: &NodePtr<K, V, C>, value: T) {
+        self.entries.insert(node.as_ptr() as usize, (C::Pointer::downgrade(&node.0), value));
+        if self.entries.len() > 2 * self.live + 64 {
+            self.purge();
+        }
+    }
+
+    /// Number of remembered nodes that are still in use by some map.
+    pub(crate) fn len(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|(node, _)| C::Pointer::strong_count(node) > 0)
+            .count()
+    }
+
+    /// Forget the nodes that are no longer used by any map.
+    pub(crate) fn purge(&mut self) {
+        self.entries.retain(|_, (node, _)| C::Pointer::strong_count(node) > 0);
+        self.live = self.entries.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NodeCache;
+    use crate::test_util::build;
+    use crate::{DefaultConfig, HAMT};
+
+    #[test]
+    fn remembers_live_nodes() {
+        let map = build(0..1000);
+        let mut cache = NodeCache::<i32, i32, DefaultConfig, usize>::new();
+        cache.insert(&map.root, 1000);
+        assert_eq!(cache.get(&map.root), Some(&1000));
+        assert_eq!(cache.get(&map.insert(0, 1).root), None);
+        drop(map);
+        assert_eq!(cache.len(), 0);
+        cache.purge();
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn forgets_dropped_nodes() {
+        let mut cache = NodeCache::<i32, i32, DefaultConfig, ()>::new();
+        let mut map = HAMT::new();
+        for k in 0..10_000 {
+            map = map.insert(k, k);
+            cache.insert(&map.root, ());
+        }
+        // Only the last root is alive, and the cache was purged along the way.
+        assert_eq!(cache.len(), 1);
+        assert!(cache.entries.len() < 200);
+    }
+}
@@ -0,0 +1,315 @@
+//! A shared, atomically updatable reference to the current version of a map.
+//!
+//! A [`HamtCell`] holds a [`SyncHAMT`](crate::SyncHAMT) (or any map whose nodes are shared through
+//! `Arc`). Readers take the current version with [`load`](HamtCell::load), which only clones the
+//! root pointer, and keep working with it while writers publish new versions.
+//! Writers replace the version with [`store`](HamtCell::store), or with a compare-and-swap on the
+//! root pointer, which [`update`](HamtCell::update) retries until it succeeds.
+//!
+//! Every published version gets a number. [`Subscriber`]s remember the last version they saw,
+//! so they can wait for the next one and list what changed since.
+
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::diff::Change;
+use crate::epoch::{pin, AtomicArc};
+use crate::{ArcPointer, HamtConfig, SyncConfig, HAMT};
+
+/// A published version of the map.
+struct Version<K, V, C: HamtConfig> {
+    number: u64,
+    map: HAMT<K, V, C>,
+}
+
+/// A shared reference to the current version of a map, that can be replaced atomically.
+pub struct HamtCell<K, V, C: HamtConfig<Pointer = ArcPointer> = SyncConfig> {
+    current: AtomicArc<Version<K, V, C>>,
+    // Subscribers wait on the condition variable for a new version to be published.
+    // Publishing notifies while holding the lock, so that a subscriber cannot miss it.
+    published: Mutex<()>,
+    changed: Condvar,
+}
+
+/// Follows the versions published in a [`HamtCell`](HamtCell), returned by
+/// [`HamtCell::subscribe`](HamtCell::subscribe).
+pub struct Subscriber<'a, K, V, C: HamtConfig<Pointer = ArcPointer> = SyncConfig> {
+    cell: &'a HamtCell<K, V, C>,
+    version: u64,
+    map: HAMT<K, V, C>,
+}
+
+/// The versions a [`Subscriber`](Subscriber) moved between.
+/// Versions published in between are skipped.
+pub struct Update<K, V, C: HamtConfig = SyncConfig> {
+    /// Number of the version the subscriber saw last.
+    pub old_version: u64,
+    /// The version the subscriber saw last.
+    pub old: HAMT<K, V, C>,
+    /// Number of the current version.
+    pub version: u64,
+    /// The current version.
+    pub new: HAMT<K, V, C>,
+}
+
+impl<K, V, C> HamtCell<K, V, C>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+    C: HamtConfig<Pointer = ArcPointer>,
+{
+    /// Construct a cell holding `map` as version 0.
+    pub fn new(map: HAMT<K, V, C>) -> Self {
+        HamtCell {
+            current: AtomicArc::new(Some(Arc::new(Version { number: 0, map }))),
+            published: Mutex::new(()),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn current(&self) -> Arc<Version<K, V, C>> {
+        self.current.load(&pin()).unwrap()
+    }
+
+    /// Get the current version of the map. This does not copy the map.
+    pub fn load(&self) -> HAMT<K, V, C> {
+        self.current().map.clone()
+    }
+
+    /// Number of the current version. It is incremented by every successful `store`, swap and update.
+    pub fn version(&self) -> u64 {
+        self.current().number
+    }
+
+    /// Publish `map` as the new version.
+    pub fn store(&self, map: HAMT<K, V, C>) {
+        let mut version = Arc::new(Version { number: 0, map });
+        let guard = pin();
+        loop {
+            let current = self.current.load(&guard).unwrap();
+            Arc::get_mut(&mut version).unwrap().number = current.number + 1;
+            match self.current.compare_exchange(Some(&current), Some(version), &guard) {
+                Ok(()) => break,
+                Err(rejected) => version = rejected.unwrap(),
+            }
+        }
+        drop(guard);
+        self.notify();
+    }
+
+    /// Publish `new` if the current version is still `expected`, i.e. shares its root node.
+    /// On failure, the current version is returned.
+    pub fn compare_and_swap(&self, expected: &HAMT<K, V, C>, new: HAMT<K, V, C>) -> Result<(), HAMT<K, V, C>> {
+        let mut version = Arc::new(Version { number: 0, map: new });
+        let guard = pin();
+        loop {
+            let current = self.current.load(&guard).unwrap();
+            if !current.map.ptr_eq(expected) {
+                return Err(current.map.clone());
+            }
+            Arc::get_mut(&mut version).unwrap().number = current.number + 1;
+            // Another writer may have published the same root again in the meantime, so retry
+            // rather than fail if the version itself changed.
+            match self.current.compare_exchange(Some(&current), Some(version), &guard) {
+                Ok(()) => break,
+                Err(rejected) => version = rejected.unwrap(),
+            }
+        }
+        drop(guard);
+        self.notify();
+        Ok(())
+    }
+
+    /// Publish the map `f` computes from the current version, and return it.
+    /// If another version is published concurrently, `f` is called again on that version.
+    pub fn update<F: FnMut(&HAMT<K, V, C>) -> HAMT<K, V, C>>(&self, mut f: F) -> HAMT<K, V, C> {
+        let mut current = self.load();
+        loop {
+            let new = f(&current);
+            match self.compare_and_swap(&current, new.clone()) {
+                Ok(()) => return new,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Follow the versions published from now on.
+    pub fn subscribe(&self) -> Subscriber<'_, K, V, C> {
+        let current = self.current();
+        Subscriber {
+            cell: self,
+            version: current.number,
+            map: current.map.clone(),
+        }
+    }
+
+    fn notify(&self) {
+        let _lock = self.published.lock().unwrap_or_else(|e| e.into_inner());
+        self.changed.notify_all();
+    }
+}
+
+impl<'a, K, V, C> Subscriber<'a, K, V, C>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+    C: HamtConfig<Pointer = ArcPointer>,
+{
+    /// Number of the version seen last.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// The version seen last.
+    pub fn map(&self) -> &HAMT<K, V, C> {
+        &self.map
+    }
+
+    /// Move to the current version, if it is newer than the version seen last.
+    pub fn poll(&mut self) -> Option<Update<K, V, C>> {
+        let current = self.cell.current();
+        if current.number == self.version {
+            return None;
+        }
+        let update = Update {
+            old_version: self.version,
+            old: std::mem::replace(&mut self.map, current.map.clone()),
+            version: current.number,
+            new: current.map.clone(),
+        };
+        self.version = current.number;
+        Some(update)
+    }
+
+    /// Wait until a version newer than the one seen last is published, and move to it.
+    pub fn wait(&mut self) -> Update<K, V, C> {
+        let mut lock = self.cell.published.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(update) = self.poll() {
+                return update;
+            }
+            lock = self.cell.changed.wait(lock).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Like [`wait`](Subscriber::wait), but give up and return `None` after `timeout`.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Option<Update<K, V, C>> {
+        let deadline = Instant::now() + timeout;
+        let mut lock = self.cell.published.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(update) = self.poll() {
+                return Some(update);
+            }
+            let remaining = deadline.checked_duration_since(Instant::now())?;
+            lock = self
+                .cell
+                .changed
+                .wait_timeout(lock, remaining)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+}
+
+impl<K: Hash + Eq, V: PartialEq, C: HamtConfig> Update<K, V, C> {
+    /// List the changes between the two versions, see [`HAMT::diff`](HAMT::diff).
+    pub fn changes(&self) -> Vec<Change<'_, K, V>> {
+        self.old.diff(&self.new)
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, C: HamtConfig<Pointer = ArcPointer>> fmt::Debug for HamtCell<K, V, C>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let current = self.current();
+        f.debug_struct("HamtCell")
+            .field("version", &current.number)
+            .field("map", &current.map)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HamtCell;
+    use crate::diff::Change;
+    use crate::SyncHAMT;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn load_store_and_swap() {
+        let cell = HamtCell::new(SyncHAMT::with_config().insert(1, 1));
+        let first = cell.load();
+        assert!(first.ptr_eq(&cell.load()));
+
+        cell.store(first.insert(2, 2));
+        assert_eq!(cell.version(), 1);
+        assert_eq!(cell.load().get(2), Some(&2));
+
+        // The cell no longer holds `first`, so swapping from it fails and returns what it holds.
+        let current = cell.compare_and_swap(&first, first.insert(3, 3)).unwrap_err();
+        assert!(current.ptr_eq(&cell.load()));
+        // An equal map with a different root is not the expected version either.
+        assert!(cell.compare_and_swap(&first.insert(2, 2), SyncHAMT::with_config()).is_err());
+        assert!(cell.compare_and_swap(&current, current.remove(1)).is_ok());
+        assert_eq!(cell.version(), 2);
+        assert!(!cell.load().contains_key(1));
+    }
+
+    #[test]
+    fn concurrent_updates() {
+        let cell = HamtCell::new(SyncHAMT::with_config());
+        thread::scope(|s| {
+            for t in 0..8 {
+                let cell = &cell;
+                s.spawn(move || {
+                    for i in 0..200 {
+                        cell.update(|map| map.insert(t * 200 + i, t));
+                    }
+                });
+            }
+        });
+        let map = cell.load();
+        assert_eq!(cell.version(), 1600);
+        for k in 0..1600 {
+            assert_eq!(map.get(k), Some(&(k / 200)));
+        }
+    }
+
+    #[test]
+    fn subscribers_see_new_versions() {
+        let cell = HamtCell::new(SyncHAMT::with_config());
+        let mut subscriber = cell.subscribe();
+        assert!(subscriber.poll().is_none());
+        assert!(subscriber.wait_timeout(Duration::from_millis(10)).is_none());
+
+        thread::scope(|s| {
+            let cell = &cell;
+            s.spawn(move || {
+                for k in 0..100 {
+                    cell.update(|map| map.insert(k, k));
+                }
+            });
+            // Replaying the changes of every update rebuilds the map.
+            let mut replayed = SyncHAMT::with_config();
+            while subscriber.version() < 100 {
+                let update = subscriber.wait();
+                assert!(update.version > update.old_version);
+                for change in update.changes() {
+                    match change {
+                        Change::Added(k, v) => replayed = replayed.insert(*k, *v),
+                        change => panic!("unexpected change {:?}", change),
+                    }
+                }
+                assert_eq!(replayed, update.new);
+            }
+        });
+        assert!(subscriber.map().ptr_eq(&cell.load()));
+    }
+}
@@ -0,0 +1,227 @@
+//! Binary encoding of keys and values, for the formats that write maps out.
+
+use std::error::Error;
+use std::fmt;
+
+/// How a type is written to and read from bytes.
+///
+/// Implementations are provided for integers, `bool`, `()`, `String`, and for `Vec`, `Option` and
+/// pairs of encodable types. Decoding must consume exactly the bytes encoding wrote.
+pub trait Codec: Sized {
+    /// Append the encoding of `self` to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Decode a value from the start of `input`, and advance `input` past it.
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError>;
+}
+
+/// Why bytes could not be decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ended in the middle of a value.
+    UnexpectedEnd,
+    /// The bytes do not encode a valid value; the message says what was wrong.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            DecodeError::Invalid(message) => write!(f, "invalid encoding: {}", message),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Take the first `len` bytes of `input`.
+pub(crate) fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], DecodeError> {
+    if input.len() < len {
+        return Err(DecodeError::UnexpectedEnd);
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes)
+}
+
+/// Decode a length, checking that it fits in memory.
+pub(crate) fn decode_len(input: &mut &[u8]) -> Result<usize, DecodeError> {
+    let len = u64::decode(input)?;
+    if len > usize::MAX as u64 {
+        return Err(DecodeError::Invalid("length does not fit in memory"));
+    }
+    Ok(len as usize)
+}
+
+/// Most items a sequence can have if its items take no bytes, so that decoding does not spin.
+const MAX_EMPTY_ITEMS: usize = 1 << 24;
+
+/// Decode a sequence of `len` items with `item`. Items normally take at least a byte, which bounds the
+/// length by the input, but items encoded in no bytes do not: when the first item takes none, a length
+/// above `MAX_EMPTY_ITEMS` is rejected instead of looping on it.
+pub(crate) fn decode_items<T>(
+    input: &mut &[u8],
+    len: usize,
+    mut item: impl FnMut(&mut &[u8]) -> Result<T, DecodeError>,
+) -> Result<Vec<T>, DecodeError> {
+    let mut items = Vec::with_capacity(len.min(input.len()));
+    for i in 0..len {
+        let before = input.len();
+        items.push(item(input)?);
+        if i == 0 && input.len() == before && len > MAX_EMPTY_ITEMS {
+            return Err(DecodeError::Invalid("too many items that take no bytes"));
+        }
+    }
+    Ok(items)
+}
+
+macro_rules! impl_codec_int {
+    ($($t:ty),*) => {
+        $(
+            impl Codec for $t {
+                fn encode(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+                    let mut bytes = [0; std::mem::size_of::<$t>()];
+                    bytes.copy_from_slice(take(input, std::mem::size_of::<$t>())?);
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_codec_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl Codec for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u64).encode(out)
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        decode_len(input)
+    }
+}
+
+impl Codec for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8)
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::Invalid("boolean is neither 0 nor 1")),
+        }
+    }
+}
+
+impl Codec for () {
+    fn encode(&self, _out: &mut Vec<u8>) {}
+
+    fn decode(_input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(())
+    }
+}
+
+impl Codec for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = decode_len(input)?;
+        let bytes = take(input, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::Invalid("string is not UTF-8"))
+    }
+}
+
+impl<T: Codec> Codec for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.len().encode(out);
+        for item in self {
+            item.encode(out);
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = decode_len(input)?;
+        decode_items(input, len, T::decode)
+    }
+}
+
+impl<T: Codec> Codec for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                value.encode(out);
+            }
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match bool::decode(input) {
+            Ok(false) => Ok(None),
+            Ok(true) => Ok(Some(T::decode(input)?)),
+            Err(DecodeError::Invalid(_)) => Err(DecodeError::Invalid("option tag is neither 0 nor 1")),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<A: Codec, B: Codec> Codec for (A, B) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok((A::decode(input)?, B::decode(input)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Codec, DecodeError};
+
+    fn round_trip<T: Codec + PartialEq + std::fmt::Debug>(value: T) {
+        let mut bytes = Vec::new();
+        value.encode(&mut bytes);
+        let mut input = &bytes[..];
+        assert_eq!(T::decode(&mut input).unwrap(), value);
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(-5i32);
+        round_trip(u128::MAX);
+        round_trip(true);
+        round_trip(());
+        round_trip(String::from("hamster"));
+        round_trip(vec![Some((1u8, String::from("a"))), None]);
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(u32::decode(&mut &[1, 2][..]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(bool::decode(&mut &[2][..]), Err(DecodeError::Invalid("boolean is neither 0 nor 1")));
+        let mut bytes = Vec::new();
+        u64::MAX.encode(&mut bytes);
+        assert_eq!(Vec::<u8>::decode(&mut &bytes[..]), Err(DecodeError::UnexpectedEnd));
+        // Items that take no bytes cannot run out of input, so their number is bounded instead.
+        assert!(matches!(Vec::<()>::decode(&mut &bytes[..]), Err(DecodeError::Invalid(_))));
+        round_trip(vec![(); 1000]);
+        let mut bytes = Vec::new();
+        2usize.encode(&mut bytes);
+        bytes.extend_from_slice(&[0xff, 0xfe]);
+        assert!(String::decode(&mut &bytes[..]).is_err());
+    }
+}
@@ -0,0 +1,308 @@
+//! Compile-time configuration of the trie shape.
+//!
+//! A [`HAMT`](crate::HAMT) is parameterized by a [`HamtConfig`], which fixes how many bits of the
+//! hash are consumed per level (and therefore the width of the presence map of each node),
+//! and how wide the hash itself is.
+//! The provided [`Config`] type selects these with const generics, e.g. `Config<6, u128>`.
+//! It also selects the reference-counted pointer nodes are shared through: `Rc` by default,
+//! or `Arc` for maps that are shared between threads (see [`SyncConfig`]).
+
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::rc::{self, Rc};
+use std::sync::{self, Arc};
+
+use crate::hasher::StableHasher;
+use crate::HAMTNode;
+
+/// A presence map: a fixed-width set of bits, one per possible entry of a node.
+pub trait Bitmap: Copy + Eq + Send + Sync + fmt::Debug + fmt::Binary + 'static {
+    /// The map with no entries present.
+    const EMPTY: Self;
+
+    /// Is the entry at `index` present?
+    fn contains(self, index: u32) -> bool;
+
+    /// Return the map with the entry at `index` marked as present.
+    fn with(self, index: u32) -> Self;
+
+    /// Return the map with the entry at `index` marked as absent.
+    fn without(self, index: u32) -> Self;
+
+    /// Count the present entries with an index strictly smaller than `index`.
+    /// This is the position of `index` in the entries vector.
+    fn count_below(self, index: u32) -> usize;
+
+    /// Count all the present entries.
+    fn count(self) -> u32;
+
+    /// Widen the map to a `u64`, e.g. for encoding.
+    fn to_u64(self) -> u64;
+
+    /// Narrow a `u64` back to a map, returning `None` if bits outside of the map's width are set.
+    fn from_u64(bits: u64) -> Option<Self>;
+}
+
+macro_rules! impl_bitmap {
+    ($($t:ty),*) => {
+        $(
+            impl Bitmap for $t {
+                const EMPTY: Self = 0;
+
+                fn contains(self, index: u32) -> bool {
+                    (self >> index) & 1 == 1
+                }
+
+                fn with(self, index: u32) -> Self {
+                    self | (1 << index)
+                }
+
+                fn without(self, index: u32) -> Self {
+                    self & !(1 << index)
+                }
+
+                fn count_below(self, index: u32) -> usize {
+                    if index == 0 {
+                        0
+                    } else {
+                        (self & ((1 << index) - 1)).count_ones() as usize
+                    }
+                }
+
+                fn count(self) -> u32 {
+                    self.count_ones()
+                }
+
+                fn to_u64(self) -> u64 {
+                    self as u64
+                }
+
+                fn from_u64(bits: u64) -> Option<Self> {
+                    use std::convert::TryFrom;
+                    <$t>::try_from(bits).ok()
+                }
+            }
+        )*
+    };
+}
+
+impl_bitmap!(u16, u32, u64);
+
+/// An unsigned integer type that key hashes are computed into.
+pub trait HashWord: Copy + Eq + Ord + Hash + Send + Sync + fmt::Debug + fmt::Binary + 'static {
+    /// Width of the hash in bits.
+    const BITS: u32;
+
+    /// The all-zero hash.
+    const ZERO: Self;
+
+    /// Hash the given key, with a hash function that does not change between builds or platforms.
+    fn hash_of<K: Hash + ?Sized>(key: &K) -> Self;
+
+    /// Extract the `bits` most significant bits of the hash.
+    /// This will always be a number below `2^bits`.
+    fn fragment(self, bits: u32) -> u32;
+
+    /// Shift the hash left by `bits`, so that the next fragment is in position.
+    /// Shifting by the full width or more produces zero rather than overflowing.
+    fn shift(self, bits: u32) -> Self;
+
+    /// Widen the hash to a `u128`, e.g. for encoding.
+    fn to_u128(self) -> u128;
+
+    /// Narrow a `u128` back to a hash, returning `None` if it does not fit.
+    fn from_u128(value: u128) -> Option<Self>;
+}
+
+impl HashWord for u64 {
+    const BITS: u32 = 64;
+    const ZERO: Self = 0;
+
+    fn hash_of<K: Hash + ?Sized>(key: &K) -> Self {
+        let mut hasher = StableHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn fragment(self, bits: u32) -> u32 {
+        (self >> (Self::BITS - bits)) as u32
+    }
+
+    fn shift(self, bits: u32) -> Self {
+        self.checked_shl(bits).unwrap_or(0)
+    }
+
+    fn to_u128(self) -> u128 {
+        self as u128
+    }
+
+    fn from_u128(value: u128) -> Option<Self> {
+        use std::convert::TryFrom;
+        u64::try_from(value).ok()
+    }
+}
+
+/// Salt mixed into the second half of a 128-bit hash, so the two halves are independent.
+const HASH128_SALT: u64 = 0x9e37_79b9_7f4a_7c15;
+
+impl HashWord for u128 {
+    const BITS: u32 = 128;
+    const ZERO: Self = 0;
+
+    /// The most significant 64 bits are the same hash that `u64` produces,
+    /// the least significant 64 bits come from a second, salted hasher.
+    fn hash_of<K: Hash + ?Sized>(key: &K) -> Self {
+        let mut high = StableHasher::new();
+        key.hash(&mut high);
+        let mut low = StableHasher::new();
+        low.write_u64(HASH128_SALT);
+        key.hash(&mut low);
+        ((high.finish() as u128) << 64) | low.finish() as u128
+    }
+
+    fn fragment(self, bits: u32) -> u32 {
+        (self >> (Self::BITS - bits)) as u32
+    }
+
+    fn shift(self, bits: u32) -> Self {
+        self.checked_shl(bits).unwrap_or(0)
+    }
+
+    fn to_u128(self) -> u128 {
+        self
+    }
+
+    fn from_u128(value: u128) -> Option<Self> {
+        Some(value)
+    }
+}
+
+/// A reference-counted pointer type that trie nodes are shared through.
+pub trait SharedPointer: 'static {
+    /// The strong pointer, e.g. `Rc<T>`.
+    type Ptr<T>: Clone + Deref<Target = T>;
+
+    /// The matching weak pointer, e.g. `rc::Weak<T>`.
+    type Weak<T>: Clone;
+
+    /// Move the value into a new allocation.
+    fn new<T>(value: T) -> Self::Ptr<T>;
+
+    /// Check if both pointers point to the same allocation.
+    fn ptr_eq<T>(a: &Self::Ptr<T>, b: &Self::Ptr<T>) -> bool;
+
+    /// The address of the allocation.
+    fn as_ptr<T>(ptr: &Self::Ptr<T>) -> *const T;
+
+    /// Make a weak pointer to the allocation.
+    fn downgrade<T>(ptr: &Self::Ptr<T>) -> Self::Weak<T>;
+
+    /// Get a strong pointer back from a weak one, if the allocation is still alive.
+    fn upgrade<T>(weak: &Self::Weak<T>) -> Option<Self::Ptr<T>>;
+
+    /// Number of strong pointers to the allocation of a weak pointer.
+    fn strong_count<T>(weak: &Self::Weak<T>) -> usize;
+
+    /// Check if both weak pointers point to the same allocation, even if it was dropped.
+    fn weak_ptr_eq<T>(a: &Self::Weak<T>, b: &Self::Weak<T>) -> bool;
+}
+
+/// A weak pointer to a node of a map with the configuration `C`.
+pub(crate) type WeakNode<K, V, C> = <<C as HamtConfig>::Pointer as SharedPointer>::Weak<HAMTNode<K, V, C>>;
+
+macro_rules! impl_shared_pointer {
+    ($name:ident, $ptr:ident, $module:ident) => {
+        impl SharedPointer for $name {
+            type Ptr<T> = $ptr<T>;
+            type Weak<T> = $module::Weak<T>;
+
+            fn new<T>(value: T) -> Self::Ptr<T> {
+                $ptr::new(value)
+            }
+
+            fn ptr_eq<T>(a: &Self::Ptr<T>, b: &Self::Ptr<T>) -> bool {
+                $ptr::ptr_eq(a, b)
+            }
+
+            fn as_ptr<T>(ptr: &Self::Ptr<T>) -> *const T {
+                $ptr::as_ptr(ptr)
+            }
+
+            fn downgrade<T>(ptr: &Self::Ptr<T>) -> Self::Weak<T> {
+                $ptr::downgrade(ptr)
+            }
+
+            fn upgrade<T>(weak: &Self::Weak<T>) -> Option<Self::Ptr<T>> {
+                weak.upgrade()
+            }
+
+            fn strong_count<T>(weak: &Self::Weak<T>) -> usize {
+                weak.strong_count()
+            }
+
+            fn weak_ptr_eq<T>(a: &Self::Weak<T>, b: &Self::Weak<T>) -> bool {
+                a.ptr_eq(b)
+            }
+        }
+    };
+}
+
+/// Share nodes through `Rc`. Maps are cheaper to update, but cannot be sent to other threads.
+pub struct RcPointer;
+
+/// Share nodes through `Arc`, so maps are `Send` and `Sync` when their keys and values are.
+pub struct ArcPointer;
+
+impl_shared_pointer!(RcPointer, Rc, rc);
+impl_shared_pointer!(ArcPointer, Arc, sync);
+
+/// The shape of a trie: how many hash bits each level consumes, and how wide hashes are.
+pub trait HamtConfig: 'static {
+    /// Number of hash bits consumed per level. A node has up to `2^BITS` entries.
+    const BITS: u32;
+
+    /// Number of levels of internal nodes. Keys whose hashes agree on every level are chained.
+    const MAX_DEPTH: u32 = <Self::Hash as HashWord>::BITS.div_ceil(Self::BITS);
+
+    /// Presence map with at least `2^BITS` bits.
+    type Bitmap: Bitmap;
+
+    /// The hash keys are reduced to.
+    type Hash: HashWord;
+
+    /// The pointer nodes are shared through.
+    type Pointer: SharedPointer;
+}
+
+/// The standard [`HamtConfig`]: `BITS` bits per level (4, 5 or 6) over a hash of type `H`
+/// (`u64` or `u128`), with nodes shared through `P` ([`RcPointer`] or [`ArcPointer`]).
+pub struct Config<const BITS: u32, H = u64, P = RcPointer>(PhantomData<fn() -> (H, P)>);
+
+impl<H: HashWord, P: SharedPointer> HamtConfig for Config<4, H, P> {
+    const BITS: u32 = 4;
+    type Bitmap = u16;
+    type Hash = H;
+    type Pointer = P;
+}
+
+impl<H: HashWord, P: SharedPointer> HamtConfig for Config<5, H, P> {
+    const BITS: u32 = 5;
+    type Bitmap = u32;
+    type Hash = H;
+    type Pointer = P;
+}
+
+impl<H: HashWord, P: SharedPointer> HamtConfig for Config<6, H, P> {
+    const BITS: u32 = 6;
+    type Bitmap = u64;
+    type Hash = H;
+    type Pointer = P;
+}
+
+/// 32-way nodes over 64-bit hashes.
+pub type DefaultConfig = Config<5, u64>;
+
+/// The default configuration, with nodes shared through `Arc`.
+pub type SyncConfig = Config<5, u64, ArcPointer>;
@@ -0,0 +1,436 @@
+//! Replicated maps that converge, whatever the order in which replicas merge each other's state.
+//!
+//! Both maps keep, for each key, state that only grows, and merging two replicas is the union of
+//! their maps, with the states of keys in both joined. The join is commutative, associative and
+//! idempotent, so merges are too. The union skips the subtrees both replicas share, so merging a
+//! replica with one it was recently merged with only visits the entries written since.
+//!
+//! [`LwwMap`] keeps the last write of each key, ordered by a Lamport timestamp and the id of the
+//! replica that wrote it. [`OrMap`] tags each write with a unique [`Dot`], and a removal only
+//! removes the dots it observed, so a write concurrent with a removal survives it.
+
+use std::hash::Hash;
+
+use crate::{union_entries_with, DefaultConfig, HAMTNodeEntry, HamtConfig, HAMT};
+
+/// Union of two maps, with `resolve(key, a, b)` giving the value of keys in both.
+fn union_with<K, V, C, F>(a: &HAMT<K, V, C>, b: &HAMT<K, V, C>, resolve: F) -> HAMT<K, V, C>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    C: HamtConfig,
+    F: Fn(&K, &V, &V) -> V,
+{
+    let a = HAMTNodeEntry::Node(a.root.clone());
+    let b = HAMTNodeEntry::Node(b.root.clone());
+    match union_entries_with(&a, &b, 0, &resolve) {
+        HAMTNodeEntry::Node(root) => HAMT { root },
+        _ => unreachable!(),
+    }
+}
+
+/// The last write of a key in a [`LwwMap`]: its value, or `None` for a removal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LwwEntry<V> {
+    pub timestamp: u64,
+    pub replica: u64,
+    pub value: Option<V>,
+}
+
+impl<V> LwwEntry<V> {
+    /// Whether this write comes after `other`, by timestamp, then by replica.
+    fn wins(&self, other: &Self) -> bool {
+        (self.timestamp, self.replica) > (other.timestamp, other.replica)
+    }
+}
+
+/// A last-writer-wins map, replicated under a unique replica id.
+pub struct LwwMap<K, V, C: HamtConfig = DefaultConfig> {
+    replica: u64,
+    clock: u64,
+    entries: HAMT<K, LwwEntry<V>, C>,
+}
+
+impl<K, V> LwwMap<K, V> {
+    /// Construct an empty replica with the id `replica`, which no other replica may use.
+    pub fn new(replica: u64) -> Self {
+        Self::with_config(replica)
+    }
+}
+
+impl<K, V, C: HamtConfig> LwwMap<K, V, C> {
+    /// Construct an empty replica with the trie shape `C`.
+    pub fn with_config(replica: u64) -> Self {
+        LwwMap {
+            replica,
+            clock: 0,
+            entries: HAMT::with_config(),
+        }
+    }
+
+    /// The id of the replica.
+    pub fn replica(&self) -> u64 {
+        self.replica
+    }
+
+    /// The highest timestamp the replica wrote or merged.
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    /// The last write of each key, including removals.
+    pub fn entries(&self) -> &HAMT<K, LwwEntry<V>, C> {
+        &self.entries
+    }
+}
+
+impl<K, V, C> LwwMap<K, V, C>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    C: HamtConfig,
+{
+    /// Get the value of `key`, unless it is absent or removed.
+    pub fn get(&self, key: K) -> Option<&V> {
+        self.entries.get(key).and_then(|entry| entry.value.as_ref())
+    }
+
+    fn write(&mut self, key: K, value: Option<V>) {
+        self.clock += 1;
+        let entry = LwwEntry {
+            timestamp: self.clock,
+            replica: self.replica,
+            value,
+        };
+        self.entries = self.entries.insert(key, entry);
+    }
+
+    /// Set `key` to `value`, after every write the replica saw.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.write(key, Some(value));
+    }
+
+    /// Remove `key`, after every write the replica saw.
+    pub fn remove(&mut self, key: K) {
+        self.write(key, None);
+    }
+
+    /// Merge the state of `other` into this replica, keeping the last write of each key.
+    pub fn merge(&mut self, other: &Self) {
+        self.entries = union_with(&self.entries, &other.entries, |_, a, b| if b.wins(a) { b.clone() } else { a.clone() });
+        self.clock = self.clock.max(other.clock);
+    }
+}
+
+impl<K, V, C: HamtConfig> Clone for LwwMap<K, V, C> {
+    fn clone(&self) -> Self {
+        LwwMap {
+            replica: self.replica,
+            clock: self.clock,
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+// Replicas are equal when they hold the same writes, whatever their ids and clocks.
+impl<K: Hash + Eq, V: PartialEq, C: HamtConfig> PartialEq for LwwMap<K, V, C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+/// A write in an [`OrMap`]: the replica that made it, and the number of writes it had made.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dot {
+    pub counter: u64,
+    pub replica: u64,
+}
+
+/// The writes of a key in an [`OrMap`]: the value of each, and the ones that were removed.
+pub struct OrEntry<V, C: HamtConfig = DefaultConfig> {
+    pub adds: HAMT<Dot, V, C>,
+    pub removes: HAMT<Dot, (), C>,
+}
+
+impl<V, C: HamtConfig> Clone for OrEntry<V, C> {
+    fn clone(&self) -> Self {
+        OrEntry {
+            adds: self.adds.clone(),
+            removes: self.removes.clone(),
+        }
+    }
+}
+
+impl<V: PartialEq, C: HamtConfig> PartialEq for OrEntry<V, C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.adds == other.adds && self.removes == other.removes
+    }
+}
+
+impl<V, C: HamtConfig> OrEntry<V, C> {
+    /// The writes that were not removed, with their values.
+    pub fn live(&self) -> impl Iterator<Item = (&Dot, &V)> {
+        self.adds.iter().filter(move |(dot, _)| !self.removes.contains_key(**dot))
+    }
+}
+
+/// An observed-remove map, replicated under a unique replica id. Writes concurrent with a removal
+/// survive it, and concurrent writes of a key are all kept, [`get`](OrMap::get) returning the
+/// value of the latest one.
+pub struct OrMap<K, V, C: HamtConfig = DefaultConfig> {
+    replica: u64,
+    counter: u64,
+    entries: HAMT<K, OrEntry<V, C>, C>,
+}
+
+impl<K, V> OrMap<K, V> {
+    /// Construct an empty replica with the id `replica`, which no other replica may use.
+    pub fn new(replica: u64) -> Self {
+        Self::with_config(replica)
+    }
+}
+
+impl<K, V, C: HamtConfig> OrMap<K, V, C> {
+    /// Construct an empty replica with the trie shape `C`.
+    pub fn with_config(replica: u64) -> Self {
+        OrMap {
+            replica,
+            counter: 0,
+            entries: HAMT::with_config(),
+        }
+    }
+
+    /// The id of the replica.
+    pub fn replica(&self) -> u64 {
+        self.replica
+    }
+
+    /// The writes of each key, including removed ones.
+    pub fn entries(&self) -> &HAMT<K, OrEntry<V, C>, C> {
+        &self.entries
+    }
+}
+
+impl<K, V, C> OrMap<K, V, C>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    C: HamtConfig,
+{
+    /// Get the value of the latest write of `key` that was not removed.
+    pub fn get(&self, key: K) -> Option<&V> {
+        let entry = self.entries.get(key)?;
+        entry.live().max_by_key(|(dot, _)| **dot).map(|(_, v)| v)
+    }
+
+    /// Get the values of all the writes of `key` that were not removed, as concurrent writes are all kept.
+    pub fn values(&self, key: K) -> Vec<&V> {
+        match self.entries.get(key) {
+            Some(entry) => entry.live().map(|(_, v)| v).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Check if `key` has a value.
+    pub fn contains_key(&self, key: K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Remove the writes of `key` the replica saw, and add `value` if it is given.
+    fn write(&mut self, key: K, value: Option<V>) {
+        let mut entry = match self.entries.get(key.clone()) {
+            Some(entry) => entry.clone(),
+            None if value.is_none() => return,
+            None => OrEntry {
+                adds: HAMT::with_config(),
+                removes: HAMT::with_config(),
+            },
+        };
+        let observed: Vec<Dot> = entry.live().map(|(dot, _)| *dot).collect();
+        for dot in observed {
+            entry.removes = entry.removes.insert(dot, ());
+        }
+        if let Some(value) = value {
+            self.counter += 1;
+            let dot = Dot {
+                counter: self.counter,
+                replica: self.replica,
+            };
+            entry.adds = entry.adds.insert(dot, value);
+        }
+        self.entries = self.entries.insert(key, entry);
+    }
+
+    /// Set `key` to `value`, replacing the values the replica saw.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.write(key, Some(value));
+    }
+
+    /// Remove the values of `key` the replica saw.
+    pub fn remove(&mut self, key: K) {
+        self.write(key, None);
+    }
+
+    /// Merge the state of `other` into this replica, keeping the writes of both, and the removals of both.
+    pub fn merge(&mut self, other: &Self) {
+        self.entries = union_with(&self.entries, &other.entries, |_, a, b| OrEntry {
+            adds: union_with(&a.adds, &b.adds, |_, v, _| v.clone()),
+            removes: union_with(&a.removes, &b.removes, |_, _, _| ()),
+        });
+        // Later writes get higher counters than the writes they saw.
+        self.counter = self.counter.max(other.counter);
+    }
+}
+
+impl<K, V, C: HamtConfig> Clone for OrMap<K, V, C> {
+    fn clone(&self) -> Self {
+        OrMap {
+            replica: self.replica,
+            counter: self.counter,
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+// Replicas are equal when they hold the same writes, whatever their ids and counters.
+impl<K: Hash + Eq, V: PartialEq, C: HamtConfig> PartialEq for OrMap<K, V, C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LwwMap, OrMap};
+    use std::convert::TryInto;
+
+    /// A small xorshift generator, so that the properties are checked on the same cases every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    /// Replicas with random writes and merges, on keys that often overlap.
+    fn lww_replicas(rng: &mut Rng) -> Vec<LwwMap<u64, u64>> {
+        let mut replicas: Vec<_> = (0..3).map(LwwMap::new).collect();
+        for _ in 0..200 {
+            let i = rng.below(3) as usize;
+            match rng.below(10) {
+                0 => {
+                    let other = replicas[rng.below(3) as usize].clone();
+                    replicas[i].merge(&other);
+                }
+                1..=2 => replicas[i].remove(rng.below(50)),
+                _ => replicas[i].insert(rng.below(50), rng.below(1000)),
+            }
+        }
+        replicas
+    }
+
+    fn or_replicas(rng: &mut Rng) -> Vec<OrMap<u64, u64>> {
+        let mut replicas: Vec<_> = (0..3).map(OrMap::new).collect();
+        for _ in 0..200 {
+            let i = rng.below(3) as usize;
+            match rng.below(10) {
+                0 => {
+                    let other = replicas[rng.below(3) as usize].clone();
+                    replicas[i].merge(&other);
+                }
+                1..=2 => replicas[i].remove(rng.below(50)),
+                _ => replicas[i].insert(rng.below(50), rng.below(1000)),
+            }
+        }
+        replicas
+    }
+
+    /// Check that merging is commutative, associative and idempotent on the replicas.
+    macro_rules! check_laws {
+        ($a:expr, $b:expr, $c:expr, $empty:expr) => {{
+            let merged = |x: &_, y: &_| {
+                let mut m = $empty;
+                m.merge(x);
+                m.merge(y);
+                m
+            };
+            assert!(merged(&$a, &$b) == merged(&$b, &$a), "merge is not commutative");
+            let ab_c = merged(&merged(&$a, &$b), &$c);
+            let a_bc = merged(&$a, &merged(&$b, &$c));
+            assert!(ab_c == a_bc, "merge is not associative");
+            assert!(merged(&$a, &$a) == merged(&$a, &$empty), "merge is not idempotent");
+            assert!(merged(&ab_c, &$b) == ab_c, "merging again changes the state");
+        }};
+    }
+
+    #[test]
+    fn merge_laws() {
+        for seed in 1..=20u64 {
+            let mut rng = Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+            let [a, b, c]: [LwwMap<u64, u64>; 3] = lww_replicas(&mut rng).try_into().ok().unwrap();
+            check_laws!(a, b, c, LwwMap::new(7));
+            let [a, b, c]: [OrMap<u64, u64>; 3] = or_replicas(&mut rng).try_into().ok().unwrap();
+            check_laws!(a, b, c, OrMap::new(7));
+        }
+    }
+
+    #[test]
+    fn last_writer_wins() {
+        let mut a = LwwMap::new(1);
+        let mut b = LwwMap::new(2);
+        // Concurrent writes at the same timestamp go to the higher replica.
+        a.insert("z", 1);
+        b.insert("z", 2);
+        a.insert("x", 1);
+        b.merge(&a);
+        b.insert("x", 2);
+        a.insert("y", 1);
+        a.remove("y");
+        a.merge(&b);
+        b.merge(&a);
+        assert!(a == b);
+        assert_eq!(a.get("x"), Some(&2));
+        assert_eq!(a.get("y"), None);
+        assert_eq!(a.get("z"), Some(&2));
+        // Writes after a merge come after everything merged.
+        a.insert("x", 3);
+        b.merge(&a);
+        assert_eq!(b.get("x"), Some(&3));
+    }
+
+    #[test]
+    fn observed_remove() {
+        let mut a = OrMap::new(1);
+        let mut b = OrMap::new(2);
+        a.insert("x", 1);
+        b.merge(&a);
+        // A removal only removes the writes it saw, so a concurrent write survives.
+        b.remove("x");
+        a.insert("x", 2);
+        b.merge(&a);
+        assert_eq!(b.get("x"), Some(&2));
+        a.remove("x");
+        b.merge(&a);
+        assert!(!b.contains_key("x"));
+
+        // Concurrent writes are both kept, until a write that saw them both.
+        a.insert("y", 1);
+        b.insert("y", 2);
+        a.merge(&b);
+        let mut values = a.values("y");
+        values.sort();
+        assert_eq!(values, vec![&1, &2]);
+        a.insert("y", 3);
+        b.merge(&a);
+        assert_eq!(b.values("y"), vec![&3]);
+
+        // Merging a replica with itself, or an older state of it, shares the whole trie.
+        let before = b.clone();
+        b.merge(&before);
+        assert!(b.entries().ptr_eq(before.entries()));
+    }
+}
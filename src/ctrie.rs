@@ -0,0 +1,1126 @@
+//! A lock-free concurrent hash trie with constant-time snapshots.
+//!
+//! This is the Ctrie of [Pro12]: the same bitmap-indexed nodes as [`HAMT`](crate::HAMT), but
+//! every internal node sits behind an *indirection node* (`INode`) holding an atomic pointer to
+//! it, so that an update only has to swap one pointer with a compare-and-swap.
+//!
+//! Snapshots are taken by giving the trie a new *generation*: the root `INode` is replaced by a
+//! copy in a new generation, and the snapshot gets another. Both share all the nodes below,
+//! and every update lazily copies the `INode`s on its path that belong to an older generation
+//! before modifying them. To make the switch of generation atomic with respect to concurrent
+//! updates, nodes are swapped with a generation-checking compare-and-swap (GCAS), and the
+//! root is swapped with a restricted double compare single swap (RDCSS).
+//!
+//! Removing keys may leave `INode`s holding a single key. These are *entombed* (`TNode`), and
+//! then merged into their parent by whichever operation finds them next.
+//!
+//! Memory is reclaimed with the [`epoch`](crate::epoch) scheme.
+//!
+//! [Pro12] Aleksandar Prokopec, Nathan Bronson, Phil Bagwell and Martin Odersky.
+//! *Concurrent tries with efficient non-blocking snapshots.* 2012.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::epoch::{pin, AtomicArc, Guard};
+use crate::{build_node, hash_key, Bitmap, DefaultConfig, HamtConfig, HashWord, NodePtr, HAMT};
+
+/// A generation. Generations are only compared by identity.
+struct Gen;
+
+/// A key and value, with the key's hash.
+struct SNode<K, V, H> {
+    key: K,
+    value: V,
+    hash: H,
+}
+
+/// A shared `SNode` of a trie with the shape `C`.
+type Leaf<K, V, C> = Arc<SNode<K, V, <C as HamtConfig>::Hash>>;
+
+/// An entry of a `CNode`.
+enum Branch<K, V, C: HamtConfig> {
+    I(Arc<INode<K, V, C>>),
+    S(Leaf<K, V, C>),
+}
+
+/// A shared `INode`.
+type Node<K, V, C> = Arc<INode<K, V, C>>;
+
+/// An indirection node: the only mutable part of the trie.
+struct INode<K, V, C: HamtConfig> {
+    main: AtomicArc<MainNode<K, V, C>>,
+    gen: Arc<Gen>,
+}
+
+/// The node an `INode` points to, together with the previous node while a GCAS is in progress.
+struct MainNode<K, V, C: HamtConfig> {
+    kind: Kind<K, V, C>,
+    prev: AtomicArc<Prev<K, V, C>>,
+}
+
+enum Kind<K, V, C: HamtConfig> {
+    /// A bitmap-indexed node, like `HAMTNode`.
+    C(CNode<K, V, C>),
+    /// A tombed key, which is to be merged into the parent.
+    T(Leaf<K, V, C>),
+    /// Keys whose hashes are fully equal.
+    L(Vec<Leaf<K, V, C>>),
+}
+
+struct CNode<K, V, C: HamtConfig> {
+    bitmap: C::Bitmap,
+    array: Vec<Branch<K, V, C>>,
+    gen: Arc<Gen>,
+}
+
+/// State of a GCAS in progress: either it may still commit, or it has failed and must be rolled back.
+enum Prev<K, V, C: HamtConfig> {
+    Main(Arc<MainNode<K, V, C>>),
+    Failed(Arc<MainNode<K, V, C>>),
+}
+
+/// The root pointer: either the root `INode`, or an RDCSS in progress.
+enum Root<K, V, C: HamtConfig> {
+    I(Arc<INode<K, V, C>>),
+    Rdcss(Descriptor<K, V, C>),
+}
+
+/// The root pointer, together with the root `INode` it refers to.
+type RootRead<K, V, C> = (Arc<Root<K, V, C>>, Node<K, V, C>);
+
+/// Replace the root `old` with `new`, if the main node of `old` is still `expected`.
+struct Descriptor<K, V, C: HamtConfig> {
+    old: Arc<INode<K, V, C>>,
+    expected: Arc<MainNode<K, V, C>>,
+    new: Arc<INode<K, V, C>>,
+    committed: AtomicBool,
+}
+
+/// Result of an operation that may have to be restarted from the root.
+enum Res<T> {
+    Done(T),
+    Restart,
+}
+
+impl<K, V, C: HamtConfig> Clone for Branch<K, V, C> {
+    fn clone(&self) -> Self {
+        match self {
+            Branch::I(inode) => Branch::I(Arc::clone(inode)),
+            Branch::S(snode) => Branch::S(Arc::clone(snode)),
+        }
+    }
+}
+
+/// The fragment of the hash used to index into a node at the given level.
+fn fragment_at<C: HamtConfig>(hash: C::Hash, level: u32) -> u32 {
+    hash.shift(C::BITS * level).fragment(C::BITS)
+}
+
+impl<K, V, C> MainNode<K, V, C>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+    C: HamtConfig + 'static,
+{
+    fn new(kind: Kind<K, V, C>) -> Arc<Self> {
+        Arc::new(MainNode {
+            kind,
+            prev: AtomicArc::new(None),
+        })
+    }
+}
+
+impl<K, V, C> INode<K, V, C>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+    C: HamtConfig + 'static,
+{
+    fn new(kind: Kind<K, V, C>, gen: &Arc<Gen>) -> Arc<Self> {
+        Arc::new(INode {
+            main: AtomicArc::new(Some(MainNode::new(kind))),
+            gen: Arc::clone(gen),
+        })
+    }
+}
+
+impl<K, V, C: HamtConfig> CNode<K, V, C> {
+    fn updated(&self, pos: usize, branch: Branch<K, V, C>, gen: &Arc<Gen>) -> Self {
+        let mut array = self.array.clone();
+        array[pos] = branch;
+        CNode {
+            bitmap: self.bitmap,
+            array,
+            gen: Arc::clone(gen),
+        }
+    }
+
+    fn inserted(&self, pos: usize, frag: u32, branch: Branch<K, V, C>, gen: &Arc<Gen>) -> Self {
+        let mut array = Vec::with_capacity(self.array.len() + 1);
+        array.extend_from_slice(&self.array[..pos]);
+        array.push(branch);
+        array.extend_from_slice(&self.array[pos..]);
+        CNode {
+            bitmap: self.bitmap.with(frag),
+            array,
+            gen: Arc::clone(gen),
+        }
+    }
+
+    fn removed(&self, pos: usize, frag: u32, gen: &Arc<Gen>) -> Self {
+        let mut array = self.array.clone();
+        array.remove(pos);
+        CNode {
+            bitmap: self.bitmap.without(frag),
+            array,
+            gen: Arc::clone(gen),
+        }
+    }
+
+    /// Entomb the node if it is below the root and only holds a single key.
+    fn contracted(self, level: u32) -> Kind<K, V, C> {
+        if level > 0 && self.array.len() == 1 {
+            if let Branch::S(snode) = &self.array[0] {
+                return Kind::T(Arc::clone(snode));
+            }
+        }
+        Kind::C(self)
+    }
+}
+
+/// Build the node holding two keys whose hashes agree up to the given level.
+fn dual<K, V, C>(x: Leaf<K, V, C>, y: Leaf<K, V, C>, level: u32, gen: &Arc<Gen>) -> Kind<K, V, C>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+    C: HamtConfig + 'static,
+{
+    if level == C::MAX_DEPTH {
+        return Kind::L(vec![x, y]);
+    }
+    let x_frag = fragment_at::<C>(x.hash, level);
+    let y_frag = fragment_at::<C>(y.hash, level);
+    let (bitmap, array) = if x_frag == y_frag {
+        let below = INode::new(dual(x, y, level + 1, gen), gen);
+        (C::Bitmap::EMPTY.with(x_frag), vec![Branch::I(below)])
+    } else if x_frag < y_frag {
+        (
+            C::Bitmap::EMPTY.with(x_frag).with(y_frag),
+            vec![Branch::S(x), Branch::S(y)],
+        )
+    } else {
+        (
+            C::Bitmap::EMPTY.with(x_frag).with(y_frag),
+            vec![Branch::S(y), Branch::S(x)],
+        )
+    };
+    Kind::C(CNode {
+        bitmap,
+        array,
+        gen: Arc::clone(gen),
+    })
+}
+
+/// A lock-free concurrent hash trie.
+///
+/// All operations take `&self`, so a `Ctrie` can be shared between threads directly (e.g. from
+/// `std::thread::scope`, or behind an `Arc`). Values are returned by clone, as a reference could be
+/// invalidated by a concurrent update.
+pub struct Ctrie<K, V, C: HamtConfig = DefaultConfig> {
+    root: AtomicArc<Root<K, V, C>>,
+    read_only: bool,
+}
+
+/// An immutable snapshot of a [`Ctrie`](Ctrie), returned by [`Ctrie::snapshot`](Ctrie::snapshot).
+pub struct CtrieSnapshot<K, V, C: HamtConfig = DefaultConfig> {
+    trie: Ctrie<K, V, C>,
+}
+
+impl<K, V> Ctrie<K, V>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    /// Construct an empty trie.
+    pub fn new() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K, V> Default for Ctrie<K, V>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, C> Ctrie<K, V, C>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+    C: HamtConfig + 'static,
+{
+    /// Construct an empty trie with the trie shape `C`.
+    pub fn with_config() -> Self {
+        let gen = Arc::new(Gen);
+        let root_node = CNode {
+            bitmap: C::Bitmap::EMPTY,
+            array: Vec::new(),
+            gen: Arc::clone(&gen),
+        };
+        Self::with_root(INode::new(Kind::C(root_node), &gen), false)
+    }
+
+    fn with_root(root: Arc<INode<K, V, C>>, read_only: bool) -> Self {
+        Ctrie {
+            root: AtomicArc::new(Some(Arc::new(Root::I(root)))),
+            read_only,
+        }
+    }
+
+    /// Read the main node of `inode`, completing a GCAS in progress if there is one.
+    fn gcas_read(&self, inode: &INode<K, V, C>, guard: &Guard) -> Arc<MainNode<K, V, C>> {
+        let main = inode.main.load(guard).unwrap();
+        if main.prev.load(guard).is_none() {
+            main
+        } else {
+            self.gcas_complete(inode, main, guard)
+        }
+    }
+
+    /// Replace the main node `old` of `inode` by a new node with the contents `kind`.
+    /// This only succeeds if the generation of the trie is still that of `inode` when the swap is committed.
+    fn gcas(
+        &self,
+        inode: &INode<K, V, C>,
+        old: &Arc<MainNode<K, V, C>>,
+        kind: Kind<K, V, C>,
+        guard: &Guard,
+    ) -> bool {
+        let new = Arc::new(MainNode {
+            kind,
+            prev: AtomicArc::new(Some(Arc::new(Prev::Main(Arc::clone(old))))),
+        });
+        if inode
+            .main
+            .compare_exchange(Some(old), Some(Arc::clone(&new)), guard)
+            .is_ok()
+        {
+            self.gcas_complete(inode, Arc::clone(&new), guard);
+            new.prev.load(guard).is_none()
+        } else {
+            false
+        }
+    }
+
+    /// Commit or roll back the GCAS that installed `main` in `inode`, and return the resulting main node.
+    fn gcas_complete(
+        &self,
+        inode: &INode<K, V, C>,
+        mut main: Arc<MainNode<K, V, C>>,
+        guard: &Guard,
+    ) -> Arc<MainNode<K, V, C>> {
+        loop {
+            let prev = match main.prev.load(guard) {
+                None => return main,
+                Some(prev) => prev,
+            };
+            let root = self.rdcss_read_root(true, guard);
+            match &*prev {
+                Prev::Failed(old) => {
+                    // Roll back to the node that was replaced.
+                    if inode
+                        .main
+                        .compare_exchange(Some(&main), Some(Arc::clone(old)), guard)
+                        .is_ok()
+                    {
+                        return Arc::clone(old);
+                    }
+                    main = inode.main.load(guard).unwrap();
+                }
+                Prev::Main(old) => {
+                    if Arc::ptr_eq(&root.gen, &inode.gen) && !self.read_only {
+                        // The generation did not change, so commit.
+                        if main.prev.compare_exchange(Some(&prev), None, guard).is_ok() {
+                            return main;
+                        }
+                    } else {
+                        // A snapshot was taken in the meantime, so this update must not be visible.
+                        let failed = Arc::new(Prev::Failed(Arc::clone(old)));
+                        let _ = main.prev.compare_exchange(Some(&prev), Some(failed), guard);
+                        main = inode.main.load(guard).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read the root `INode`, completing (or, if `abort`, aborting) an RDCSS in progress.
+    fn rdcss_read_root(&self, abort: bool, guard: &Guard) -> Arc<INode<K, V, C>> {
+        let root = self.root.load(guard).unwrap();
+        match &*root {
+            Root::I(inode) => Arc::clone(inode),
+            Root::Rdcss(_) => self.rdcss_complete(abort, guard).1,
+        }
+    }
+
+    /// Complete or abort the RDCSS in progress, if any, and return the root pointer and the root `INode`.
+    fn rdcss_complete(&self, abort: bool, guard: &Guard) -> RootRead<K, V, C> {
+        loop {
+            let root = self.root.load(guard).unwrap();
+            let desc = match &*root {
+                Root::I(inode) => return (Arc::clone(&root), Arc::clone(inode)),
+                Root::Rdcss(desc) => desc,
+            };
+            let commit = !abort && Arc::ptr_eq(&self.gcas_read(&desc.old, guard), &desc.expected);
+            let result = if commit { &desc.new } else { &desc.old };
+            let replacement = Arc::new(Root::I(Arc::clone(result)));
+            if self
+                .root
+                .compare_exchange(Some(&root), Some(Arc::clone(&replacement)), guard)
+                .is_ok()
+            {
+                if commit {
+                    desc.committed.store(true, Ordering::SeqCst);
+                }
+                return (replacement, Arc::clone(result));
+            }
+        }
+    }
+
+    /// Replace the root `old`, stored in the root pointer `old_root`, by `new`,
+    /// if the main node of `old` is still `expected`.
+    fn rdcss_root(
+        &self,
+        old_root: &Arc<Root<K, V, C>>,
+        old: Arc<INode<K, V, C>>,
+        expected: Arc<MainNode<K, V, C>>,
+        new: Arc<INode<K, V, C>>,
+        guard: &Guard,
+    ) -> bool {
+        let desc = Arc::new(Root::Rdcss(Descriptor {
+            old,
+            expected,
+            new,
+            committed: AtomicBool::new(false),
+        }));
+        if self
+            .root
+            .compare_exchange(Some(old_root), Some(Arc::clone(&desc)), guard)
+            .is_ok()
+        {
+            self.rdcss_complete(false, guard);
+            match &*desc {
+                Root::Rdcss(desc) => desc.committed.load(Ordering::SeqCst),
+                Root::I(_) => unreachable!(),
+            }
+        } else {
+            false
+        }
+    }
+
+    /// Copy `inode` into the generation `gen`, sharing its main node.
+    fn copy_to_gen(
+        &self,
+        inode: &INode<K, V, C>,
+        gen: &Arc<Gen>,
+        guard: &Guard,
+    ) -> Arc<INode<K, V, C>> {
+        Arc::new(INode {
+            main: AtomicArc::new(Some(self.gcas_read(inode, guard))),
+            gen: Arc::clone(gen),
+        })
+    }
+
+    /// Copy the node into the generation `gen`, along with the `INode`s directly below it.
+    fn renewed(&self, cnode: &CNode<K, V, C>, gen: &Arc<Gen>, guard: &Guard) -> Kind<K, V, C> {
+        let array = cnode
+            .array
+            .iter()
+            .map(|branch| match branch {
+                Branch::I(inode) => Branch::I(self.copy_to_gen(inode, gen, guard)),
+                Branch::S(snode) => Branch::S(Arc::clone(snode)),
+            })
+            .collect();
+        Kind::C(CNode {
+            bitmap: cnode.bitmap,
+            array,
+            gen: Arc::clone(gen),
+        })
+    }
+
+    /// Merge entombed children into the node.
+    fn compressed(
+        &self,
+        cnode: &CNode<K, V, C>,
+        level: u32,
+        gen: &Arc<Gen>,
+        guard: &Guard,
+    ) -> Kind<K, V, C> {
+        let array = cnode
+            .array
+            .iter()
+            .map(|branch| match branch {
+                Branch::I(inode) => match &self.gcas_read(inode, guard).kind {
+                    Kind::T(snode) => Branch::S(Arc::clone(snode)),
+                    _ => Branch::I(Arc::clone(inode)),
+                },
+                Branch::S(snode) => Branch::S(Arc::clone(snode)),
+            })
+            .collect();
+        CNode {
+            bitmap: cnode.bitmap,
+            array,
+            gen: Arc::clone(gen),
+        }
+        .contracted(level)
+    }
+
+    /// Compress the node of `inode`, at the given level.
+    fn clean(&self, inode: &INode<K, V, C>, level: u32, guard: &Guard) {
+        let main = self.gcas_read(inode, guard);
+        if let Kind::C(cnode) = &main.kind {
+            let compressed = self.compressed(cnode, level, &inode.gen, guard);
+            self.gcas(inode, &main, compressed, guard);
+        }
+    }
+
+    /// Merge the entombed `inode` into its parent, at the given level.
+    fn clean_parent(
+        &self,
+        parent: &INode<K, V, C>,
+        inode: &Arc<INode<K, V, C>>,
+        hash: C::Hash,
+        level: u32,
+        start_gen: &Arc<Gen>,
+        guard: &Guard,
+    ) {
+        loop {
+            let parent_main = self.gcas_read(parent, guard);
+            let cnode = match &parent_main.kind {
+                Kind::C(cnode) => cnode,
+                _ => return,
+            };
+            let frag = fragment_at::<C>(hash, level);
+            if !cnode.bitmap.contains(frag) {
+                return;
+            }
+            let pos = cnode.bitmap.count_below(frag);
+            match &cnode.array[pos] {
+                Branch::I(sub) if Arc::ptr_eq(sub, inode) => {}
+                _ => return,
+            }
+            if let Kind::T(snode) = &self.gcas_read(inode, guard).kind {
+                let updated = cnode.updated(pos, Branch::S(Arc::clone(snode)), &inode.gen);
+                if !self.gcas(parent, &parent_main, updated.contracted(level), guard)
+                    && Arc::ptr_eq(&self.rdcss_read_root(false, guard).gen, start_gen)
+                {
+                    continue;
+                }
+            }
+            return;
+        }
+    }
+
+    /// Look up the key in the subtree of `inode`, at the given level.
+    #[allow(clippy::too_many_arguments)]
+    fn lookup<Q>(
+        &self,
+        inode: &Arc<INode<K, V, C>>,
+        key: &Q,
+        hash: C::Hash,
+        level: u32,
+        parent: Option<&Arc<INode<K, V, C>>>,
+        start_gen: &Arc<Gen>,
+        guard: &Guard,
+    ) -> Res<Option<Leaf<K, V, C>>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let matches = |snode: &Leaf<K, V, C>| snode.hash == hash && snode.key.borrow() == key;
+        let main = self.gcas_read(inode, guard);
+        match &main.kind {
+            Kind::C(cnode) => {
+                let frag = fragment_at::<C>(hash, level);
+                if !cnode.bitmap.contains(frag) {
+                    return Res::Done(None);
+                }
+                match &cnode.array[cnode.bitmap.count_below(frag)] {
+                    Branch::I(sub) => {
+                        if self.read_only || Arc::ptr_eq(start_gen, &sub.gen) {
+                            self.lookup(sub, key, hash, level + 1, Some(inode), start_gen, guard)
+                        } else if self.gcas(
+                            inode,
+                            &main,
+                            self.renewed(cnode, start_gen, guard),
+                            guard,
+                        ) {
+                            self.lookup(inode, key, hash, level, parent, start_gen, guard)
+                        } else {
+                            Res::Restart
+                        }
+                    }
+                    Branch::S(snode) => Res::Done(Some(Arc::clone(snode)).filter(matches)),
+                }
+            }
+            Kind::T(snode) => {
+                if self.read_only {
+                    Res::Done(Some(Arc::clone(snode)).filter(matches))
+                } else {
+                    self.clean(parent.unwrap(), level - 1, guard);
+                    Res::Restart
+                }
+            }
+            Kind::L(list) => Res::Done(list.iter().find(|snode| matches(snode)).cloned()),
+        }
+    }
+
+    /// Insert the key in the subtree of `inode`, at the given level, and return the replaced entry.
+    #[allow(clippy::too_many_arguments)]
+    fn insert_at(
+        &self,
+        inode: &Arc<INode<K, V, C>>,
+        new: &Leaf<K, V, C>,
+        level: u32,
+        parent: Option<&Arc<INode<K, V, C>>>,
+        start_gen: &Arc<Gen>,
+        guard: &Guard,
+    ) -> Res<Option<Leaf<K, V, C>>>
+    where
+        K: Eq,
+    {
+        let main = self.gcas_read(inode, guard);
+        match &main.kind {
+            Kind::C(cnode) => {
+                let frag = fragment_at::<C>(new.hash, level);
+                let pos = cnode.bitmap.count_below(frag);
+                // Nodes of older generations are copied before they are modified.
+                let renewed;
+                let current = if Arc::ptr_eq(&cnode.gen, &inode.gen) {
+                    cnode
+                } else {
+                    renewed = match self.renewed(cnode, &inode.gen, guard) {
+                        Kind::C(renewed) => renewed,
+                        _ => unreachable!(),
+                    };
+                    &renewed
+                };
+                if !cnode.bitmap.contains(frag) {
+                    let updated =
+                        current.inserted(pos, frag, Branch::S(Arc::clone(new)), &inode.gen);
+                    return if self.gcas(inode, &main, Kind::C(updated), guard) {
+                        Res::Done(None)
+                    } else {
+                        Res::Restart
+                    };
+                }
+                match &cnode.array[pos] {
+                    Branch::I(sub) => {
+                        if Arc::ptr_eq(start_gen, &sub.gen) {
+                            self.insert_at(sub, new, level + 1, Some(inode), start_gen, guard)
+                        } else if self.gcas(
+                            inode,
+                            &main,
+                            self.renewed(cnode, start_gen, guard),
+                            guard,
+                        ) {
+                            self.insert_at(inode, new, level, parent, start_gen, guard)
+                        } else {
+                            Res::Restart
+                        }
+                    }
+                    Branch::S(snode) => {
+                        let (branch, replaced) = if snode.hash == new.hash && snode.key == new.key {
+                            (Branch::S(Arc::clone(new)), Some(Arc::clone(snode)))
+                        } else {
+                            let below =
+                                dual(Arc::clone(snode), Arc::clone(new), level + 1, &inode.gen);
+                            (Branch::I(INode::new(below, &inode.gen)), None)
+                        };
+                        let updated = current.updated(pos, branch, &inode.gen);
+                        if self.gcas(inode, &main, Kind::C(updated), guard) {
+                            Res::Done(replaced)
+                        } else {
+                            Res::Restart
+                        }
+                    }
+                }
+            }
+            Kind::T(_) => {
+                self.clean(parent.unwrap(), level - 1, guard);
+                Res::Restart
+            }
+            Kind::L(list) => {
+                let mut updated = list.clone();
+                let replaced = match updated.iter().position(|snode| snode.key == new.key) {
+                    Some(i) => Some(std::mem::replace(&mut updated[i], Arc::clone(new))),
+                    None => {
+                        updated.push(Arc::clone(new));
+                        None
+                    }
+                };
+                if self.gcas(inode, &main, Kind::L(updated), guard) {
+                    Res::Done(replaced)
+                } else {
+                    Res::Restart
+                }
+            }
+        }
+    }
+
+    /// Remove the key from the subtree of `inode`, at the given level, and return the removed entry.
+    #[allow(clippy::too_many_arguments)]
+    fn remove_at<Q>(
+        &self,
+        inode: &Arc<INode<K, V, C>>,
+        key: &Q,
+        hash: C::Hash,
+        level: u32,
+        parent: Option<&Arc<INode<K, V, C>>>,
+        start_gen: &Arc<Gen>,
+        guard: &Guard,
+    ) -> Res<Option<Leaf<K, V, C>>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let main = self.gcas_read(inode, guard);
+        match &main.kind {
+            Kind::C(cnode) => {
+                let frag = fragment_at::<C>(hash, level);
+                if !cnode.bitmap.contains(frag) {
+                    return Res::Done(None);
+                }
+                let pos = cnode.bitmap.count_below(frag);
+                let result = match &cnode.array[pos] {
+                    Branch::I(sub) => {
+                        if Arc::ptr_eq(start_gen, &sub.gen) {
+                            self.remove_at(sub, key, hash, level + 1, Some(inode), start_gen, guard)
+                        } else if self.gcas(
+                            inode,
+                            &main,
+                            self.renewed(cnode, start_gen, guard),
+                            guard,
+                        ) {
+                            self.remove_at(inode, key, hash, level, parent, start_gen, guard)
+                        } else {
+                            Res::Restart
+                        }
+                    }
+                    Branch::S(snode) => {
+                        if snode.hash == hash && snode.key.borrow() == key {
+                            let updated = cnode.removed(pos, frag, &inode.gen).contracted(level);
+                            if self.gcas(inode, &main, updated, guard) {
+                                Res::Done(Some(Arc::clone(snode)))
+                            } else {
+                                Res::Restart
+                            }
+                        } else {
+                            Res::Done(None)
+                        }
+                    }
+                };
+                if let (Res::Done(Some(_)), Some(parent)) = (&result, parent) {
+                    // The root is never entombed.
+                    if let Kind::T(_) = self.gcas_read(inode, guard).kind {
+                        self.clean_parent(parent, inode, hash, level - 1, start_gen, guard);
+                    }
+                }
+                result
+            }
+            Kind::T(_) => {
+                self.clean(parent.unwrap(), level - 1, guard);
+                Res::Restart
+            }
+            Kind::L(list) => match list.iter().position(|snode| snode.key.borrow() == key) {
+                None => Res::Done(None),
+                Some(i) => {
+                    let mut updated = list.clone();
+                    let removed = updated.remove(i);
+                    // A single remaining key is entombed, so it is merged into the parent later.
+                    let kind = if updated.len() == 1 {
+                        Kind::T(updated.pop().unwrap())
+                    } else {
+                        Kind::L(updated)
+                    };
+                    if self.gcas(inode, &main, kind, guard) {
+                        Res::Done(Some(removed))
+                    } else {
+                        Res::Restart
+                    }
+                }
+            },
+        }
+    }
+
+    fn get_entry<Q>(&self, key: &Q) -> Option<Leaf<K, V, C>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let guard = pin();
+        let hash = hash_key::<C, Q>(key);
+        loop {
+            let root = self.rdcss_read_root(false, &guard);
+            if let Res::Done(result) = self.lookup(&root, key, hash, 0, None, &root.gen, &guard) {
+                return result;
+            }
+        }
+    }
+
+    /// Check if the trie contains the given key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_entry(key).is_some()
+    }
+
+    /// Remove the given key, returning its value if it was present.
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        assert!(!self.read_only);
+        let guard = pin();
+        let hash = hash_key::<C, Q>(key);
+        loop {
+            let root = self.rdcss_read_root(false, &guard);
+            if let Res::Done(removed) = self.remove_at(&root, key, hash, 0, None, &root.gen, &guard)
+            {
+                return removed.map(|snode| snode.value.clone());
+            }
+        }
+    }
+
+    /// Take a snapshot of the trie in constant time.
+    /// Later updates of the trie are not visible in the snapshot.
+    pub fn snapshot(&self) -> CtrieSnapshot<K, V, C> {
+        let guard = pin();
+        loop {
+            let (root_ptr, root) = self.rdcss_complete(false, &guard);
+            let expected = self.gcas_read(&root, &guard);
+            let renewed = self.copy_to_gen(&root, &Arc::new(Gen), &guard);
+            if self.rdcss_root(&root_ptr, Arc::clone(&root), expected, renewed, &guard) {
+                // The old root can no longer be modified, so the snapshot can share its main node.
+                let snapshot_root = self.copy_to_gen(&root, &Arc::new(Gen), &guard);
+                return CtrieSnapshot {
+                    trie: Ctrie::with_root(snapshot_root, true),
+                };
+            }
+        }
+    }
+}
+
+impl<K, V, C> Ctrie<K, V, C>
+where
+    K: Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    C: HamtConfig + 'static,
+{
+    /// Get a copy of the value stored at key if it exists, otherwise return `None`.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get_entry(key).map(|snode| snode.value.clone())
+    }
+
+    /// Insert the given key and value, returning the value previously stored at the key.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        assert!(!self.read_only);
+        let guard = pin();
+        let hash = hash_key::<C, K>(&key);
+        let new = Arc::new(SNode { key, value, hash });
+        loop {
+            let root = self.rdcss_read_root(false, &guard);
+            if let Res::Done(replaced) = self.insert_at(&root, &new, 0, None, &root.gen, &guard) {
+                return replaced.map(|snode| snode.value.clone());
+            }
+        }
+    }
+}
+
+impl<K, V, C> CtrieSnapshot<K, V, C>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+    C: HamtConfig + 'static,
+{
+    /// Check if the snapshot contains the given key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.trie.contains_key(key)
+    }
+
+    /// Call `f` on every key and value in the snapshot.
+    pub fn for_each<F: FnMut(&K, &V)>(&self, mut f: F) {
+        self.for_each_leaf(|snode| f(&snode.key, &snode.value));
+    }
+
+    /// Call `f` on every leaf in the snapshot, which holds a key with its value and hash.
+    fn for_each_leaf<F: FnMut(&SNode<K, V, C::Hash>)>(&self, mut f: F) {
+        let guard = pin();
+        let root = self.trie.rdcss_read_root(false, &guard);
+        self.for_each_at(&root, &mut f, &guard);
+    }
+
+    fn for_each_at<F: FnMut(&SNode<K, V, C::Hash>)>(&self, inode: &INode<K, V, C>, f: &mut F, guard: &Guard) {
+        let main = self.trie.gcas_read(inode, guard);
+        match &main.kind {
+            Kind::C(cnode) => {
+                for branch in cnode.array.iter() {
+                    match branch {
+                        Branch::I(sub) => self.for_each_at(sub, f, guard),
+                        Branch::S(snode) => f(snode),
+                    }
+                }
+            }
+            Kind::T(snode) => f(snode),
+            Kind::L(list) => list.iter().for_each(|snode| f(snode)),
+        }
+    }
+
+    /// Count the entries of the snapshot. This visits every entry.
+    pub fn len(&self) -> usize {
+        let mut len = 0;
+        self.for_each(|_, _| len += 1);
+        len
+    }
+
+    /// Check if the snapshot has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K, V, C> CtrieSnapshot<K, V, C>
+where
+    K: Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    C: HamtConfig + 'static,
+{
+    /// Get a copy of the value stored at key if it exists, otherwise return `None`.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.trie.get(key)
+    }
+
+    /// Copy the snapshot into a [`HAMT`](HAMT) with the same trie shape.
+    /// The trie is built in one pass from the keys and their stored hashes.
+    pub fn to_hamt(&self) -> HAMT<K, V, C>
+    where
+        K: Clone,
+    {
+        let mut items = Vec::new();
+        self.for_each_leaf(|snode| items.push((snode.hash, snode.key.clone(), snode.value.clone())));
+        HAMT {
+            root: NodePtr::new(build_node(items, 0)),
+        }
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, C> fmt::Debug for CtrieSnapshot<K, V, C>
+where
+    K: Send + Sync + 'static,
+    V: Send + Sync + 'static,
+    C: HamtConfig + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        self.for_each(|k, v| {
+            map.entry(k, v);
+        });
+        map.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ctrie;
+    use crate::test_util::{build, Colliding};
+    use crate::{Config, HAMT};
+    use std::thread;
+
+    #[test]
+    fn insert_get_remove() {
+        let trie = Ctrie::new();
+        for k in 0..5000 {
+            assert_eq!(trie.insert(k, -k), None);
+        }
+        assert_eq!(trie.insert(7, 7), Some(-7));
+        for k in (0..5000).step_by(2) {
+            assert_eq!(trie.remove(&k), Some(if k == 7 { 7 } else { -k }));
+        }
+        assert_eq!(trie.remove(&0), None);
+        for k in 0..5000 {
+            assert_eq!(
+                trie.get(&k),
+                if k % 2 == 0 {
+                    None
+                } else if k == 7 {
+                    Some(7)
+                } else {
+                    Some(-k)
+                }
+            );
+        }
+        assert_eq!(trie.snapshot().len(), 2500);
+    }
+
+    #[test]
+    fn collisions() {
+        let trie: Ctrie<Colliding, i32, Config<6, u128>> = Ctrie::with_config();
+        for id in 0..20 {
+            trie.insert(
+                Colliding {
+                    bucket: (id % 2) as u8,
+                    id,
+                },
+                id,
+            );
+        }
+        for id in 0..20 {
+            assert_eq!(
+                trie.get(&Colliding {
+                    bucket: (id % 2) as u8,
+                    id
+                }),
+                Some(id)
+            );
+        }
+        let map = (0..20).fold(HAMT::with_config(), |map, id| map.insert(Colliding { bucket: (id % 2) as u8, id }, id));
+        assert_eq!(trie.snapshot().to_hamt(), map);
+        for id in 0..19 {
+            assert_eq!(
+                trie.remove(&Colliding {
+                    bucket: (id % 2) as u8,
+                    id
+                }),
+                Some(id)
+            );
+        }
+        assert_eq!(trie.get(&Colliding { bucket: 1, id: 19 }), Some(19));
+        assert_eq!(trie.snapshot().len(), 1);
+    }
+
+    #[test]
+    fn snapshot_isolation() {
+        let trie = Ctrie::new();
+        for k in 0..1000 {
+            trie.insert(k, k);
+        }
+        let snapshot = trie.snapshot();
+        for k in 0..1000 {
+            trie.insert(k, k + 1);
+        }
+        trie.remove(&0);
+        trie.insert(1000, 0);
+        for k in 0..1000 {
+            assert_eq!(snapshot.get(&k), Some(k));
+            assert_eq!(trie.get(&k), if k == 0 { None } else { Some(k + 1) });
+        }
+        assert!(!snapshot.contains_key(&1000));
+        assert_eq!(snapshot.to_hamt(), build(0..1000));
+        assert_eq!(snapshot.len(), 1000);
+        assert_eq!(trie.snapshot().len(), 1000);
+    }
+
+    #[test]
+    fn concurrent_inserts_and_removes() {
+        let trie = Ctrie::new();
+        let threads = 8;
+        let per_thread = 2000;
+        thread::scope(|s| {
+            for t in 0..threads {
+                let trie = &trie;
+                s.spawn(move || {
+                    for i in 0..per_thread {
+                        trie.insert(t * per_thread + i, t);
+                    }
+                    for i in (0..per_thread).step_by(2) {
+                        assert_eq!(trie.remove(&(t * per_thread + i)), Some(t));
+                    }
+                });
+            }
+        });
+        for t in 0..threads {
+            for i in 0..per_thread {
+                let expected = if i % 2 == 0 { None } else { Some(t) };
+                assert_eq!(trie.get(&(t * per_thread + i)), expected);
+            }
+        }
+        assert_eq!(trie.snapshot().len(), (threads * per_thread / 2) as usize);
+    }
+
+    #[test]
+    fn concurrent_snapshots_are_consistent() {
+        let trie = Ctrie::new();
+        let n = 5000;
+        thread::scope(|s| {
+            // The writer inserts keys in order, so every snapshot must hold a prefix of them.
+            s.spawn(|| {
+                for k in 0..n {
+                    trie.insert(k, k);
+                }
+            });
+            for _ in 0..3 {
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        let snapshot = trie.snapshot();
+                        let len = snapshot.len() as i32;
+                        for k in 0..len {
+                            assert_eq!(snapshot.get(&k), Some(k));
+                        }
+                        assert!(!snapshot.contains_key(&len));
+                    }
+                });
+            }
+        });
+        assert_eq!(trie.snapshot().len(), n as usize);
+    }
+
+    #[test]
+    fn concurrent_updates_of_same_keys() {
+        let trie = Ctrie::new();
+        thread::scope(|s| {
+            for t in 0..4 {
+                let trie = &trie;
+                s.spawn(move || {
+                    for round in 0..200 {
+                        for k in 0..50 {
+                            if (round + t) % 3 == 0 {
+                                trie.remove(&k);
+                            } else {
+                                trie.insert(k, t);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        for k in 0..50 {
+            if let Some(t) = trie.get(&k) {
+                assert!((0..4).contains(&t));
+            }
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Resumable iteration over the entries of a map.
+//!
+//! A [`Cursor`] visits the entries in trie order, like [`Iter`](crate::Iter), but holds its own
+//! pointers to the nodes it visits rather than borrowing the map. At any point it can be turned into
+//! a [`Position`], which identifies the next entry by the path of hash fragments leading to it, and
+//! its offset in a chain. [`HAMT::iter_from`] goes down that path to resume iterating, in the same
+//! map or in any later version of it.
+
+use std::convert::TryInto;
+use std::iter::FusedIterator;
+
+use crate::{fragment, get_entries_index, push_fragment, Bitmap, DefaultConfig, HAMTNodeEntry, HamtConfig, HashWord, NodePtr, HAMT};
+
+/// Where a [`Cursor`] stands in the trie: the hash of the next key to visit, whose fragments are the path to
+/// it, and the offset of that key in its chain. Positions are ordered like the entries they point to.
+///
+/// A position can be stored as an opaque token with [`to_bytes`](Position::to_bytes).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    hash: u128,
+    offset: u64,
+}
+
+impl Position {
+    /// The position of a cursor that visited every entry. No hash or chain reaches it.
+    const END: Position = Position {
+        hash: u128::MAX,
+        offset: u64::MAX,
+    };
+
+    /// Encode the position as 24 bytes.
+    pub fn to_bytes(&self) -> [u8; 24] {
+        let mut bytes = [0; 24];
+        bytes[..16].copy_from_slice(&self.hash.to_le_bytes());
+        bytes[16..].copy_from_slice(&self.offset.to_le_bytes());
+        bytes
+    }
+
+    /// Decode a position encoded by [`to_bytes`](Position::to_bytes).
+    pub fn from_bytes(bytes: [u8; 24]) -> Self {
+        let mut hash = [0; 16];
+        let mut offset = [0; 8];
+        hash.copy_from_slice(&bytes[..16]);
+        offset.copy_from_slice(&bytes[16..]);
+        Position {
+            hash: u128::from_le_bytes(hash),
+            offset: u64::from_le_bytes(offset),
+        }
+    }
+
+    /// Check if this is the position of a cursor that visited every entry.
+    pub fn is_end(&self) -> bool {
+        *self == Position::END
+    }
+}
+
+/// An iterator over clones of the keys and values of a [`HAMT`](HAMT), in trie order, that can be
+/// paused at a [`Position`] and resumed with [`HAMT::iter_from`].
+///
+/// The cursor holds its own pointer to the root of the map it was created from, so it does not
+/// borrow the map, and the version it visits stays alive for as long as the cursor does.
+pub struct Cursor<K, V, C: HamtConfig = DefaultConfig> {
+    /// The nodes from the root down to the entry being visited, with the fragment of that entry at each level.
+    /// A fragment past the last entry of a node means the node was fully visited.
+    stack: Vec<(NodePtr<K, V, C>, u32)>,
+    /// The offset in the chain being visited, if any.
+    offset: usize,
+}
+
+impl<K, V, C: HamtConfig> Clone for Cursor<K, V, C> {
+    fn clone(&self) -> Self {
+        Cursor {
+            stack: self.stack.clone(),
+            offset: self.offset,
+        }
+    }
+}
+
+impl<K, V, C: HamtConfig> Cursor<K, V, C> {
+    /// Move to the next entry to visit without visiting it, going down into nodes as needed.
+    /// Returns `false` if every entry was visited.
+    fn settle(&mut self) -> bool {
+        loop {
+            let depth = self.stack.len();
+            let (node, next) = self.stack.last_mut().unwrap();
+            let rest = node.presence_map.to_u64().checked_shr(*next).unwrap_or(0);
+            if rest == 0 {
+                if depth == 1 {
+                    return false;
+                }
+                self.stack.pop();
+                self.stack.last_mut().unwrap().1 += 1;
+                continue;
+            }
+            *next += rest.trailing_zeros();
+            let child = match &node.entries[get_entries_index(node.presence_map, *next)] {
+                HAMTNodeEntry::Value(..) => return true,
+                HAMTNodeEntry::Chained(vec) if self.offset < vec.len() => return true,
+                HAMTNodeEntry::Chained(_) => {
+                    self.offset = 0;
+                    *next += 1;
+                    continue;
+                }
+                HAMTNodeEntry::Node(child) => child.clone(),
+            };
+            self.stack.push((child, 0));
+        }
+    }
+}
+
+impl<K, V, C: HamtConfig> Cursor<K, V, C> {
+    /// The position of the next entry the cursor will visit.
+    pub fn position(&self) -> Position {
+        let mut cursor = self.clone();
+        if !cursor.settle() {
+            return Position::END;
+        }
+        let (node, frag) = cursor.stack.last().unwrap();
+        match &node.entries[get_entries_index(node.presence_map, *frag)] {
+            HAMTNodeEntry::Value(_, _, hash) => Position {
+                hash: hash.to_u128(),
+                offset: 0,
+            },
+            HAMTNodeEntry::Chained(_) => Position {
+                hash: cursor.path_hash(),
+                offset: cursor.offset as u64,
+            },
+            HAMTNodeEntry::Node(_) => unreachable!(),
+        }
+    }
+
+    /// The hash made of the fragments on the path of the cursor. A chain is below every fragment
+    /// of the hash of its keys, so at a chain this is that hash.
+    fn path_hash(&self) -> u128 {
+        (0..).zip(&self.stack).fold(0, |hash, (level, (_, frag))| push_fragment::<C>(hash, *frag, level))
+    }
+}
+
+impl<K: Clone, V: Clone, C: HamtConfig> Iterator for Cursor<K, V, C> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.settle() {
+            return None;
+        }
+        let (node, next) = self.stack.last_mut().unwrap();
+        match &node.entries[get_entries_index(node.presence_map, *next)] {
+            HAMTNodeEntry::Value(k, v, _) => {
+                *next += 1;
+                Some((k.clone(), v.clone()))
+            }
+            HAMTNodeEntry::Chained(vec) => {
+                self.offset += 1;
+                Some(vec[self.offset - 1].clone())
+            }
+            HAMTNodeEntry::Node(_) => unreachable!(),
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, C: HamtConfig> FusedIterator for Cursor<K, V, C> {}
+
+impl<K, V, C: HamtConfig> HAMT<K, V, C> {
+    /// Iterate over clones of the keys and values of the map, with a cursor that can be paused and resumed.
+    pub fn cursor(&self) -> Cursor<K, V, C> {
+        Cursor {
+            stack: vec![(self.root.clone(), 0)],
+            offset: 0,
+        }
+    }
+}
+
+impl<K, V, C: HamtConfig> HAMT<K, V, C> {
+    /// Resume iterating from `position`, taken from a cursor over this map or over an earlier version of it.
+    /// This goes down the path of the position once, rather than visiting the entries before it.
+    ///
+    /// The cursor visits every entry that comes after the position in trie order. When resuming a later version,
+    /// entries inserted before the position are skipped, and so are entries of a chain that shifted back
+    /// because an entry was removed from it before the offset.
+    pub fn iter_from(&self, position: &Position) -> Cursor<K, V, C> {
+        let mut cursor = self.cursor();
+        let hash = match C::Hash::from_u128(position.hash) {
+            Some(hash) if !position.is_end() => hash,
+            _ => {
+                cursor.stack[0].1 = u64::BITS;
+                return cursor;
+            }
+        };
+        let mut level = 0;
+        loop {
+            let (node, next) = cursor.stack.last_mut().unwrap();
+            let frag = fragment::<C>(hash.shift(C::BITS * level));
+            *next = frag;
+            if !node.presence_map.contains(frag) {
+                // Every entry after this fragment comes after the position.
+                return cursor;
+            }
+            // A chain is below every fragment of the hash of its keys, which are those of the position.
+            let (entry_hash, chained) = match &node.entries[get_entries_index(node.presence_map, frag)] {
+                HAMTNodeEntry::Node(child) => {
+                    let child = child.clone();
+                    cursor.stack.push((child, 0));
+                    level += 1;
+                    continue;
+                }
+                HAMTNodeEntry::Value(_, _, entry_hash) => (*entry_hash, false),
+                HAMTNodeEntry::Chained(_) => (hash, true),
+            };
+            if entry_hash == hash && chained {
+                cursor.offset = position.offset.try_into().unwrap_or(usize::MAX);
+            } else if entry_hash < hash || (entry_hash == hash && position.offset > 0) {
+                *next += 1;
+            }
+            return cursor;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Position;
+    use crate::HAMT;
+    use crate::test_util::{build, Colliding};
+    use std::collections::HashSet;
+
+    #[test]
+    fn paginate() {
+        let map = (0..2000).fold(HAMT::new(), |map, k| map.insert(k, k * 2));
+        let expected: Vec<(i32, i32)> = map.iter().map(|(k, v)| (*k, *v)).collect();
+        let mut pages = Vec::new();
+        let mut token = map.cursor().position().to_bytes();
+        loop {
+            let mut cursor = map.iter_from(&Position::from_bytes(token));
+            let page: Vec<(i32, i32)> = cursor.by_ref().take(150).collect();
+            token = cursor.position().to_bytes();
+            if page.is_empty() {
+                break;
+            }
+            pages.extend(page);
+        }
+        assert_eq!(pages, expected);
+        assert!(Position::from_bytes(token).is_end());
+    }
+
+    #[test]
+    fn cursor_owns_its_version() {
+        let map = build(0..100);
+        let mut cursor = map.cursor();
+        let first = cursor.next().unwrap();
+        let position = cursor.position();
+        drop(map);
+        assert_eq!(cursor.position(), position);
+        assert!(!cursor.by_ref().any(|item| item == first));
+        assert!(cursor.position().is_end());
+        assert_eq!(cursor.next(), None);
+    }
+
+    #[test]
+    fn resume_later_version() {
+        let old = build(0..1000);
+        let mut cursor = old.cursor();
+        let seen: HashSet<i32> = cursor.by_ref().take(400).map(|(k, _)| k).collect();
+        let position = cursor.position();
+        let mut new = old.clone();
+        for k in (0..1000).step_by(7) {
+            new = new.remove(k);
+        }
+        for k in 1000..1500 {
+            new = new.insert(k, k);
+        }
+        let resumed: Vec<i32> = new.iter_from(&position).map(|(k, _)| k).collect();
+        assert!(resumed.iter().all(|k| !seen.contains(k)));
+        // Every key kept since the old version is listed once across both pages.
+        for k in (0..1000).filter(|k| k % 7 != 0) {
+            assert!(seen.contains(&k) != resumed.contains(&k));
+        }
+        // The resumed page is the end of the new version, so keys inserted since are listed if they come after the position.
+        let order: Vec<i32> = new.iter().map(|(k, _)| *k).collect();
+        assert_eq!(order[order.len() - resumed.len()..], resumed[..]);
+    }
+
+    #[test]
+    fn resume_inside_chains() {
+        let key = |id| Colliding { bucket: (id % 4) as u8, id };
+        let map = (0..40).fold(HAMT::new(), |map, id| map.insert(key(id), id));
+        let mut ids = Vec::new();
+        let mut position = map.cursor().position();
+        while !position.is_end() {
+            let mut cursor = map.iter_from(&position);
+            ids.extend(cursor.next().map(|(_, v)| v));
+            position = cursor.position();
+        }
+        assert_eq!(ids, map.iter().map(|(_, v)| *v).collect::<Vec<_>>());
+        assert_eq!(ids.len(), 40);
+    }
+}
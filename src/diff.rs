@@ -0,0 +1,149 @@
+//! Structural comparison of two versions of a map.
+//!
+//! Versions derived from one another share every subtree that was not modified in between,
+//! so the comparison skips shared nodes by pointer and only visits the modified paths.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::{for_each_in_entry, get_entries_index, Bitmap, HAMTNodeEntry, HamtConfig, NodePtr, HAMT};
+
+/// A difference between two maps, as listed by [`HAMT::diff`](HAMT::diff).
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change<'a, K, V> {
+    /// The key is only in the new map.
+    Added(&'a K, &'a V),
+    /// The key is only in the old map.
+    Removed(&'a K, &'a V),
+    /// The key is in both maps, with a different value: the old value, then the new one.
+    Updated(&'a K, &'a V, &'a V),
+}
+
+/// Compare two nodes at the same level.
+fn diff_nodes<'a, K: Hash + Eq, V: PartialEq, C: HamtConfig>(
+    old: &'a NodePtr<K, V, C>,
+    new: &'a NodePtr<K, V, C>,
+    changes: &mut Vec<Change<'a, K, V>>,
+) {
+    if NodePtr::ptr_eq(old, new) {
+        return;
+    }
+    let mut present = old.presence_map.to_u64() | new.presence_map.to_u64();
+    while present != 0 {
+        let frag = present.trailing_zeros();
+        present &= present - 1;
+        let old_entry = old
+            .presence_map
+            .contains(frag)
+            .then(|| &old.entries[get_entries_index(old.presence_map, frag)]);
+        let new_entry = new
+            .presence_map
+            .contains(frag)
+            .then(|| &new.entries[get_entries_index(new.presence_map, frag)]);
+        match (old_entry, new_entry) {
+            (Some(old_entry), None) => for_each_in_entry(old_entry, &mut |k, v| changes.push(Change::Removed(k, v))),
+            (None, Some(new_entry)) => for_each_in_entry(new_entry, &mut |k, v| changes.push(Change::Added(k, v))),
+            (Some(old_entry), Some(new_entry)) => diff_entries(old_entry, new_entry, changes),
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+/// Compare two entries for the same hash prefix.
+fn diff_entries<'a, K: Hash + Eq, V: PartialEq, C: HamtConfig>(
+    old: &'a HAMTNodeEntry<K, V, C>,
+    new: &'a HAMTNodeEntry<K, V, C>,
+    changes: &mut Vec<Change<'a, K, V>>,
+) {
+    match (old, new) {
+        (HAMTNodeEntry::Node(old), HAMTNodeEntry::Node(new)) => diff_nodes(old, new, changes),
+        (HAMTNodeEntry::Value(k1, v1, _), HAMTNodeEntry::Value(k2, v2, _)) if k1 == k2 => {
+            if v1 != v2 {
+                changes.push(Change::Updated(k1, v1, v2));
+            }
+        }
+        (old, new) => {
+            // The entries have different shapes, or store different keys, so compare what they store.
+            let mut old_items = HashMap::new();
+            for_each_in_entry(old, &mut |k, v| {
+                old_items.insert(k, v);
+            });
+            for_each_in_entry(new, &mut |k, v| match old_items.remove(k) {
+                Some(old_v) if old_v != v => changes.push(Change::Updated(k, old_v, v)),
+                Some(_) => {}
+                None => changes.push(Change::Added(k, v)),
+            });
+            changes.extend(old_items.into_iter().map(|(k, v)| Change::Removed(k, v)));
+        }
+    }
+}
+
+impl<K: Hash + Eq, V: PartialEq, C: HamtConfig> HAMT<K, V, C> {
+    /// List the changes that turn `self` into `other`, in no particular order.
+    ///
+    /// Subtrees shared by both maps are skipped, so comparing a map with a version derived from
+    /// it takes time proportional to the number of changes, rather than to the size of the maps.
+    pub fn diff<'a>(&'a self, other: &'a Self) -> Vec<Change<'a, K, V>> {
+        let mut changes = Vec::new();
+        diff_nodes(&self.root, &other.root, &mut changes);
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Change;
+    use crate::HAMT;
+    use crate::test_util::{build, Colliding};
+
+    fn sorted<'a>(mut changes: Vec<Change<'a, i32, i32>>) -> Vec<Change<'a, i32, i32>> {
+        let key = |change: &Change<i32, i32>| match change {
+            Change::Added(k, _) | Change::Removed(k, _) | Change::Updated(k, _, _) => **k,
+        };
+        changes.sort_by_key(key);
+        changes
+    }
+
+    #[test]
+    fn diff_versions() {
+        let mut old = HAMT::new();
+        for k in 0..1000 {
+            old = old.insert(k, k);
+        }
+        let new = old.insert(5, 50).insert(1000, 0).remove(7).insert(8, 8);
+        assert!(new.diff(&new).is_empty());
+        assert_eq!(
+            sorted(old.diff(&new)),
+            vec![Change::Updated(&5, &5, &50), Change::Removed(&7, &7), Change::Added(&1000, &0)]
+        );
+        assert_eq!(
+            sorted(new.diff(&old)),
+            vec![Change::Updated(&5, &50, &5), Change::Added(&7, &7), Change::Removed(&1000, &0)]
+        );
+    }
+
+    #[test]
+    fn diff_independent_maps() {
+        // Maps built separately share no nodes, and may differ in shape.
+        let a = build(0..500);
+        let b = build((0..500).rev()).remove(3);
+        assert_eq!(sorted(a.diff(&b)), vec![Change::Removed(&3, &3)]);
+    }
+
+    #[test]
+    fn diff_chains() {
+        let key = |id| Colliding { bucket: 0, id };
+        let old = (0..5).fold(HAMT::new(), |map, id| map.insert(key(id), id));
+        let new = old.remove(key(2)).insert(key(3), 30).insert(key(5), 5);
+        let mut changes: Vec<_> = old.diff(&new).into_iter().map(|change| format!("{:?}", change)).collect();
+        changes.sort();
+        assert_eq!(
+            changes,
+            vec![
+                "Added(Colliding { bucket: 0, id: 5 }, 5)",
+                "Removed(Colliding { bucket: 0, id: 2 }, 2)",
+                "Updated(Colliding { bucket: 0, id: 3 }, 3, 30)",
+            ]
+        );
+    }
+}
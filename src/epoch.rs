@@ -0,0 +1,336 @@
+//! Epoch-based memory reclamation, used by the concurrent trie.
+//!
+//! Nodes of the concurrent trie are reference counted, and reachable through atomic pointers
+//! ([`AtomicArc`](AtomicArc)). Loading such a pointer and incrementing the count of what it points
+//! to are two steps, so when a pointer is swapped out its count cannot be released right away:
+//! a concurrent reader may have read the old pointer and not incremented the count yet.
+//!
+//! Readers [`pin`](pin) the current thread for the duration of an operation, which records the
+//! global epoch they observed. Swapped out pointers are retired with the epoch they were retired in,
+//! and their count is released once the global epoch has advanced twice since then: the epoch only
+//! advances when every pinned thread has observed the current one, so by then no reader can still
+//! be holding the old pointer.
+
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering::SeqCst};
+use std::sync::{Arc, Mutex};
+
+/// The global epoch.
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+
+/// Every thread that has pinned itself. Entries are never freed, only reused by later threads.
+static PARTICIPANTS: AtomicPtr<Participant> = AtomicPtr::new(ptr::null_mut());
+
+/// Garbage left behind by threads that exited before it could be released.
+static ORPHANS: Mutex<Vec<Deferred>> = Mutex::new(Vec::new());
+
+/// Flag set in a participant's state while it is pinned. The rest of the state is the epoch it observed.
+const PINNED: usize = 1;
+
+/// Number of retirements between attempts to advance the epoch and release garbage.
+const COLLECT_EVERY: usize = 64;
+
+struct Participant {
+    state: AtomicUsize,
+    in_use: AtomicBool,
+    next: *mut Participant,
+}
+
+/// A retired `Arc`, released once the global epoch reaches `epoch + 2`.
+struct Deferred {
+    epoch: usize,
+    ptr: *const (),
+    release: unsafe fn(*const ()),
+}
+
+// Deferred values are only created for `Arc`s of `Send + Sync` types.
+unsafe impl Send for Deferred {}
+
+unsafe fn release_arc<T>(ptr: *const ()) {
+    drop(Arc::from_raw(ptr as *const T));
+}
+
+struct Local {
+    participant: &'static Participant,
+    guards: Cell<usize>,
+    bag: RefCell<Vec<Deferred>>,
+}
+
+impl Local {
+    /// Claim an unused participant, or add a new one.
+    fn register() -> Local {
+        let mut cur = PARTICIPANTS.load(SeqCst);
+        while let Some(participant) = unsafe { cur.as_ref() } {
+            if participant
+                .in_use
+                .compare_exchange(false, true, SeqCst, SeqCst)
+                .is_ok()
+            {
+                return Local::with(participant);
+            }
+            cur = participant.next;
+        }
+        let participant = Box::leak(Box::new(Participant {
+            state: AtomicUsize::new(0),
+            in_use: AtomicBool::new(true),
+            next: ptr::null_mut(),
+        }));
+        let mut head = PARTICIPANTS.load(SeqCst);
+        loop {
+            participant.next = head;
+            match PARTICIPANTS.compare_exchange(head, participant, SeqCst, SeqCst) {
+                Ok(_) => return Local::with(participant),
+                Err(new_head) => head = new_head,
+            }
+        }
+    }
+
+    fn with(participant: &'static Participant) -> Local {
+        Local {
+            participant,
+            guards: Cell::new(0),
+            bag: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        self.participant.state.store(0, SeqCst);
+        let bag = std::mem::take(&mut *self.bag.borrow_mut());
+        ORPHANS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend(bag);
+        self.participant.in_use.store(false, SeqCst);
+    }
+}
+
+thread_local! {
+    static LOCAL: Local = Local::register();
+}
+
+/// Advance the global epoch if every pinned thread has observed it.
+fn try_advance() -> usize {
+    let epoch = EPOCH.load(SeqCst);
+    let mut cur = PARTICIPANTS.load(SeqCst);
+    while let Some(participant) = unsafe { cur.as_ref() } {
+        let state = participant.state.load(SeqCst);
+        if state & PINNED != 0 && state >> 1 != epoch {
+            return epoch;
+        }
+        cur = participant.next;
+    }
+    match EPOCH.compare_exchange(epoch, epoch + 1, SeqCst, SeqCst) {
+        Ok(_) => epoch + 1,
+        Err(current) => current,
+    }
+}
+
+/// Release the garbage of `bag` that no reader can reach anymore.
+fn collect_from(bag: &mut Vec<Deferred>, epoch: usize) -> Vec<Deferred> {
+    let mut expired = Vec::new();
+    let mut i = 0;
+    while i < bag.len() {
+        if bag[i].epoch + 2 <= epoch {
+            expired.push(bag.swap_remove(i));
+        } else {
+            i += 1;
+        }
+    }
+    expired
+}
+
+fn release(expired: Vec<Deferred>) {
+    for deferred in expired {
+        unsafe { (deferred.release)(deferred.ptr) };
+    }
+}
+
+fn collect(local: &Local) {
+    let epoch = try_advance();
+    // Take the garbage out first, as releasing it may run arbitrary drops.
+    let expired = collect_from(&mut local.bag.borrow_mut(), epoch);
+    release(expired);
+    let orphans = ORPHANS
+        .try_lock()
+        .map(|mut orphans| collect_from(&mut orphans, epoch));
+    if let Ok(orphans) = orphans {
+        release(orphans);
+    }
+}
+
+/// Proof that the current thread is pinned. Pointers loaded while it is alive stay valid.
+pub(crate) struct Guard {
+    // Pinning is per-thread.
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Pin the current thread until the returned guard is dropped.
+pub(crate) fn pin() -> Guard {
+    LOCAL.with(|local| {
+        let guards = local.guards.get();
+        if guards == 0 {
+            let epoch = EPOCH.load(SeqCst);
+            local.participant.state.store((epoch << 1) | PINNED, SeqCst);
+            fence(SeqCst);
+        }
+        local.guards.set(guards + 1);
+    });
+    Guard {
+        _not_send: PhantomData,
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        // During thread teardown the participant is already released.
+        let _ = LOCAL.try_with(|local| {
+            let guards = local.guards.get() - 1;
+            local.guards.set(guards);
+            if guards == 0 {
+                local.participant.state.store(0, SeqCst);
+                if local.bag.borrow().len() >= COLLECT_EVERY {
+                    collect(local);
+                }
+            }
+        });
+    }
+}
+
+/// Release the count held by `ptr` once no pinned thread can still be reading it.
+fn retire<T: Send + Sync + 'static>(ptr: *const T, _guard: &Guard) {
+    let deferred = Deferred {
+        epoch: EPOCH.load(SeqCst),
+        ptr: ptr as *const (),
+        release: release_arc::<T>,
+    };
+    let kept = LOCAL.try_with(|local| local.bag.borrow_mut().push(deferred));
+    if kept.is_err() {
+        // This thread is exiting, hand the garbage to the other threads.
+        ORPHANS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Deferred {
+                epoch: EPOCH.load(SeqCst),
+                ptr: ptr as *const (),
+                release: release_arc::<T>,
+            });
+    }
+}
+
+/// An atomic, nullable pointer to an `Arc`, which owns one count of what it points to.
+pub(crate) struct AtomicArc<T> {
+    ptr: AtomicPtr<T>,
+    _marker: PhantomData<Arc<T>>,
+}
+
+fn into_raw<T>(value: Option<Arc<T>>) -> *mut T {
+    value.map_or(ptr::null_mut(), |arc| Arc::into_raw(arc) as *mut T)
+}
+
+fn as_raw<T>(value: Option<&Arc<T>>) -> *mut T {
+    value.map_or(ptr::null_mut(), |arc| Arc::as_ptr(arc) as *mut T)
+}
+
+impl<T: Send + Sync + 'static> AtomicArc<T> {
+    pub(crate) fn new(value: Option<Arc<T>>) -> Self {
+        AtomicArc {
+            ptr: AtomicPtr::new(into_raw(value)),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Load the current value.
+    pub(crate) fn load(&self, _guard: &Guard) -> Option<Arc<T>> {
+        let ptr = self.ptr.load(SeqCst);
+        if ptr.is_null() {
+            None
+        } else {
+            // The count owned by this pointer is only released two epochs after it is swapped out,
+            // and this thread is pinned, so the value is still alive.
+            unsafe {
+                Arc::increment_strong_count(ptr);
+                Some(Arc::from_raw(ptr))
+            }
+        }
+    }
+
+    /// Replace the value with `new` if it is still `current` (compared by pointer).
+    /// On failure `new` is handed back.
+    pub(crate) fn compare_exchange(
+        &self,
+        current: Option<&Arc<T>>,
+        new: Option<Arc<T>>,
+        guard: &Guard,
+    ) -> Result<(), Option<Arc<T>>> {
+        let new = into_raw(new);
+        match self
+            .ptr
+            .compare_exchange(as_raw(current), new, SeqCst, SeqCst)
+        {
+            Ok(old) => {
+                if !old.is_null() {
+                    retire(old as *const T, guard);
+                }
+                Ok(())
+            }
+            Err(_) => Err(if new.is_null() {
+                None
+            } else {
+                Some(unsafe { Arc::from_raw(new) })
+            }),
+        }
+    }
+}
+
+impl<T> Drop for AtomicArc<T> {
+    fn drop(&mut self) {
+        // Whoever drops the pointer owns it, so no reader can be loading from it.
+        let ptr = *self.ptr.get_mut();
+        if !ptr.is_null() {
+            unsafe { drop(Arc::from_raw(ptr)) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pin, AtomicArc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct Counted<'a>(&'a AtomicUsize);
+
+    impl Drop for Counted<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn retired_values_are_released() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let cell = AtomicArc::new(Some(Arc::new(Counted(&DROPS))));
+        for _ in 0..1000 {
+            let guard = pin();
+            let current = cell.load(&guard);
+            assert!(cell
+                .compare_exchange(current.as_ref(), Some(Arc::new(Counted(&DROPS))), &guard)
+                .is_ok());
+        }
+        // Other tests may keep the epoch from advancing for a while, but not forever.
+        for _ in 0..100_000 {
+            if DROPS.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+            let guard = pin();
+            let current = cell.load(&guard);
+            let _ = cell.compare_exchange(current.as_ref(), current.clone(), &guard);
+        }
+        assert!(DROPS.load(Ordering::SeqCst) > 0);
+        drop(cell);
+    }
+}
@@ -0,0 +1,617 @@
+//! A flat, read-only file format for maps, that is queried in place rather than deserialized.
+//!
+//! Keys and values are stored as bytes. The file is laid out as nodes with presence maps like the
+//! in-memory trie, whose entries point to their records and child nodes by relative offsets, so the
+//! file can be memory-mapped and queried directly. The trie is indexed by the hashes of the encoded
+//! keys, so [`FlatMap`] only needs bytes to look a key up.
+//!
+//! Layout, with integers in little endian:
+//!
+//! - header: the magic bytes `HAMTFLAT`, the format version (`u32`), the bits per level and bits of
+//!   the hash of the configuration, and the identifier of the hash function (`u8` each);
+//! - records and nodes, each written after everything it points to. A record is a key length, a value
+//!   length (`u32` each), then the key and value. A chain is a number of records (`u32`), then the
+//!   records. A node is a presence map (`u64`), then for each entry a `u64` holding the distance back
+//!   from the start of the node to its target, shifted left by two bits, with the kind of the target in
+//!   the low bits: `0` for a record, `1` for a chain, `2` for a node;
+//! - footer: the offset of the root node from the start of the file, and the number of entries (`u64` each).
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+
+use crate::hasher::HASH_ID;
+use crate::{fragment, Bitmap, DefaultConfig, HamtConfig, HashWord, HAMT};
+
+const MAGIC: &[u8; 8] = b"HAMTFLAT";
+const VERSION: u32 = 2;
+const HEADER_LEN: usize = 15;
+const FOOTER_LEN: usize = 16;
+
+const KIND_RECORD: u64 = 0;
+const KIND_CHAIN: u64 = 1;
+const KIND_NODE: u64 = 2;
+
+/// A key or value that can be stored in a flat file, as bytes.
+///
+/// Distinct keys must have distinct encodings.
+pub trait FlatField {
+    /// Append the bytes of `self` to `out`.
+    fn write_flat(&self, out: &mut Vec<u8>);
+}
+
+/// A value with a fixed-size encoding, that can be read back from the bytes stored in a flat file.
+pub trait FixedLayout: FlatField + Sized {
+    /// Length of the encoding.
+    const SIZE: usize;
+
+    /// Decode a value from exactly `SIZE` bytes.
+    fn read_flat(bytes: &[u8]) -> Self;
+}
+
+impl FlatField for [u8] {
+    fn write_flat(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self)
+    }
+}
+
+impl FlatField for Vec<u8> {
+    fn write_flat(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self)
+    }
+}
+
+impl FlatField for str {
+    fn write_flat(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes())
+    }
+}
+
+impl FlatField for String {
+    fn write_flat(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes())
+    }
+}
+
+impl<T: FlatField + ?Sized> FlatField for &T {
+    fn write_flat(&self, out: &mut Vec<u8>) {
+        (**self).write_flat(out)
+    }
+}
+
+macro_rules! impl_fixed_layout_int {
+    ($($t:ty),*) => {
+        $(
+            impl FlatField for $t {
+                fn write_flat(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes())
+                }
+            }
+
+            impl FixedLayout for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+
+                fn read_flat(bytes: &[u8]) -> Self {
+                    let mut array = [0; std::mem::size_of::<$t>()];
+                    array.copy_from_slice(bytes);
+                    <$t>::from_le_bytes(array)
+                }
+            }
+        )*
+    };
+}
+
+impl_fixed_layout_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+/// Why a flat file could not be opened.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FlatError {
+    /// The file does not start with the magic bytes of a flat map.
+    NotAFlatMap,
+    /// The file was written in a version of the format this version of the crate cannot read.
+    UnsupportedVersion(u32),
+    /// The file was written for another configuration: its bits per level and bits of the hash.
+    ConfigMismatch { bits: u8, hash_bits: u8 },
+    /// The keys were hashed with a hash function this version of the crate does not use, given by its identifier.
+    UnsupportedHash(u8),
+    /// Something at `offset` points or extends past the bounds it must stay within.
+    OutOfBounds { offset: u64 },
+    /// The structure at `offset` is invalid; the message says how.
+    Invalid { offset: u64, message: &'static str },
+}
+
+impl fmt::Display for FlatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlatError::NotAFlatMap => write!(f, "not a flat map"),
+            FlatError::UnsupportedVersion(version) => write!(f, "unsupported flat map version {}", version),
+            FlatError::ConfigMismatch { bits, hash_bits } => write!(
+                f,
+                "flat map for {} bits per level and {}-bit hashes",
+                bits, hash_bits
+            ),
+            FlatError::UnsupportedHash(id) => write!(f, "flat map with keys hashed by unknown hash function {}", id),
+            FlatError::OutOfBounds { offset } => write!(f, "offset out of bounds at {}", offset),
+            FlatError::Invalid { offset, message } => write!(f, "invalid flat map at {}: {}", offset, message),
+        }
+    }
+}
+
+impl Error for FlatError {}
+
+/// A key and value to write, with the hash of the encoded key.
+type Item<'a, K, V, C> = (<C as HamtConfig>::Hash, &'a K, &'a V);
+
+/// Writes the records and nodes of a flat file, counting the bytes written so far.
+struct Writer<W: Write> {
+    out: W,
+    pos: u64,
+    /// Buffer the key and value of a record are encoded into, reused between records.
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Writer<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.pos += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn record<K: FlatField, V: FlatField>(&mut self, key: &K, value: &V) -> io::Result<()> {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        key.write_flat(&mut buf);
+        let key_len = buf.len();
+        value.write_flat(&mut buf);
+        let too_long = || io::Error::new(io::ErrorKind::InvalidInput, "key or value longer than 4 GiB");
+        let value_len = u32::try_from(buf.len() - key_len).map_err(|_| too_long())?;
+        let key_len = u32::try_from(key_len).map_err(|_| too_long())?;
+        self.write(&key_len.to_le_bytes())?;
+        self.write(&value_len.to_le_bytes())?;
+        self.write(&buf)?;
+        self.buf = buf;
+        Ok(())
+    }
+
+    /// Write the node at `level` storing `items`, which are sorted by hash, after everything it points
+    /// to, and return its offset. The node has the shape `build_node` would give it.
+    fn node<K: FlatField, V: FlatField, C: HamtConfig>(&mut self, items: &[Item<'_, K, V, C>], level: u32) -> io::Result<u64> {
+        let frag_at = |hash: C::Hash| fragment::<C>(hash.shift(C::BITS * level));
+        let mut presence_map = C::Bitmap::EMPTY;
+        let mut targets = Vec::new();
+        let mut rest = items;
+        // Sorting by hash sorts by fragment at every level, so the items of each entry are contiguous.
+        while let Some((hash, _, _)) = rest.first() {
+            let frag = frag_at(*hash);
+            let (group, after) = rest.split_at(rest.partition_point(|(hash, _, _)| frag_at(*hash) == frag));
+            rest = after;
+            presence_map = presence_map.with(frag);
+            let offset = self.pos;
+            targets.push(if group.len() == 1 {
+                self.record(group[0].1, group[0].2)?;
+                (offset, KIND_RECORD)
+            } else if level + 1 == C::MAX_DEPTH {
+                self.write(&(group.len() as u32).to_le_bytes())?;
+                for (_, k, v) in group {
+                    self.record(*k, *v)?;
+                }
+                (offset, KIND_CHAIN)
+            } else {
+                (self.node::<K, V, C>(group, level + 1)?, KIND_NODE)
+            });
+        }
+        let offset = self.pos;
+        let mut bytes = Vec::with_capacity(8 + targets.len() * 8);
+        bytes.extend_from_slice(&presence_map.to_u64().to_le_bytes());
+        for (target, kind) in targets {
+            bytes.extend_from_slice(&((offset - target) << 2 | kind).to_le_bytes());
+        }
+        self.write(&bytes)?;
+        Ok(offset)
+    }
+}
+
+/// Write the entries of `map` to `out` as a flat file, to be opened with [`FlatMap::open`].
+///
+/// The file is written sequentially, so `out` may be a buffered file of any size. The trie of the
+/// file is indexed by the hashes of the encoded keys rather than of the keys, so the entries are first
+/// sorted by those hashes: this takes a hash and two references per entry in memory, while keys and
+/// values are encoded as they are written.
+pub fn write_flat<K: FlatField, V: FlatField, C: HamtConfig, W: Write>(map: &HAMT<K, V, C>, out: W) -> io::Result<()> {
+    let mut buf = Vec::new();
+    let mut items: Vec<Item<'_, K, V, C>> = map
+        .iter()
+        .map(|(k, v)| {
+            buf.clear();
+            k.write_flat(&mut buf);
+            (C::Hash::hash_of(&buf[..]), k, v)
+        })
+        .collect();
+    // The sort is stable, so chained keys keep the order they have in the map.
+    items.sort_by_key(|(hash, _, _)| *hash);
+
+    let mut writer = Writer { out, pos: 0, buf };
+    writer.write(MAGIC)?;
+    writer.write(&VERSION.to_le_bytes())?;
+    writer.write(&[C::BITS as u8, C::Hash::BITS as u8, HASH_ID])?;
+    let root = writer.node::<K, V, C>(&items, 0)?;
+    writer.write(&root.to_le_bytes())?;
+    writer.write(&(items.len() as u64).to_le_bytes())?;
+    writer.out.flush()
+}
+
+/// Read a little endian `u32` at `offset`, which must be in bounds.
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut array = [0; 4];
+    array.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(array)
+}
+
+/// Read a little endian `u64` at `offset`, which must be in bounds.
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut array = [0; 8];
+    array.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(array)
+}
+
+/// A map stored in a flat file written by [`write_flat`], queried in place.
+///
+/// The bytes are usually a memory-mapped file. They are validated once by [`open`](FlatMap::open),
+/// after which queries cannot fail.
+pub struct FlatMap<'a, C: HamtConfig = DefaultConfig> {
+    bytes: &'a [u8],
+    root: usize,
+    len: usize,
+    config: PhantomData<C>,
+}
+
+impl<'a, C: HamtConfig> FlatMap<'a, C> {
+    /// Open the flat map stored in `bytes`, checking the header and that every offset and length of the
+    /// records and nodes reachable from the root stays within `bytes`.
+    pub fn open(bytes: &'a [u8]) -> Result<Self, FlatError> {
+        if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(FlatError::NotAFlatMap);
+        }
+        let version = u32_at(bytes, MAGIC.len());
+        if version != VERSION {
+            return Err(FlatError::UnsupportedVersion(version));
+        }
+        let (bits, hash_bits) = (bytes[12], bytes[13]);
+        if bits as u32 != C::BITS || hash_bits as u32 != C::Hash::BITS {
+            return Err(FlatError::ConfigMismatch { bits, hash_bits });
+        }
+        if bytes[14] != HASH_ID {
+            return Err(FlatError::UnsupportedHash(bytes[14]));
+        }
+        let end = bytes
+            .len()
+            .checked_sub(FOOTER_LEN)
+            .filter(|end| *end >= HEADER_LEN)
+            .ok_or(FlatError::OutOfBounds { offset: bytes.len() as u64 })?;
+        let root = u64_at(bytes, end);
+        if root < HEADER_LEN as u64 || root >= end as u64 {
+            return Err(FlatError::OutOfBounds { offset: end as u64 });
+        }
+        let map = FlatMap {
+            bytes: &bytes[..end],
+            root: root as usize,
+            len: u64_at(bytes, end + 8) as usize,
+            config: PhantomData,
+        };
+        let count = map.validate_node(map.root, 0, &mut HashSet::new())?;
+        if count != map.len as u64 {
+            return Err(FlatError::Invalid {
+                offset: end as u64 + 8,
+                message: "number of entries does not match the nodes",
+            });
+        }
+        Ok(map)
+    }
+
+    /// Check the node at `offset` and everything under it, and count its entries.
+    /// Every node is written for a single entry, so checking takes time proportional to the size of the file.
+    fn validate_node(&self, offset: usize, level: u32, visited: &mut HashSet<usize>) -> Result<u64, FlatError> {
+        let invalid = |message| FlatError::Invalid {
+            offset: offset as u64,
+            message,
+        };
+        if !visited.insert(offset) {
+            return Err(invalid("node is pointed to by several entries"));
+        }
+        if offset + 8 > self.bytes.len() {
+            return Err(FlatError::OutOfBounds { offset: offset as u64 });
+        }
+        let presence_map = u64_at(self.bytes, offset);
+        if C::BITS < 6 && presence_map >> (1 << C::BITS) != 0 {
+            return Err(invalid("presence map has bits past the last fragment"));
+        }
+        let entries = presence_map.count_ones() as usize;
+        if offset + 8 + entries * 8 > self.bytes.len() {
+            return Err(FlatError::OutOfBounds { offset: offset as u64 });
+        }
+        let mut count = 0;
+        for index in 0..entries {
+            let word = u64_at(self.bytes, offset + 8 + index * 8);
+            // Everything a node points to is written before it, so following offsets always terminates.
+            let distance = word >> 2;
+            if distance == 0 || distance > (offset - HEADER_LEN) as u64 {
+                return Err(FlatError::OutOfBounds {
+                    offset: (offset + 8 + index * 8) as u64,
+                });
+            }
+            let target = offset - distance as usize;
+            count += match word & 3 {
+                KIND_RECORD => {
+                    self.validate_record(target, offset)?;
+                    1
+                }
+                KIND_CHAIN => {
+                    if target + 4 > offset {
+                        return Err(FlatError::OutOfBounds { offset: target as u64 });
+                    }
+                    let len = u32_at(self.bytes, target);
+                    if len < 2 {
+                        return Err(invalid("chain of fewer than two records"));
+                    }
+                    let mut record = target + 4;
+                    for _ in 0..len {
+                        record = self.validate_record(record, offset)?;
+                    }
+                    len as u64
+                }
+                KIND_NODE if level + 1 < C::MAX_DEPTH => self.validate_node(target, level + 1, visited)?,
+                KIND_NODE => return Err(invalid("node deeper than the hash allows")),
+                _ => return Err(invalid("unknown kind of entry")),
+            };
+        }
+        if entries == 0 && level > 0 {
+            return Err(invalid("empty node below the root"));
+        }
+        Ok(count)
+    }
+
+    /// Check that the record at `offset` ends before `limit`, and return where it ends.
+    fn validate_record(&self, offset: usize, limit: usize) -> Result<usize, FlatError> {
+        let out_of_bounds = FlatError::OutOfBounds { offset: offset as u64 };
+        if offset + 8 > limit {
+            return Err(out_of_bounds);
+        }
+        let len = u32_at(self.bytes, offset) as usize + u32_at(self.bytes, offset + 4) as usize;
+        if offset + 8 + len > limit {
+            return Err(out_of_bounds);
+        }
+        Ok(offset + 8 + len)
+    }
+
+    /// The key and value of the record at `offset`, and where it ends.
+    fn record(&self, offset: usize) -> (&'a [u8], &'a [u8], usize) {
+        let key_len = u32_at(self.bytes, offset) as usize;
+        let value_len = u32_at(self.bytes, offset + 4) as usize;
+        let key = offset + 8;
+        let value = key + key_len;
+        (&self.bytes[key..value], &self.bytes[value..value + value_len], value + value_len)
+    }
+
+    /// The entry of the node at `offset` with the given index, as the kind and offset of its target.
+    fn entry(&self, offset: usize, index: usize) -> (u64, usize) {
+        let word = u64_at(self.bytes, offset + 8 + index * 8);
+        (word & 3, offset - (word >> 2) as usize)
+    }
+
+    /// Number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if the map has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the value stored for the key with the given encoding.
+    pub fn get_bytes(&self, key: &[u8]) -> Option<&'a [u8]> {
+        let mut hash = C::Hash::hash_of(key);
+        let mut node = self.root;
+        loop {
+            let presence_map = u64_at(self.bytes, node);
+            let frag = hash.fragment(C::BITS);
+            if presence_map & (1 << frag) == 0 {
+                return None;
+            }
+            let index = (presence_map & ((1 << frag) - 1)).count_ones() as usize;
+            let (kind, target) = self.entry(node, index);
+            match kind {
+                KIND_RECORD => {
+                    let (k, v, _) = self.record(target);
+                    return (k == key).then_some(v);
+                }
+                KIND_CHAIN => {
+                    let mut record = target + 4;
+                    for _ in 0..u32_at(self.bytes, target) {
+                        let (k, v, next) = self.record(record);
+                        if k == key {
+                            return Some(v);
+                        }
+                        record = next;
+                    }
+                    return None;
+                }
+                _ => {
+                    node = target;
+                    hash = hash.shift(C::BITS);
+                }
+            }
+        }
+    }
+
+    /// Get the encoded value stored for `key`.
+    pub fn get<Q: FlatField + ?Sized>(&self, key: &Q) -> Option<&'a [u8]> {
+        let mut bytes = Vec::new();
+        key.write_flat(&mut bytes);
+        self.get_bytes(&bytes)
+    }
+
+    /// Get the value stored for `key`, decoded. Returns `None` if the stored value does not have the size of a `T`.
+    pub fn get_fixed<Q: FlatField + ?Sized, T: FixedLayout>(&self, key: &Q) -> Option<T> {
+        self.get(key).filter(|bytes| bytes.len() == T::SIZE).map(T::read_flat)
+    }
+
+    /// Check if the map contains `key`.
+    pub fn contains_key<Q: FlatField + ?Sized>(&self, key: &Q) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Iterate over the encoded keys and values, in trie order.
+    pub fn iter(&self) -> FlatIter<'a, C> {
+        FlatIter {
+            map: *self,
+            stack: vec![(self.root, 0)],
+            chain: (0, 0),
+        }
+    }
+}
+
+impl<C: HamtConfig> Clone for FlatMap<'_, C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<C: HamtConfig> Copy for FlatMap<'_, C> {}
+
+impl<C: HamtConfig> fmt::Debug for FlatMap<'_, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FlatMap").field("len", &self.len).field("bytes", &self.bytes.len()).finish()
+    }
+}
+
+/// An iterator over the encoded keys and values of a [`FlatMap`].
+pub struct FlatIter<'a, C: HamtConfig> {
+    map: FlatMap<'a, C>,
+    /// The nodes being visited, with the index of the next entry to visit, deepest last.
+    stack: Vec<(usize, usize)>,
+    /// The next record of the chain being visited, and the number of records left in it.
+    chain: (usize, u32),
+}
+
+impl<'a, C: HamtConfig> Iterator for FlatIter<'a, C> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.chain.1 > 0 {
+                let (k, v, next) = self.map.record(self.chain.0);
+                self.chain = (next, self.chain.1 - 1);
+                return Some((k, v));
+            }
+            let (node, index) = self.stack.last_mut()?;
+            let node = *node;
+            if *index == u64_at(self.map.bytes, node).count_ones() as usize {
+                self.stack.pop();
+                continue;
+            }
+            let (kind, target) = self.map.entry(node, *index);
+            *index += 1;
+            match kind {
+                KIND_RECORD => {
+                    let (k, v, _) = self.map.record(target);
+                    return Some((k, v));
+                }
+                KIND_CHAIN => self.chain = (target + 4, u32_at(self.map.bytes, target)),
+                _ => self.stack.push((target, 0)),
+            }
+        }
+    }
+}
+
+impl<K: FlatField, V: FlatField, C: HamtConfig> HAMT<K, V, C> {
+    /// Write the map to `out` as a flat file, see [`write_flat`].
+    pub fn write_flat<W: Write>(&self, out: W) -> io::Result<()> {
+        write_flat(self, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_flat, FlatError, FlatMap};
+    use crate::{Config, DefaultConfig, HAMT};
+    use std::collections::HashMap;
+
+    fn table() -> (HAMT<String, u64>, Vec<u8>) {
+        let map = (0..5000u64).fold(HAMT::new(), |map, k| map.insert(format!("key {}", k), k * 3));
+        let mut bytes = Vec::new();
+        write_flat(&map, &mut bytes).unwrap();
+        (map, bytes)
+    }
+
+    #[test]
+    fn query_in_place() {
+        let (map, bytes) = table();
+        let flat = FlatMap::<DefaultConfig>::open(&bytes).unwrap();
+        assert_eq!(flat.len(), 5000);
+        for k in 0..5000u64 {
+            assert_eq!(flat.get_fixed::<_, u64>(&format!("key {}", k)), Some(k * 3));
+        }
+        assert!(!flat.contains_key("key 5000"));
+        assert_eq!(flat.get("key 1"), Some(&3u64.to_le_bytes()[..]));
+        assert_eq!(flat.get_fixed::<_, u32>("key 1"), None);
+
+        let items: HashMap<&[u8], &[u8]> = flat.iter().collect();
+        assert_eq!(items.len(), 5000);
+        for (k, v) in &map {
+            assert_eq!(items[k.as_bytes()], &v.to_le_bytes()[..]);
+        }
+
+        let mut empty = Vec::new();
+        HAMT::<Vec<u8>, Vec<u8>>::new().write_flat(&mut empty).unwrap();
+        let empty = FlatMap::<DefaultConfig>::open(&empty).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.iter().next(), None);
+        assert_eq!(empty.get("anything"), None);
+    }
+
+    #[test]
+    fn byte_keys_keep_the_trie_shape() {
+        // Byte vectors hash like the byte slices the flat map is queried with.
+        let map = (0..300u32).fold(HAMT::<_, _, Config<4, u128>>::with_config(), |map, k| {
+            map.insert(k.to_be_bytes().to_vec(), vec![k as u8; k as usize % 5])
+        });
+        let mut bytes = Vec::new();
+        map.write_flat(&mut bytes).unwrap();
+        let flat = FlatMap::<Config<4, u128>>::open(&bytes).unwrap();
+        let in_order: Vec<(&[u8], &[u8])> = map.iter().map(|(k, v)| (&k[..], &v[..])).collect();
+        assert_eq!(flat.iter().collect::<Vec<_>>(), in_order);
+        assert_eq!(FlatMap::<DefaultConfig>::open(&bytes).unwrap_err(), FlatError::ConfigMismatch { bits: 4, hash_bits: 128 });
+    }
+
+    #[test]
+    fn corrupt_files_are_rejected() {
+        let (_, bytes) = table();
+        assert_eq!(FlatMap::<DefaultConfig>::open(b"HAMTSNAP").unwrap_err(), FlatError::NotAFlatMap);
+        let mut newer = bytes.clone();
+        newer[8] = 3;
+        assert_eq!(FlatMap::<DefaultConfig>::open(&newer).unwrap_err(), FlatError::UnsupportedVersion(3));
+        let mut rehashed = bytes.clone();
+        rehashed[14] = 0;
+        assert_eq!(FlatMap::<DefaultConfig>::open(&rehashed).unwrap_err(), FlatError::UnsupportedHash(0));
+        for len in (0..bytes.len()).step_by(97) {
+            assert!(FlatMap::<DefaultConfig>::open(&bytes[..len]).is_err());
+        }
+        // Whatever a flipped bit does, opening either fails or gives a map that can be queried without panicking.
+        let mut state = 0x2545f4914f6cdd1du64;
+        for _ in 0..500 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let mut corrupt = bytes.clone();
+            corrupt[(state % bytes.len() as u64) as usize] ^= 1 << (state >> 61);
+            if let Ok(flat) = FlatMap::<DefaultConfig>::open(&corrupt) {
+                assert!(flat.iter().count() <= corrupt.len());
+                flat.get("key 17");
+            }
+        }
+    }
+}
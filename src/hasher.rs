@@ -0,0 +1,174 @@
+//! The hash function keys are hashed with.
+//!
+//! Maps written to files ([snapshots](crate::write_snapshot), [stores](crate::HamtStore) and
+//! [flat maps](crate::write_flat)) keep the hashes of their keys, as the shape of the trie or the
+//! entries themselves. So the hash must not change between builds, platforms or versions of Rust,
+//! which `std`'s `DefaultHasher` does not promise. [`StableHasher`] is SipHash-1-3 with zero keys,
+//! the same function `DefaultHasher` uses today, with integers always written in little endian.
+
+use std::hash::Hasher;
+
+/// The identifier of the hash function written in file headers. Files written with another
+/// hash function are rejected, since the keys they store would not be found.
+pub(crate) const HASH_ID: u8 = 1;
+
+/// SipHash-1-3 with zero keys, hashing integers as their little-endian bytes and `usize` as a `u64`.
+#[derive(Clone, Debug)]
+pub(crate) struct StableHasher {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+    /// Bytes written since the last full word, in the low bytes.
+    tail: u64,
+    ntail: usize,
+    length: usize,
+}
+
+impl StableHasher {
+    pub(crate) fn new() -> Self {
+        StableHasher {
+            v0: 0x736f_6d65_7073_6575,
+            v1: 0x646f_7261_6e64_6f6d,
+            v2: 0x6c79_6765_6e65_7261,
+            v3: 0x7465_6462_7974_6573,
+            tail: 0,
+            ntail: 0,
+            length: 0,
+        }
+    }
+
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13) ^ self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16) ^ self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21) ^ self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17) ^ self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+
+    /// Mix in a full word of the message.
+    fn compress(&mut self, word: u64) {
+        self.v3 ^= word;
+        self.round();
+        self.v0 ^= word;
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, mut bytes: &[u8]) {
+        self.length += bytes.len();
+        // Complete the word started by earlier writes.
+        while self.ntail > 0 && !bytes.is_empty() {
+            self.tail |= (bytes[0] as u64) << (8 * self.ntail);
+            self.ntail = (self.ntail + 1) % 8;
+            bytes = &bytes[1..];
+            if self.ntail == 0 {
+                self.compress(self.tail);
+                self.tail = 0;
+            }
+        }
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(word);
+            self.compress(u64::from_le_bytes(buf));
+        }
+        for (i, byte) in words.remainder().iter().enumerate() {
+            self.tail |= (*byte as u64) << (8 * i);
+        }
+        self.ntail = words.remainder().len();
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write_u16(i as u16);
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write_u32(i as u32);
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i128(&mut self, i: i128) {
+        self.write_u128(i as u128);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        let mut state = self.clone();
+        let last = ((self.length as u64 & 0xff) << 56) | self.tail;
+        state.compress(last);
+        state.v2 ^= 0xff;
+        for _ in 0..3 {
+            state.round();
+        }
+        state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StableHasher;
+    use crate::HashWord;
+    use std::hash::{Hash, Hasher};
+
+    fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+        let mut hasher = StableHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn known_hashes() {
+        // Files written by earlier builds depend on these.
+        assert_eq!(hash(&0u64), 0xbd60_acb6_58c7_9e45);
+        assert_eq!(hash("hamster"), 0x9dc4_3c95_c4c0_ffc5);
+        assert_eq!(hash(&[1u8, 2, 3][..]), 0x7038_a626_8d64_a5c1);
+        assert_eq!(u64::hash_of(&42usize), u64::hash_of(&42u64));
+        assert_eq!(<u128 as HashWord>::hash_of("hamster") >> 64, 0x9dc4_3c95_c4c0_ffc5);
+    }
+
+    #[test]
+    fn split_writes() {
+        // The result only depends on the bytes written, not on how they were split.
+        let bytes: Vec<u8> = (0..40).collect();
+        for split in 0..bytes.len() {
+            let mut hasher = StableHasher::new();
+            hasher.write(&bytes[..split]);
+            hasher.write(&bytes[split..]);
+            let mut whole = StableHasher::new();
+            whole.write(&bytes);
+            assert_eq!(hasher.finish(), whole.finish());
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Undo and redo over versions of a map.
+//!
+//! A [`History`] keeps the maps committed to it, so that going back to one is a matter of making it
+//! current again. Consecutive versions share all the nodes an edit did not copy, so the memory a
+//! history uses is the memory of the nodes of its versions, counting each shared node once. The
+//! history tracks it as versions come and go, and can drop its oldest versions to stay under a number
+//! of steps or of bytes.
+
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+
+use crate::{DefaultConfig, HAMTNode, HAMTNodeEntry, HamtConfig, NodePtr, HAMT};
+
+/// A version of the map, with the label of the commit that made it.
+struct Step<K, V, C: HamtConfig> {
+    map: HAMT<K, V, C>,
+    label: Option<String>,
+}
+
+/// Bytes used by a node, not counting its children, nor the memory owned by its keys and values.
+fn node_bytes<K, V, C: HamtConfig>(node: &HAMTNode<K, V, C>) -> usize {
+    // The shared pointer keeps two counts next to the node.
+    2 * mem::size_of::<usize>()
+        + mem::size_of::<HAMTNode<K, V, C>>()
+        + node.entries.capacity() * mem::size_of::<HAMTNodeEntry<K, V, C>>()
+        + node
+            .entries
+            .iter()
+            .map(|entry| match entry {
+                HAMTNodeEntry::Chained(vec) => vec.capacity() * mem::size_of::<(K, V)>(),
+                _ => 0,
+            })
+            .sum::<usize>()
+}
+
+/// The distinct nodes of the versions kept by a history, with how many versions and nodes point to each.
+#[derive(Default)]
+struct Accounting {
+    /// References to each node and its size, by address. The versions keep the nodes alive, so the
+    /// addresses are not reused while they are counted.
+    nodes: HashMap<usize, (usize, usize)>,
+    bytes: usize,
+}
+
+impl Accounting {
+    fn retain<K, V, C: HamtConfig>(&mut self, node: &NodePtr<K, V, C>) {
+        let address = node.as_ptr() as usize;
+        if let Some((refs, _)) = self.nodes.get_mut(&address) {
+            *refs += 1;
+            return;
+        }
+        let bytes = node_bytes(node);
+        self.nodes.insert(address, (1, bytes));
+        self.bytes += bytes;
+        for entry in &node.entries {
+            if let HAMTNodeEntry::Node(child) = entry {
+                self.retain(child);
+            }
+        }
+    }
+
+    fn release<K, V, C: HamtConfig>(&mut self, node: &NodePtr<K, V, C>) {
+        let address = node.as_ptr() as usize;
+        let (refs, bytes) = self.nodes.get_mut(&address).unwrap();
+        *refs -= 1;
+        if *refs > 0 {
+            return;
+        }
+        self.bytes -= *bytes;
+        self.nodes.remove(&address);
+        for entry in &node.entries {
+            if let HAMTNodeEntry::Node(child) = entry {
+                self.release(child);
+            }
+        }
+    }
+}
+
+/// The versions of a map committed by an editor, to undo and redo changes.
+pub struct History<K, V, C: HamtConfig = DefaultConfig> {
+    past: VecDeque<Step<K, V, C>>,
+    current: Step<K, V, C>,
+    /// Undone versions, the next to redo last.
+    future: Vec<Step<K, V, C>>,
+    max_depth: Option<usize>,
+    max_bytes: Option<usize>,
+    /// The label of the open group, and whether a commit was made in it yet.
+    group: Option<(String, bool)>,
+    accounting: Accounting,
+}
+
+impl<K, V, C: HamtConfig> History<K, V, C> {
+    /// Start a history at `initial`, without a limit on its depth.
+    pub fn new(initial: HAMT<K, V, C>) -> Self {
+        let mut accounting = Accounting::default();
+        accounting.retain(&initial.root);
+        History {
+            past: VecDeque::new(),
+            current: Step {
+                map: initial,
+                label: None,
+            },
+            future: Vec::new(),
+            max_depth: None,
+            max_bytes: None,
+            group: None,
+            accounting,
+        }
+    }
+
+    /// The current version of the map.
+    pub fn current(&self) -> &HAMT<K, V, C> {
+        &self.current.map
+    }
+
+    /// Label of the commit that made the current version, which [`undo`](History::undo) would revert.
+    /// `None` for the initial version.
+    pub fn label(&self) -> Option<&str> {
+        self.current.label.as_deref()
+    }
+
+    /// Label of the commit that [`redo`](History::redo) would restore.
+    pub fn redo_label(&self) -> Option<&str> {
+        self.future.last().and_then(|step| step.label.as_deref())
+    }
+
+    /// Number of steps that can be undone.
+    pub fn undo_depth(&self) -> usize {
+        self.past.len()
+    }
+
+    /// Number of steps that can be redone.
+    pub fn redo_depth(&self) -> usize {
+        self.future.len()
+    }
+
+    /// Bytes used by the nodes of all the versions kept, counting the nodes they share once.
+    /// This does not count the memory that keys and values own, such as the contents of strings.
+    pub fn memory_usage(&self) -> usize {
+        self.accounting.bytes
+    }
+
+    /// Keep at most `depth` steps to undo, dropping the oldest ones first.
+    pub fn set_max_depth(&mut self, depth: Option<usize>) {
+        self.max_depth = depth;
+        self.trim();
+    }
+
+    /// Drop the oldest steps to undo while the history uses more than `bytes`, as counted by
+    /// [`memory_usage`](History::memory_usage). The current version and the steps to redo are always kept.
+    pub fn set_max_bytes(&mut self, bytes: Option<usize>) {
+        self.max_bytes = bytes;
+        self.trim();
+    }
+
+    /// Make `map` the current version, as a step that can be undone. The steps that were undone can no
+    /// longer be redone.
+    ///
+    /// Within a group, the first commit makes a step, with the label of the group, and the next ones
+    /// update it.
+    pub fn commit(&mut self, map: HAMT<K, V, C>, label: &str) {
+        for step in mem::take(&mut self.future) {
+            self.accounting.release(&step.map.root);
+        }
+        self.accounting.retain(&map.root);
+        let label = match &mut self.group {
+            Some((_, true)) => {
+                let step = mem::replace(&mut self.current.map, map);
+                self.accounting.release(&step.root);
+                return;
+            }
+            Some((group, started)) => {
+                *started = true;
+                group.clone()
+            }
+            None => label.to_string(),
+        };
+        let previous = mem::replace(
+            &mut self.current,
+            Step {
+                map,
+                label: Some(label),
+            },
+        );
+        self.past.push_back(previous);
+        self.trim();
+    }
+
+    /// Group the next commits into one step, until [`end_group`](History::end_group), undoing or redoing.
+    pub fn begin_group(&mut self, label: &str) {
+        self.group = Some((label.to_string(), false));
+    }
+
+    /// End the group of commits.
+    pub fn end_group(&mut self) {
+        self.group = None;
+    }
+
+    /// Go back to the version before the current one, and return it, or return `None` if there is none.
+    pub fn undo(&mut self) -> Option<&HAMT<K, V, C>> {
+        self.group = None;
+        let previous = self.past.pop_back()?;
+        self.future.push(mem::replace(&mut self.current, previous));
+        Some(&self.current.map)
+    }
+
+    /// Go forward to the version that was last undone, and return it, or return `None` if there is none.
+    pub fn redo(&mut self) -> Option<&HAMT<K, V, C>> {
+        self.group = None;
+        let next = self.future.pop()?;
+        self.past.push_back(mem::replace(&mut self.current, next));
+        Some(&self.current.map)
+    }
+
+    /// Drop the oldest steps to undo until the limits are met.
+    fn trim(&mut self) {
+        while !self.past.is_empty()
+            && (self.max_depth.is_some_and(|depth| self.past.len() > depth)
+                || self.max_bytes.is_some_and(|bytes| self.accounting.bytes > bytes))
+        {
+            let step = self.past.pop_front().unwrap();
+            self.accounting.release(&step.map.root);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::History;
+    use crate::HAMT;
+    use crate::test_util::build;
+
+    #[test]
+    fn undo_and_redo() {
+        let mut history = History::new(HAMT::new());
+        history.commit(history.current().insert("a", 1), "add a");
+        history.commit(history.current().insert("b", 2), "add b");
+        assert_eq!(history.label(), Some("add b"));
+        assert_eq!(history.undo().unwrap().get("b"), None);
+        assert_eq!(history.redo_label(), Some("add b"));
+        assert_eq!(history.undo().unwrap().get("a"), None);
+        assert!(history.undo().is_none());
+        assert_eq!(history.label(), None);
+        assert_eq!(history.redo().unwrap().get("a"), Some(&1));
+        assert_eq!(history.redo_depth(), 1);
+
+        // Committing drops the steps that were undone.
+        history.commit(history.current().insert("c", 3), "add c");
+        assert!(history.redo().is_none());
+        assert_eq!(history.undo_depth(), 2);
+        assert_eq!(history.current().get("c"), Some(&3));
+    }
+
+    #[test]
+    fn groups_are_undone_at_once() {
+        let mut history = History::new(HAMT::new());
+        history.commit(history.current().insert(0, 0), "zero");
+        history.begin_group("fill");
+        for k in 1..10 {
+            history.commit(history.current().insert(k, k), "ignored");
+        }
+        history.end_group();
+        assert_eq!(history.undo_depth(), 2);
+        assert_eq!(history.label(), Some("fill"));
+        let undone = history.undo().unwrap();
+        assert_eq!(undone.iter().count(), 1);
+        assert_eq!(history.redo().unwrap().iter().count(), 10);
+    }
+
+    #[test]
+    fn memory_usage_and_limits() {
+        let map = build(0..10_000);
+        let mut history = History::new(map);
+        let single = history.memory_usage();
+        for k in 0..100 {
+            history.commit(history.current().insert(k, -k), "edit");
+        }
+        // Each edit only adds the nodes on its path.
+        let hundred = history.memory_usage();
+        assert!(hundred < single * 2, "{} bytes for one version, {} for a hundred", single, hundred);
+
+        history.set_max_depth(Some(10));
+        assert_eq!(history.undo_depth(), 10);
+        let ten = history.memory_usage();
+        assert!(ten < hundred);
+        history.set_max_bytes(Some(single));
+        assert_eq!(history.undo_depth(), 0);
+        assert!(history.undo().is_none());
+        assert!(history.memory_usage() >= single);
+
+        // Dropping every other version leaves the nodes of the current one.
+        let current = history.current().clone();
+        let fresh = History::new(current);
+        assert_eq!(history.memory_usage(), fresh.memory_usage());
+    }
+}
@@ -0,0 +1,211 @@
+//! Hash-consing of trie nodes.
+//!
+//! Maps built independently from overlapping data contain many nodes with identical contents
+//! that live in separate allocations. A [`NodeInterner`] canonicalizes nodes by content, so that
+//! every such node is replaced by a single shared pointer.
+//! Because interning is bottom-up, two interned nodes are equal exactly when their children are the
+//! same pointers, so nodes never need to be compared deeply.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::config::WeakNode;
+use crate::{Bitmap, DefaultConfig, HAMTNode, HAMTNodeEntry, HamtConfig, NodePtr, SharedPointer, HAMT};
+
+/// Canonical nodes whose contents have the same hash.
+type Bucket<K, V, C> = Vec<WeakNode<K, V, C>>;
+
+/// A table of canonical nodes, shared by all the maps interned through it.
+///
+/// The table only keeps weak references, so it does not keep any node alive:
+/// nodes are freed once no map uses them, and [`purge`](NodeInterner::purge) drops their slots.
+pub struct NodeInterner<K, V, C: HamtConfig = DefaultConfig> {
+    nodes: HashMap<u64, Bucket<K, V, C>>,
+}
+
+/// Hash a node whose children have already been interned.
+/// Children are hashed by address, as they are canonical.
+fn hash_node<K: Hash, V: Hash, C: HamtConfig>(node: &HAMTNode<K, V, C>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    node.presence_map.to_u64().hash(&mut hasher);
+    for entry in node.entries.iter() {
+        match entry {
+            HAMTNodeEntry::Value(k, v, _) => {
+                0u8.hash(&mut hasher);
+                k.hash(&mut hasher);
+                v.hash(&mut hasher);
+            }
+            HAMTNodeEntry::Chained(vec) => {
+                1u8.hash(&mut hasher);
+                vec.hash(&mut hasher);
+            }
+            HAMTNodeEntry::Node(child) => {
+                2u8.hash(&mut hasher);
+                child.as_ptr().hash(&mut hasher);
+            }
+        }
+    }
+    hasher.finish()
+}
+
+/// Compare two nodes whose children have already been interned.
+fn shallow_eq<K: Eq, V: Eq, C: HamtConfig>(a: &HAMTNode<K, V, C>, b: &HAMTNode<K, V, C>) -> bool {
+    a.presence_map == b.presence_map
+        && a.entries.iter().zip(b.entries.iter()).all(|pair| match pair {
+            (HAMTNodeEntry::Value(k1, v1, h1), HAMTNodeEntry::Value(k2, v2, h2)) => k1 == k2 && v1 == v2 && h1 == h2,
+            (HAMTNodeEntry::Chained(vec1), HAMTNodeEntry::Chained(vec2)) => vec1 == vec2,
+            (HAMTNodeEntry::Node(child1), HAMTNodeEntry::Node(child2)) => NodePtr::ptr_eq(child1, child2),
+            _ => false,
+        })
+}
+
+impl<K, V> NodeInterner<K, V> {
+    /// Construct an empty interner.
+    pub fn new() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K, V, C: HamtConfig> NodeInterner<K, V, C> {
+    /// Construct an empty interner for maps with the trie shape `C`.
+    pub fn with_config() -> Self {
+        NodeInterner { nodes: HashMap::new() }
+    }
+
+    /// Number of canonical nodes that are still in use by some map.
+    pub fn len(&self) -> usize {
+        self.nodes
+            .values()
+            .flat_map(|bucket| bucket.iter())
+            .filter(|node| C::Pointer::strong_count(node) > 0)
+            .count()
+    }
+
+    /// Check if no canonical node is in use.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget the nodes that are no longer used by any map.
+    pub fn purge(&mut self) {
+        self.nodes.retain(|_, bucket| {
+            bucket.retain(|node| C::Pointer::strong_count(node) > 0);
+            !bucket.is_empty()
+        });
+    }
+}
+
+impl<K, V, C: HamtConfig> Default for NodeInterner<K, V, C> {
+    fn default() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K, V, C> NodeInterner<K, V, C>
+where
+    K: Hash + Eq + Clone,
+    V: Hash + Eq + Clone,
+    C: HamtConfig,
+{
+    /// Return a map equal to `map` in which every node is canonical.
+    /// Equal subtrees of maps interned by the same interner are then the same node, so they are stored once,
+    /// and comparing them is a pointer comparison.
+    pub fn intern(&mut self, map: &HAMT<K, V, C>) -> HAMT<K, V, C> {
+        HAMT {
+            root: self.intern_node(&map.root),
+        }
+    }
+
+    /// Intern the children of the node, then the node itself.
+    fn intern_node(&mut self, node: &NodePtr<K, V, C>) -> NodePtr<K, V, C> {
+        let children: Vec<Option<NodePtr<K, V, C>>> = node
+            .entries
+            .iter()
+            .map(|entry| match entry {
+                HAMTNodeEntry::Node(child) => {
+                    let canonical = self.intern_node(child);
+                    if NodePtr::ptr_eq(&canonical, child) {
+                        None
+                    } else {
+                        Some(canonical)
+                    }
+                }
+                _ => None,
+            })
+            .collect();
+        // Only copy the node if one of its children was replaced by its canonical version.
+        let node = if children.iter().all(Option::is_none) {
+            node.clone()
+        } else {
+            let entries = node
+                .entries
+                .iter()
+                .zip(children)
+                .map(|(entry, child)| match child {
+                    Some(child) => HAMTNodeEntry::Node(child),
+                    None => entry.clone(),
+                })
+                .collect();
+            NodePtr::new(HAMTNode {
+                presence_map: node.presence_map,
+                entries,
+            })
+        };
+
+        let bucket = self.nodes.entry(hash_node(&node)).or_default();
+        for existing in bucket.iter().filter_map(C::Pointer::upgrade).map(NodePtr) {
+            if NodePtr::ptr_eq(&existing, &node) || shallow_eq(&existing, &node) {
+                return existing;
+            }
+        }
+        bucket.retain(|existing| C::Pointer::strong_count(existing) > 0);
+        bucket.push(C::Pointer::downgrade(&node.0));
+        node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NodeInterner;
+    use crate::test_util::build;
+
+    #[test]
+    fn equal_maps_share_root() {
+        let mut interner = NodeInterner::new();
+        let a = interner.intern(&build(0..2000));
+        let b = interner.intern(&build((0..2000).rev()));
+        assert!(a.ptr_eq(&b));
+        assert_eq!(a, build(0..2000));
+        // Interning a canonical map again does not copy anything.
+        assert!(interner.intern(&a).ptr_eq(&a));
+    }
+
+    #[test]
+    fn overlapping_maps_share_subtrees() {
+        let mut interner = NodeInterner::new();
+        let a = interner.intern(&build(0..2000));
+        let nodes_a = interner.len();
+        let b = interner.intern(&build(0..2000).insert(5000, 0));
+        // Only the path down to the new key is new.
+        assert!(interner.len() - nodes_a <= b.height() as usize);
+        assert_ne!(a, b);
+
+        let shared = a.root.entries.iter().zip(b.root.entries.iter()).filter(|pair| match pair {
+            (crate::HAMTNodeEntry::Node(x), crate::HAMTNodeEntry::Node(y)) => crate::NodePtr::ptr_eq(x, y),
+            _ => false,
+        });
+        assert!(shared.count() >= 30);
+    }
+
+    #[test]
+    fn purge_forgets_dropped_nodes() {
+        let mut interner = NodeInterner::new();
+        let a = interner.intern(&build(0..100));
+        assert!(!interner.is_empty());
+        drop(a);
+        assert!(interner.is_empty());
+        interner.purge();
+        assert!(interner.nodes.is_empty());
+    }
+}
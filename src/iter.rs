@@ -0,0 +1,104 @@
+//! Iteration over the entries of a map.
+
+use std::iter::FusedIterator;
+use std::slice;
+
+use crate::{HAMTNodeEntry, HamtConfig, HAMT};
+
+/// An iterator over the keys and values of a [`HAMT`](HAMT), in trie order.
+///
+/// The order only depends on the hashes of the keys (and, for fully colliding keys, on the order
+/// they were inserted in), so it is the same for every map with the same keys and configuration.
+pub struct Iter<'a, K, V, C: HamtConfig> {
+    /// The entries left to visit at each level, deepest last.
+    stack: Vec<slice::Iter<'a, HAMTNodeEntry<K, V, C>>>,
+    /// The rest of the chain being visited.
+    chain: slice::Iter<'a, (K, V)>,
+}
+
+impl<'a, K, V, C: HamtConfig> Iter<'a, K, V, C> {
+    /// Iterate over everything stored in `entries`, in order.
+    pub(crate) fn over(entries: &[&'a HAMTNodeEntry<K, V, C>]) -> Self {
+        Iter {
+            stack: entries.iter().rev().map(|entry| slice::from_ref(*entry).iter()).collect(),
+            chain: [].iter(),
+        }
+    }
+}
+
+impl<'a, K, V, C: HamtConfig> Iterator for Iter<'a, K, V, C> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((k, v)) = self.chain.next() {
+                return Some((k, v));
+            }
+            let entries = self.stack.last_mut()?;
+            match entries.next() {
+                None => {
+                    self.stack.pop();
+                }
+                Some(HAMTNodeEntry::Value(k, v, _)) => return Some((k, v)),
+                Some(HAMTNodeEntry::Chained(vec)) => self.chain = vec.iter(),
+                Some(HAMTNodeEntry::Node(node)) => self.stack.push(node.entries.iter()),
+            }
+        }
+    }
+}
+
+impl<K, V, C: HamtConfig> FusedIterator for Iter<'_, K, V, C> {}
+
+impl<K, V, C: HamtConfig> HAMT<K, V, C> {
+    /// Iterate over the keys and values of the map.
+    pub fn iter(&self) -> Iter<'_, K, V, C> {
+        Iter {
+            stack: vec![self.root.entries.iter()],
+            chain: [].iter(),
+        }
+    }
+}
+
+impl<'a, K, V, C: HamtConfig> IntoIterator for &'a HAMT<K, V, C> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, C>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::HAMT;
+    use crate::test_util::Colliding;
+    use std::collections::HashMap;
+
+    #[test]
+    fn iter_visits_every_entry_once() {
+        let mut map = HAMT::new();
+        for k in 0..3000 {
+            map = map.insert(k, k * 2);
+        }
+        let map = map.remove(17);
+        let items: HashMap<i32, i32> = map.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(items.len(), 2999);
+        assert!(items.iter().all(|(k, v)| *v == k * 2 && *k != 17));
+        assert_eq!(HAMT::<i32, i32>::new().iter().next(), None);
+    }
+
+    #[test]
+    fn iter_chains() {
+        let mut map = HAMT::new();
+        for id in 0..10 {
+            map = map.insert(Colliding { bucket: (id % 3) as u8, id }, id);
+        }
+        let mut ids = Vec::new();
+        for (k, v) in &map {
+            assert_eq!(k.id, *v);
+            ids.push(*v);
+        }
+        ids.sort_unstable();
+        assert_eq!(ids, (0..10).collect::<Vec<_>>());
+    }
+}
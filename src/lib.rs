@@ -1,52 +1,157 @@
-use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::fmt;
-use std::hash::{Hash, Hasher};
-use std::rc::Rc;
+use std::hash::Hash;
 
-/// This is the constant 0b11111 << 59.
-/// Used to extract 5 most significant bits from a u64.
-const MOST_SIG: u64 = 17870283321406128128;
+mod batch;
+mod cache;
+mod cell;
+mod codec;
+mod config;
+mod crdt;
+mod ctrie;
+mod cursor;
+mod diff;
+mod epoch;
+mod flat;
+mod hasher;
+mod history;
+mod intern;
+mod iter;
+mod merkle;
+mod mvcc;
+mod oplog;
+mod par;
+mod pool;
+mod repo;
+#[cfg(feature = "serde")]
+mod serialize;
+mod shard;
+mod snapshot;
+mod stamp;
+mod store;
+mod txn;
+mod view;
+mod weak;
+mod zipper;
+
+pub use batch::GetMany;
+pub use cell::{HamtCell, Subscriber, Update};
+pub use codec::{Codec, DecodeError};
+pub use crdt::{Dot, LwwEntry, LwwMap, OrEntry, OrMap};
+pub use ctrie::{Ctrie, CtrieSnapshot};
+pub use cursor::{Cursor, Position};
+pub use config::{ArcPointer, Bitmap, Config, DefaultConfig, HamtConfig, HashWord, RcPointer, SharedPointer, SyncConfig};
+pub use diff::Change;
+pub use flat::{write_flat, FixedLayout, FlatError, FlatField, FlatIter, FlatMap};
+pub use history::History;
+pub use intern::NodeInterner;
+pub use iter::Iter;
+pub use merkle::{Digest, Merkle, Sha256};
+pub use mvcc::{CommitError, Transaction, VersionedHamt};
+pub use oplog::{Checkpoint, LogEntry, LoggedHAMT, Op, ReplayError};
+pub use par::ParIter;
+pub use pool::{NodePool, PoolStats};
+pub use repo::{Commit, CommitId, MergeOutcome, Repo, RepoError};
+#[cfg(feature = "serde")]
+pub use serialize::set as serde_set;
+pub use shard::HashRange;
+pub use snapshot::{read_snapshot, write_snapshot, SnapshotError};
+pub use stamp::{Modified, Stamped, StampedHAMT};
+pub use store::HamtStore;
+pub use txn::{Savepoint, Txn};
+pub use view::{Children, EntryRef, NodeId, NodeRef};
+pub use weak::WeakHAMT;
+pub use zipper::Zipper;
 
 /// Implementation of a Hash Array Mapped Trie in Rust.
-#[derive(Debug)]
-pub struct HAMT<K, V> {
-    root: Rc<HAMTNode<K, V>>,
+///
+/// The shape of the trie is selected by `C`, see [`Config`](Config).
+pub struct HAMT<K, V, C: HamtConfig = DefaultConfig> {
+    root: NodePtr<K, V, C>,
 }
 
-// We can derive Clone automatically, as we are using Rc which supports clone.
-#[derive(Clone, Debug)]
-enum HAMTNodeEntry<K, V> {
-    // Key, value
-    Value(K, V),
-    Node(Rc<HAMTNode<K, V>>),
+/// A [`HAMT`](HAMT) whose nodes are shared through `Arc`, so that it can be sent to and shared
+/// between threads.
+pub type SyncHAMT<K, V> = HAMT<K, V, SyncConfig>;
+
+/// A shared pointer to a node, of the kind selected by the configuration.
+struct NodePtr<K, V, C: HamtConfig>(<C::Pointer as SharedPointer>::Ptr<HAMTNode<K, V, C>>);
+
+enum HAMTNodeEntry<K, V, C: HamtConfig> {
+    // Key, value, and the full hash the key was inserted with
+    Value(K, V, C::Hash),
+    Node(NodePtr<K, V, C>),
     Chained(Vec<(K, V)>),
 }
 
 /// An internal node of a [`HAMT`](HAMT).
-struct HAMTNode<K, V> {
-    presence_map: u32,
-    entries: Vec<HAMTNodeEntry<K, V>>,
+struct HAMTNode<K, V, C: HamtConfig> {
+    presence_map: C::Bitmap,
+    entries: Vec<HAMTNodeEntry<K, V, C>>,
 }
 
-/// Hash the given key using the rust `DefaultHasher`.
-fn hash_key<K: Hash>(key: &K) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    key.hash(&mut hasher);
-    hasher.finish()
+impl<K, V, C: HamtConfig> NodePtr<K, V, C> {
+    /// Move the node into a new allocation.
+    fn new(node: HAMTNode<K, V, C>) -> Self {
+        NodePtr(C::Pointer::new(node))
+    }
+
+    /// Check if both pointers point to the same node.
+    fn ptr_eq(a: &Self, b: &Self) -> bool {
+        C::Pointer::ptr_eq(&a.0, &b.0)
+    }
+
+    /// The address of the node.
+    fn as_ptr(&self) -> *const HAMTNode<K, V, C> {
+        C::Pointer::as_ptr(&self.0)
+    }
 }
 
-/// Given a 'presence map', and an index between 0 and 31 (inclusive), 
-/// compute what location the index will be in the entries vector.
-fn get_entries_index(presence_map: u32, index: u32) -> usize {
-    if index == 0 {
-        0
-    } else {
-        (presence_map & ((1 << index) - 1)).count_ones() as usize
+impl<K, V, C: HamtConfig> Clone for NodePtr<K, V, C> {
+    fn clone(&self) -> Self {
+        NodePtr(self.0.clone())
+    }
+}
+
+impl<K, V, C: HamtConfig> std::ops::Deref for NodePtr<K, V, C> {
+    type Target = HAMTNode<K, V, C>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
     }
 }
 
+/// Hash the given key using the hash width of the configuration.
+fn hash_key<C: HamtConfig, K: Hash + ?Sized>(key: &K) -> C::Hash {
+    C::Hash::hash_of(key)
+}
+
+/// Extract the fragment of the hash used to index into the current level.
+/// This will always be a number between 0 and `2^C::BITS - 1` (inclusive).
+fn fragment<C: HamtConfig>(cur_hashed_key: C::Hash) -> u32 {
+    cur_hashed_key.fragment(C::BITS)
+}
+
+/// Move the hash so the next fragment is in position.
+fn next_hash<C: HamtConfig>(cur_hashed_key: C::Hash) -> C::Hash {
+    cur_hashed_key.shift(C::BITS)
+}
+
+/// Append the fragment `frag`, which indexes an entry at `level`, to the hash bits of the path above it.
+/// Once the fragments of every level are appended, as for the path of a chain, this is the full hash.
+fn push_fragment<C: HamtConfig>(path: u128, frag: u32, level: u32) -> u128 {
+    let bits = (C::BITS * (level + 1)).min(C::Hash::BITS) - (C::BITS * level).min(C::Hash::BITS);
+    (path << bits) | (frag >> (C::BITS - bits)) as u128
+}
+
+/// Given a 'presence map', and a fragment index,
+/// compute what location the index will be in the entries vector.
+fn get_entries_index<B: Bitmap>(presence_map: B, index: u32) -> usize {
+    presence_map.count_below(index)
+}
+
 /// Insert an entry into a vector chain. This will replace the existing value for that key, if one exists.
-fn insert_chained<K: Eq + Clone, V: Clone>(vec: &Vec<(K, V)>, key: K, value: V) -> Vec<(K, V)> {
+fn insert_chained<K: Eq + Clone, V: Clone>(vec: &[(K, V)], key: K, value: V) -> Vec<(K, V)> {
     let mut new_vec = vec.to_vec();
     for i in new_vec.iter_mut() {
         if i.0 == key {
@@ -55,18 +160,75 @@ fn insert_chained<K: Eq + Clone, V: Clone>(vec: &Vec<(K, V)>, key: K, value: V)
         }
     }
     new_vec.insert(0, (key, value));
-    return new_vec;
+    new_vec
+}
+
+/// Call `f` on every key and value stored in the entry, including in the subtree below it.
+fn for_each_in_entry<'a, K, V, C: HamtConfig, F: FnMut(&'a K, &'a V)>(entry: &'a HAMTNodeEntry<K, V, C>, f: &mut F) {
+    match entry {
+        HAMTNodeEntry::Value(k, v, _) => f(k, v),
+        HAMTNodeEntry::Chained(vec) => vec.iter().for_each(|(k, v)| f(k, v)),
+        HAMTNodeEntry::Node(node) => node.entries.iter().for_each(|entry| for_each_in_entry(entry, f)),
+    }
+}
+
+/// Call `f` on the full hash, key and value of every key stored directly in the entry, which is a value
+/// or a chain. Chained keys agree on every fragment, so they all have the same hash: `chain_hash`.
+fn for_each_hashed<'a, K, V, C: HamtConfig, F: FnMut(C::Hash, &'a K, &'a V)>(
+    entry: &'a HAMTNodeEntry<K, V, C>,
+    chain_hash: C::Hash,
+    f: &mut F,
+) {
+    match entry {
+        HAMTNodeEntry::Value(k, v, hash) => f(*hash, k, v),
+        HAMTNodeEntry::Chained(vec) => vec.iter().for_each(|(k, v)| f(chain_hash, k, v)),
+        HAMTNodeEntry::Node(_) => unreachable!(),
+    }
+}
+
+/// Check if two nodes at the same level store the same keys and values.
+///
+/// Nodes shared between maps, or canonicalized by a [`NodeInterner`](NodeInterner), are
+/// recognized by pointer and not visited.
+fn nodes_eq<K: Hash + Eq, V: PartialEq, C: HamtConfig>(a: &NodePtr<K, V, C>, b: &NodePtr<K, V, C>) -> bool {
+    if NodePtr::ptr_eq(a, b) {
+        return true;
+    }
+    // Every present entry stores at least one key, and an entry stores exactly the keys whose hash
+    // has its prefix. So the maps can only be equal if the same entries are present.
+    if a.presence_map != b.presence_map {
+        return false;
+    }
+    a.entries.iter().zip(b.entries.iter()).all(|pair| match pair {
+        (HAMTNodeEntry::Node(a), HAMTNodeEntry::Node(b)) => nodes_eq(a, b),
+        (HAMTNodeEntry::Value(k1, v1, _), HAMTNodeEntry::Value(k2, v2, _)) => k1 == k2 && v1 == v2,
+        (a, b) => {
+            // The entries have different shapes (e.g. a chain in a different order, or a node
+            // that still has a single key after removals), so compare what they store.
+            let mut a_items = HashMap::new();
+            for_each_in_entry(a, &mut |k, v| {
+                a_items.insert(k, v);
+            });
+            let mut count = 0;
+            let mut all_found = true;
+            for_each_in_entry(b, &mut |k, v| {
+                count += 1;
+                all_found = all_found && a_items.get(k) == Some(&v);
+            });
+            all_found && count == a_items.len()
+        }
+    })
 }
 
 /// Get the height of the subtree
-fn get_height<K, V>(node: &HAMTNode<K, V>) -> u32 {
-    if node.presence_map == 0 {
+fn get_height<K, V, C: HamtConfig>(node: &HAMTNode<K, V, C>) -> u32 {
+    if node.presence_map == C::Bitmap::EMPTY {
         0
     } else {
         let mut max_child_depth = 0;
         for entry in node.entries.iter() {
             let entry_depth = match entry {
-                HAMTNodeEntry::Value(_, _) => 0,
+                HAMTNodeEntry::Value(..) => 0,
                 HAMTNodeEntry::Chained(_) => 1,
                 HAMTNodeEntry::Node(child_node) => get_height(child_node),
             };
@@ -79,250 +241,363 @@ fn get_height<K, V>(node: &HAMTNode<K, V>) -> u32 {
 }
 
 /// This is a key method: if called, there are conflicting hashed keys that need to be inserted
-/// at the current level. If the conflict occurs at the 12th level or lower,
-/// then the entry can point to a new node, which is constructed manually (we can predict what the new
-/// lower node can look like because we know both keys that it should store).
-/// If we are at the 13th level, then the data structure produces a chain instead.
-/// 
-/// Note that this can happen recursively, if the hashes of the keys share a prefix with more than 5 bits
-/// starting at the current level.
-fn create_split_entry<K, V>(
+/// at the current level. If the conflict occurs above the last level (`C::MAX_DEPTH`, the 13th level
+/// for the default configuration), then the entry can point to a new node, which is constructed manually
+/// (we can predict what the new lower node can look like because we know both keys that it should store).
+/// If we are at the last level, then the data structure produces a chain instead.
+///
+/// Note that this can happen recursively, if the hashes of the keys share a prefix with more than `C::BITS` bits
+/// starting at the current level. The hashes are the full hashes of the keys, which the new entries store.
+#[allow(clippy::too_many_arguments)]
+fn create_split_entry<K, V, C: HamtConfig>(
     key1: K,
-    hashed_key1: u64,
+    hashed_key1: C::Hash,
     val1: V,
     key2: K,
-    hashed_key2: u64,
+    hashed_key2: C::Hash,
     val2: V,
     level: u32,
-) -> HAMTNodeEntry<K, V> {
-    // If at the 13th level, there are no more bits in the keys to read.
+) -> HAMTNodeEntry<K, V, C> {
+    // If at the last level, there are no more bits in the keys to read.
     // Then a new chain is created
-    if level == 13 {
+    if level == C::MAX_DEPTH {
         let chained_vec = vec![(key1, val1), (key2, val2)];
-        return HAMTNodeEntry::Chained(chained_vec);
+        HAMTNodeEntry::Chained(chained_vec)
     } else {
-        let key1_frag = ((hashed_key1 & MOST_SIG) >> 59) as u32;
-        let key2_frag = ((hashed_key2 & MOST_SIG) >> 59) as u32;
+        let key1_frag = fragment::<C>(hashed_key1.shift(C::BITS * level));
+        let key2_frag = fragment::<C>(hashed_key2.shift(C::BITS * level));
         let node = if key1_frag == key2_frag {
             // If the next fragments are still the same, then need to split even further
-            let next_split_entry = create_split_entry(
-                key1,
-                hashed_key1 << 5,
-                val1,
-                key2,
-                hashed_key2 << 5,
-                val2,
-                level + 1,
-            );
+            let next_split_entry = create_split_entry(key1, hashed_key1, val1, key2, hashed_key2, val2, level + 1);
             HAMTNode {
-                presence_map: 1 << key1_frag,
+                presence_map: C::Bitmap::EMPTY.with(key1_frag),
                 entries: vec![next_split_entry],
             }
         } else {
             // Otherwise, create the node with only these two keys
             let entries = if key1_frag < key2_frag {
                 vec![
-                    HAMTNodeEntry::Value(key1, val1),
-                    HAMTNodeEntry::Value(key2, val2),
+                    HAMTNodeEntry::Value(key1, val1, hashed_key1),
+                    HAMTNodeEntry::Value(key2, val2, hashed_key2),
                 ]
             } else {
                 vec![
-                    HAMTNodeEntry::Value(key2, val2),
-                    HAMTNodeEntry::Value(key1, val1),
+                    HAMTNodeEntry::Value(key2, val2, hashed_key2),
+                    HAMTNodeEntry::Value(key1, val1, hashed_key1),
                 ]
             };
             HAMTNode {
-                presence_map: (1 << key1_frag) | (1 << key2_frag),
-                entries: entries,
+                presence_map: C::Bitmap::EMPTY.with(key1_frag).with(key2_frag),
+                entries,
             }
         };
-        return HAMTNodeEntry::Node(Rc::new(node));
+        HAMTNodeEntry::Node(NodePtr::new(node))
+    }
+}
+
+/// Build the entry storing all of `items` at once, where `level` is the level of the node the
+/// entry would point to. The items are `(hash, key, value)` with the full hash of the key, and
+/// must have distinct keys whose hashes agree on the fragments above `level`.
+///
+/// This is the bulk counterpart of `create_split_entry`: it produces the same shape as inserting
+/// the items one by one, without copying the path for each of them.
+fn build_entry<K, V, C: HamtConfig>(mut items: Vec<(C::Hash, K, V)>, level: u32) -> HAMTNodeEntry<K, V, C> {
+    if items.len() == 1 {
+        let (hash, key, value) = items.pop().unwrap();
+        HAMTNodeEntry::Value(key, value, hash)
+    } else if level == C::MAX_DEPTH {
+        HAMTNodeEntry::Chained(items.into_iter().map(|(_, k, v)| (k, v)).collect())
+    } else {
+        HAMTNodeEntry::Node(NodePtr::new(build_node(items, level)))
+    }
+}
+
+/// Build the node at `level` storing all of `items`, with the same requirements as `build_entry`.
+fn build_node<K, V, C: HamtConfig>(mut items: Vec<(C::Hash, K, V)>, level: u32) -> HAMTNode<K, V, C> {
+    let frag_at = |hash: C::Hash| fragment::<C>(hash.shift(C::BITS * level));
+    // The sort is stable, so items keep their relative order within each entry.
+    items.sort_by_key(|(hash, _, _)| frag_at(*hash));
+    let mut presence_map = C::Bitmap::EMPTY;
+    let mut entries = Vec::new();
+    while let Some((hash, _, _)) = items.last() {
+        let frag = frag_at(*hash);
+        let start = items.partition_point(|(hash, _, _)| frag_at(*hash) < frag);
+        let group = items.split_off(start);
+        presence_map = presence_map.with(frag);
+        entries.push(build_entry(group, level + 1));
+    }
+    entries.reverse();
+    HAMTNode { presence_map, entries }
+}
+
+/// Merge two entries for the same hash prefix, where `level` is the level of the node the entries
+/// would point to. Values from `a` are kept for keys in both.
+fn union_entries<K, V, C>(a: &HAMTNodeEntry<K, V, C>, b: &HAMTNodeEntry<K, V, C>, level: u32) -> HAMTNodeEntry<K, V, C>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    C: HamtConfig,
+{
+    union_entries_with(a, b, level, &|_, a, _| a.clone())
+}
+
+/// Merge two entries like `union_entries`, with `resolve(key, a, b)` giving the value of keys in both.
+/// Subtrees shared by both entries are kept as they are, so `resolve(key, v, v)` must be `v`.
+fn union_entries_with<K, V, C, F>(
+    a: &HAMTNodeEntry<K, V, C>,
+    b: &HAMTNodeEntry<K, V, C>,
+    level: u32,
+    resolve: &F,
+) -> HAMTNodeEntry<K, V, C>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    C: HamtConfig,
+    F: Fn(&K, &V, &V) -> V,
+{
+    match (a, b) {
+        (HAMTNodeEntry::Node(x), HAMTNodeEntry::Node(y)) => {
+            if NodePtr::ptr_eq(x, y) {
+                return a.clone();
+            }
+            let mut present = x.presence_map.to_u64() | y.presence_map.to_u64();
+            let mut node: HAMTNode<K, V, C> = HAMTNode {
+                presence_map: C::Bitmap::EMPTY,
+                entries: Vec::new(),
+            };
+            while present != 0 {
+                let frag = present.trailing_zeros();
+                present &= present - 1;
+                let x_entry = x.presence_map.contains(frag).then(|| &x.entries[get_entries_index(x.presence_map, frag)]);
+                let y_entry = y.presence_map.contains(frag).then(|| &y.entries[get_entries_index(y.presence_map, frag)]);
+                node.presence_map = node.presence_map.with(frag);
+                node.entries.push(match (x_entry, y_entry) {
+                    (Some(x_entry), Some(y_entry)) => union_entries_with(x_entry, y_entry, level + 1, resolve),
+                    (Some(entry), None) | (None, Some(entry)) => entry.clone(),
+                    (None, None) => unreachable!(),
+                });
+            }
+            HAMTNodeEntry::Node(NodePtr::new(node))
+        }
+        (HAMTNodeEntry::Node(x), other) | (other, HAMTNodeEntry::Node(x)) => {
+            // Insert the key of the other entry into the node, rather than rebuilding it. The other entry
+            // is a value, as chains are at the last level, where there are no nodes.
+            let (k, v, hash) = match other {
+                HAMTNodeEntry::Value(k, v, hash) => (k, v, *hash),
+                _ => unreachable!(),
+            };
+            let node = HAMT { root: x.clone() };
+            let value = match node.get_hashed(hash.shift(C::BITS * level), |existing| existing == k) {
+                Some((_, existing)) if std::ptr::eq(other, b) => resolve(k, existing, v),
+                Some((_, existing)) => resolve(k, v, existing),
+                None => v.clone(),
+            };
+            HAMTNodeEntry::Node(NodePtr::new(insert_at_node(&node.root, k.clone(), hash, value, level)))
+        }
+        (HAMTNodeEntry::Value(k1, v1, hash), HAMTNodeEntry::Value(k2, v2, _)) if k1 == k2 => {
+            HAMTNodeEntry::Value(k1.clone(), resolve(k1, v1, v2), *hash)
+        }
+        (HAMTNodeEntry::Chained(x), HAMTNodeEntry::Chained(y)) => {
+            // Two chains at the same position hold keys of the same hash, so their union stays chained,
+            // even if it is a single key, rather than becoming a value without its hash.
+            let mut items = x.clone();
+            for (k, v) in y.iter() {
+                match items.iter_mut().find(|(existing, _)| existing == k) {
+                    Some((_, existing)) => *existing = resolve(k, existing, v),
+                    None => items.push((k.clone(), v.clone())),
+                }
+            }
+            HAMTNodeEntry::Chained(items)
+        }
+        (a, b) => {
+            // At least one of the entries is a value, and a chain only meets a value of the same hash.
+            let chain_hash = match (a, b) {
+                (HAMTNodeEntry::Value(_, _, hash), _) | (_, HAMTNodeEntry::Value(_, _, hash)) => *hash,
+                _ => unreachable!(),
+            };
+            let mut items = Vec::new();
+            for_each_hashed(a, chain_hash, &mut |hash, k, v| items.push((hash, k.clone(), v.clone())));
+            for_each_hashed(b, chain_hash, &mut |hash, k, v| match items.iter_mut().find(|(_, existing, _)| existing == k) {
+                Some((_, _, existing)) => *existing = resolve(k, existing, v),
+                None => items.push((hash, k.clone(), v.clone())),
+            });
+            build_entry(items, level)
+        }
     }
 }
 
 /// Main method implementing insert at the current node.
-/// Level keeps track of how deep in the tree we are.
-fn insert_at_node<K: Hash + Eq + Clone, V: Clone>(
-    node: &HAMTNode<K, V>,
+/// Level keeps track of how deep in the tree we are, and `hashed_key` is the full hash of the key,
+/// which is stored with it so that splitting its entry later does not need to hash it again.
+fn insert_at_node<K: Eq + Clone, V: Clone, C: HamtConfig>(
+    node: &HAMTNode<K, V, C>,
     key: K,
-    cur_hashed_key: u64,
+    hashed_key: C::Hash,
     value: V,
     level: u32,
-) -> HAMTNode<K, V> {
-    let most_sig = ((cur_hashed_key & MOST_SIG) >> 59) as u32;
-    let key_present = (node.presence_map >> most_sig) & 1;
-    let entries_index = get_entries_index(node.presence_map, most_sig);
-    // Check if there is a key present in the node whose 5 most significant bits conflict withe current key's.
-    if key_present == 0 {
+) -> HAMTNode<K, V, C> {
+    let frag = fragment::<C>(hashed_key.shift(C::BITS * level));
+    let entries_index = get_entries_index(node.presence_map, frag);
+    // Check if there is a key present in the node whose fragment conflicts with the current key's.
+    if !node.presence_map.contains(frag) {
         // If the key is not present in the node, then the insert is more straightforward.
         // Copy the entries, insert the key and update the presence map.
-        let mut new_entries = node.entries.to_vec();
+        let mut new_entries = pool::copy_entries(&node.entries, 1);
 
-        new_entries.insert(entries_index, HAMTNodeEntry::Value(key, value));
-        return HAMTNode {
-            presence_map: node.presence_map | (1 << most_sig),
+        new_entries.insert(entries_index, HAMTNodeEntry::Value(key, value, hashed_key));
+        HAMTNode {
+            presence_map: node.presence_map.with(frag),
             entries: new_entries,
-        };
+        }
     } else {
         // If there is a conflicting key present, then we need to figure out how to update things
         // depending on the entry for that key prefix.
         let entry = &node.entries[entries_index];
-        match entry {
-            HAMTNodeEntry::Value(other_key, other_value) => {
+        let mut new_entries = pool::copy_entries(&node.entries, 0);
+        new_entries[entries_index] = match entry {
+            HAMTNodeEntry::Value(other_key, other_value, other_hashed_key) => {
                 // If there is a value in the entry
                 if other_key == &key {
                     // If it is for the same key, then just replace the value
-                    let mut new_entries = node.entries.to_vec();
-                    new_entries[entries_index] = HAMTNodeEntry::Value(key, value);
-                    return HAMTNode {
-                        presence_map: node.presence_map,
-                        entries: new_entries,
-                    };
+                    HAMTNodeEntry::Value(key, value, hashed_key)
                 } else {
-                    // Otherwise, we need to split this entry.
-                    let mut new_entries = node.entries.to_vec();
-                    let other_hashed_key = hash_key(other_key) << (5 * (level + 1));
-                    new_entries[entries_index] = create_split_entry(
+                    // Otherwise, we need to split this entry, with the hash the other key was inserted with.
+                    create_split_entry(
                         key,
-                        cur_hashed_key << 5,
+                        hashed_key,
                         value,
                         other_key.clone(),
-                        other_hashed_key,
+                        *other_hashed_key,
                         other_value.clone(),
                         level + 1,
-                    );
-                    return HAMTNode {
-                        presence_map: node.presence_map,
-                        entries: new_entries,
-                    };
+                    )
                 }
             }
             HAMTNodeEntry::Chained(vec) => {
                 // In a chain, we insert the key into the chain (replacing the existing value for that key if needed)
-                let new_chain = insert_chained(vec, key, value);
-                let mut new_entries = node.entries.to_vec();
-                new_entries[entries_index] = HAMTNodeEntry::Chained(new_chain);
-                return HAMTNode {
-                    presence_map: node.presence_map,
-                    entries: new_entries,
-                };
+                HAMTNodeEntry::Chained(insert_chained(vec, key, value))
             }
             HAMTNodeEntry::Node(child_node) => {
                 // If the entry points to another node, then we need to insert within that node.
-                let new_key = cur_hashed_key << 5;
-                let new_node = insert_at_node(child_node, key, new_key, value, level + 1);
-                let mut new_entries = node.entries.to_vec();
-                new_entries[entries_index] = HAMTNodeEntry::Node(Rc::new(new_node));
-                return HAMTNode {
-                    presence_map: node.presence_map,
-                    entries: new_entries,
-                };
+                let new_node = insert_at_node(child_node, key, hashed_key, value, level + 1);
+                HAMTNodeEntry::Node(NodePtr::new(new_node))
             }
+        };
+        HAMTNode {
+            presence_map: node.presence_map,
+            entries: new_entries,
         }
     }
 }
 
-/// Remove the given key at the node.
-fn remove_at_node<K: Eq + Clone, V: Clone>(
-    node: Rc<HAMTNode<K, V>>,
-    key: K,
-    cur_hashed_key: u64
-) -> Rc<HAMTNode<K, V>> {
-    let most_sig = ((cur_hashed_key & MOST_SIG) >> 59) as u32;
-    let key_present = (node.presence_map >> most_sig) & 1;
-    let entries_index = get_entries_index(node.presence_map, most_sig);
-    if key_present == 0 {
+/// Remove the key matched by `eq` at the node.
+/// Like the insert, `hashed_key` is the full hash of the key and level is how deep the node is.
+fn remove_at_node<K: Clone, V: Clone, C: HamtConfig, F: Fn(&K) -> bool>(
+    node: NodePtr<K, V, C>,
+    eq: &F,
+    hashed_key: C::Hash,
+    level: u32,
+) -> NodePtr<K, V, C> {
+    let frag = fragment::<C>(hashed_key.shift(C::BITS * level));
+    let entries_index = get_entries_index(node.presence_map, frag);
+    if !node.presence_map.contains(frag) {
         // If the key is not present at this level, we need to do nothing, so return the node
         node
     } else {
         let entry = &node.entries[entries_index];
         // Like the insert, what we need to do if the key's prefix is present depends on the entry for that
         // prefix
-        let ret_node = match entry {
+        match entry {
             HAMTNodeEntry::Chained(vec) => {
                 // If it is a chain, then go through the chain and remove the key if it exists.
                 let mut new_chain = vec.to_vec();
-                let mut new_entries = node.entries.to_vec();
-                let loc = new_chain.iter().position(|(k, _)| *k == key);
+                let loc = new_chain.iter().position(|(k, _)| eq(k));
                 match loc {
                     Some(i) => {
-                        new_chain.remove(i);                       
-                        if new_chain.len() == 0 {
+                        new_chain.remove(i);
+                        if new_chain.is_empty() {
                             // One special case: if the chain is now empty after removing the key,
                             // then the containing node can be updated to remove the entry pointing to
                             // that chain.
-                            new_entries.remove(entries_index);
                             let node = HAMTNode {
-                                presence_map: node.presence_map ^ (1 << most_sig),
-                                entries: new_entries
+                                presence_map: node.presence_map.without(frag),
+                                entries: pool::copy_entries_without(&node.entries, entries_index),
                             };
-                            Rc::new(node)
+                            NodePtr::new(node)
                         } else {
-                            new_entries[entries_index] = HAMTNodeEntry::Chained(new_chain);
+                            let mut new_entries = pool::copy_entries(&node.entries, 0);
+                            // A single remaining key becomes a value again. Its hash is the hash of the
+                            // removed key, as the keys of a chain have the same full hash.
+                            new_entries[entries_index] = if new_chain.len() == 1 {
+                                let (k, v) = new_chain.pop().unwrap();
+                                HAMTNodeEntry::Value(k, v, hashed_key)
+                            } else {
+                                HAMTNodeEntry::Chained(new_chain)
+                            };
                             let node = HAMTNode {
                                 presence_map: node.presence_map,
-                                entries: new_entries
+                                entries: new_entries,
                             };
-                            Rc::new(node)
+                            NodePtr::new(node)
                         }
                     }
-                    None => {
-                        node
-                    }
+                    None => node,
                 }
             }
             HAMTNodeEntry::Node(next_node) => {
                 // If it is a node, then recurse through removing the node
-                let new_node = remove_at_node(
-                    Rc::clone(next_node), key, cur_hashed_key << 5
-                );
-                let mut new_entries = node.entries.to_vec();
-                if new_node.presence_map == 0 {
+                let new_node = remove_at_node(next_node.clone(), eq, hashed_key, level + 1);
+                if new_node.presence_map == C::Bitmap::EMPTY {
                     // Also clean up the node from its parent's presence map if the node is entry.
-                    new_entries.remove(entries_index);
                     let node = HAMTNode {
-                        presence_map: node.presence_map ^ (1 << most_sig),
-                        entries: new_entries
+                        presence_map: node.presence_map.without(frag),
+                        entries: pool::copy_entries_without(&node.entries, entries_index),
                     };
-                    Rc::new(node)
+                    NodePtr::new(node)
                 } else {
+                    let mut new_entries = pool::copy_entries(&node.entries, 0);
                     new_entries[entries_index] = HAMTNodeEntry::Node(new_node);
                     let node = HAMTNode {
                         presence_map: node.presence_map,
-                        entries: new_entries
+                        entries: new_entries,
                     };
-                    Rc::new(node)
+                    NodePtr::new(node)
                 }
             }
-            HAMTNodeEntry::Value(k, _) => {
+            HAMTNodeEntry::Value(k, _, _) => {
                 // If the entry is a value, this is the most direct case.
-                if *k == key {
+                if eq(k) {
                     // If the key matches, then remove the entry.
-                    let mut new_entries = node.entries.to_vec();
-                    new_entries.remove(entries_index);
                     let node = HAMTNode {
-                        presence_map: node.presence_map ^ (1 << most_sig),
-                        entries: new_entries
+                        presence_map: node.presence_map.without(frag),
+                        entries: pool::copy_entries_without(&node.entries, entries_index),
                     };
-                    Rc::new(node)
+                    NodePtr::new(node)
                 } else {
                     node
                 }
             }
-        };
-        ret_node
+        }
     }
 }
 
 impl<K, V> HAMT<K, V> {
     /// Construct a new HAMT.
     pub fn new() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K, V, C: HamtConfig> HAMT<K, V, C> {
+    /// Construct a new HAMT with the trie shape `C`,
+    /// e.g. `HAMT::<K, V, Config<6, u128>>::with_config()`.
+    pub fn with_config() -> Self {
         let root_node = HAMTNode {
-            presence_map: 0,
+            presence_map: C::Bitmap::EMPTY,
             entries: Vec::new(),
         };
         Self {
-            root: Rc::new(root_node),
+            root: NodePtr::new(root_node),
         }
     }
 
@@ -330,104 +605,95 @@ impl<K, V> HAMT<K, V> {
     pub fn height(&self) -> u32 {
         get_height(&self.root)
     }
+
+    /// Check if both maps are the same version, i.e. share their root node.
+    /// If this returns `true` the maps are equal, but equal maps need not share their root.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        NodePtr::ptr_eq(&self.root, &other.root)
+    }
 }
 
-impl<K, V> HAMT<K, V>
-where
-    K: Eq + Hash,
-{
-    /// Get the value stored at key if it exists, otherwise return `None`.
-    pub fn get(&self, key: K) -> Option<&V> {
-        // Hash the key first.
-        let hashed_key = hash_key(&key);
+impl<K, V, C: HamtConfig> Default for HAMT<K, V, C> {
+    fn default() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K, V, C: HamtConfig> HAMT<K, V, C> {
+    /// Compute the hash of a key the way this map does.
+    /// The result can be passed to the `_hashed` methods of this map, or any other map with the same
+    /// configuration, to avoid hashing the key again.
+    pub fn hash_of<Q: Hash + ?Sized>(&self, key: &Q) -> C::Hash {
+        hash_key::<C, Q>(key)
+    }
 
+    /// Get the key and value of the entry with the given hash that `eq` accepts,
+    /// if it exists, otherwise return `None`.
+    ///
+    /// `hash` is expected to be the hash the key was inserted with, normally [`hash_of`](HAMT::hash_of) it.
+    pub fn get_hashed<F: Fn(&K) -> bool>(&self, hash: C::Hash, eq: F) -> Option<(&K, &V)> {
         let mut cur_node = &self.root;
-        let mut cur_key = hashed_key;
-        'main: loop {
-            // Get the 5 most significant bits of the key.
-            // This will always be a number between 0 and 31.
-            // We use this to index into the up to 32 entries of the node.
-            // Casting to u32 is always safe, as after we bitshift we only have the 5 least
-            // significant bits.
-            let most_sig = ((cur_key & MOST_SIG) >> 59) as u32;
+        let mut cur_key = hash;
+        loop {
+            // Get the most significant bits of the key.
+            // We use this to index into the entries of the node.
+            let frag = fragment::<C>(cur_key);
 
             // Is the key present?
-            let key_present = (cur_node.presence_map >> most_sig) & 1;
-            if key_present == 0 {
+            if !cur_node.presence_map.contains(frag) {
                 break None;
             }
             // Count the number of present entries before this.
             // This will be the index in the entries array.
-            // We assume we don't lose anything casting to usize,
-            // i.e. that usize is at least 5 bits.
-            let entries_index = get_entries_index(cur_node.presence_map, most_sig);
-            // We can unwrap, as we are guaranteed that the length of the vector
+            let entries_index = get_entries_index(cur_node.presence_map, frag);
+            // We can index directly, as we are guaranteed that the length of the vector
             // is at least the number of ones in the presence map.
             let entry = &cur_node.entries[entries_index];
             match entry {
-                HAMTNodeEntry::Value(k, v) => {
-                    if *k == key {
-                        break Some(&v);
+                HAMTNodeEntry::Value(k, v, _) => {
+                    if eq(k) {
+                        break Some((k, v));
                     } else {
                         break None;
                     }
                 }
                 HAMTNodeEntry::Chained(vec) => {
-                    for (k, v) in vec {
-                        if *k == key {
-                            break 'main Some(&v);
-                        }
-                    }
+                    // Chains only exist at the bottom level, so if the key is not in it, it is not in the map.
+                    break vec.iter().find(|(k, _)| eq(k)).map(|(k, v)| (k, v));
                 }
                 HAMTNodeEntry::Node(new_node) => {
-                    cur_node = &new_node;
-                    // Move the key so the next 5 bits are in position
-                    cur_key = cur_key << 5;
+                    cur_node = new_node;
+                    // Move the key so the next fragment is in position
+                    cur_key = next_hash::<C>(cur_key);
                 }
             }
         }
     }
+}
+
+impl<K, V, C> HAMT<K, V, C>
+where
+    K: Eq + Hash,
+    C: HamtConfig,
+{
+    /// Get the value stored at key if it exists, otherwise return `None`.
+    pub fn get(&self, key: K) -> Option<&V> {
+        // Hash the key first, then find the entry with an equal key.
+        let hashed_key = hash_key::<C, K>(&key);
+        self.get_hashed(hashed_key, |k| *k == key).map(|(_, v)| v)
+    }
 
     /// Check if the HAMT contains the given key, and return `true` if so and `false` if not.
     pub fn contains_key(&self, key: K) -> bool {
-        let hashed_key = hash_key(&key);
-        let mut cur_node = &self.root;
-        let mut cur_key = hashed_key;
-        // The main body of this is very similar to `get`, only we just finish when we find
-        // a matching key.
-        'main: loop {
-            let most_sig = ((cur_key & MOST_SIG) >> 59) as u32;
-
-            let key_present = (cur_node.presence_map >> most_sig) & 1;
-            if key_present == 0 {
-                break false;
-            }
-            let entries_index = get_entries_index(cur_node.presence_map, most_sig);
-            let entry = &cur_node.entries[entries_index];
-            match entry {
-                HAMTNodeEntry::Value(k, _) => {
-                    break *k == key;
-                }
-                HAMTNodeEntry::Chained(vec) => {
-                    for (k, _) in vec {
-                        if *k == key {
-                            break 'main true;
-                        }
-                    }
-                }
-                HAMTNodeEntry::Node(next_node) => {
-                    cur_node = &next_node;
-                    cur_key = cur_key << 5;
-                }
-            }
-        }
+        let hashed_key = hash_key::<C, K>(&key);
+        self.get_hashed(hashed_key, |k| *k == key).is_some()
     }
 }
 
 impl<K, V> HAMT<K, V>
 where
     K: Eq + Hash + Clone,
-    V: Clone
+    V: Clone,
 {
     /// Create a HAMT from the given array of pairs.
     pub fn from<const N: usize>(items: [(K, V); N]) -> Self {
@@ -437,27 +703,67 @@ where
         }
         map
     }
+}
 
+impl<K, V, C> HAMT<K, V, C>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    C: HamtConfig,
+{
     /// Insert the given key and value in to the map.
     /// Return a new HAMT, with the existing one unaffected.
-    pub fn insert(&self, key: K, value: V) -> HAMT<K, V> {
-        let hashed_key = hash_key(&key);
-        let new_root = insert_at_node(&self.root, key, hashed_key, value, 0);
+    pub fn insert(&self, key: K, value: V) -> HAMT<K, V, C> {
+        let hashed_key = hash_key::<C, K>(&key);
+        self.insert_hashed(hashed_key, key, value)
+    }
+
+    /// Insert the given key and value in to the map, where `hash` is the already computed
+    /// [`hash_of`](HAMT::hash_of) the key, or any other hash the caller uses for it consistently,
+    /// such as a content digest.
+    /// Return a new HAMT, with the existing one unaffected.
+    ///
+    /// The hash is stored with the key, so the key stays where `get_hashed` and `remove_hashed` look
+    /// for it under the same hash. A key inserted under a hash other than `hash_of` is only found by
+    /// the `_hashed` methods.
+    pub fn insert_hashed(&self, hash: C::Hash, key: K, value: V) -> HAMT<K, V, C> {
+        let new_root = insert_at_node(&self.root, key, hash, value, 0);
         HAMT {
-            root: Rc::new(new_root),
+            root: NodePtr::new(new_root),
         }
     }
 
     /// Remove the given key from the map, if it is present.
     /// Return a HAMT, with the existing one unaffected.
-    pub fn remove(&self, key: K) -> HAMT<K, V> {
-        let hashed_key = hash_key(&key);
-        let new_root = remove_at_node(Rc::clone(&self.root), key, hashed_key);
+    pub fn remove(&self, key: K) -> HAMT<K, V, C> {
+        let hashed_key = hash_key::<C, K>(&key);
+        self.remove_hashed(hashed_key, |k| *k == key)
+    }
+
+    /// Remove the entry with the given hash that `eq` accepts, if it is present.
+    /// Return a HAMT, with the existing one unaffected.
+    ///
+    /// `hash` is expected to be the hash the key was inserted with, normally [`hash_of`](HAMT::hash_of) it.
+    pub fn remove_hashed<F: Fn(&K) -> bool>(&self, hash: C::Hash, eq: F) -> HAMT<K, V, C> {
+        let new_root = remove_at_node(self.root.clone(), &eq, hash, 0);
         HAMT { root: new_root }
     }
 }
 
-impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for HAMTNode<K, V> {
+// Give the entries buffer back to the pool of the current thread, if one is installed.
+impl<K, V, C: HamtConfig> Drop for HAMTNode<K, V, C> {
+    fn drop(&mut self) {
+        pool::release_vec(std::mem::take(&mut self.entries));
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, C: HamtConfig> fmt::Debug for HAMT<K, V, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HAMT").field("root", &*self.root).finish()
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, C: HamtConfig> fmt::Debug for HAMTNode<K, V, C> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("HAMTNode")
             .field("presence_map", &format!("{:#b}", &self.presence_map))
@@ -466,22 +772,92 @@ impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for HAMTNode<K, V> {
     }
 }
 
-impl<K, V> Clone for HAMT<K, V>
-where
-    K: Clone,
-    V: Clone
-{
+impl<K: fmt::Debug, V: fmt::Debug, C: HamtConfig> fmt::Debug for HAMTNodeEntry<K, V, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HAMTNodeEntry::Value(k, v, _) => f.debug_tuple("Value").field(k).field(v).finish(),
+            HAMTNodeEntry::Node(node) => f.debug_tuple("Node").field(&**node).finish(),
+            HAMTNodeEntry::Chained(vec) => f.debug_tuple("Chained").field(vec).finish(),
+        }
+    }
+}
+
+// We can't derive Clone, as that would require `C: Clone`. Cloning a `Node` entry only clones the pointer.
+impl<K: Clone, V: Clone, C: HamtConfig> Clone for HAMTNodeEntry<K, V, C> {
+    fn clone(&self) -> Self {
+        match self {
+            HAMTNodeEntry::Value(k, v, hash) => HAMTNodeEntry::Value(k.clone(), v.clone(), *hash),
+            HAMTNodeEntry::Node(node) => HAMTNodeEntry::Node(node.clone()),
+            HAMTNodeEntry::Chained(vec) => HAMTNodeEntry::Chained(vec.clone()),
+        }
+    }
+}
+
+impl<K: Hash + Eq, V: PartialEq, C: HamtConfig> PartialEq for HAMT<K, V, C> {
+    fn eq(&self, other: &Self) -> bool {
+        nodes_eq(&self.root, &other.root)
+    }
+}
+
+impl<K: Hash + Eq, V: Eq, C: HamtConfig> Eq for HAMT<K, V, C> {}
+
+// Cloning a map only clones the pointer to its root, so it places no constraint on the keys and values.
+impl<K, V, C: HamtConfig> Clone for HAMT<K, V, C> {
     fn clone(&self) -> Self {
         Self {
-            root: Rc::clone(&self.root)
+            root: self.root.clone(),
         }
     }
 }
 
+/// Fixtures shared by the tests of several modules.
+#[cfg(test)]
+pub(crate) mod test_util {
+    use crate::{Codec, DecodeError, HamtConfig, HAMT};
+    use std::hash::{Hash, Hasher};
+
+    /// A key whose hash only depends on `bucket`, used to force hash collisions.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub(crate) struct Colliding {
+        pub(crate) bucket: u8,
+        pub(crate) id: i32,
+    }
+
+    impl Hash for Colliding {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.bucket.hash(state);
+        }
+    }
+
+    impl Codec for Colliding {
+        fn encode(&self, out: &mut Vec<u8>) {
+            self.bucket.encode(out);
+            self.id.encode(out);
+        }
+
+        fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+            Ok(Colliding {
+                bucket: u8::decode(input)?,
+                id: i32::decode(input)?,
+            })
+        }
+    }
+
+    /// A map from each of `keys` to itself.
+    pub(crate) fn build(keys: impl IntoIterator<Item = i32>) -> HAMT<i32, i32> {
+        build_with(keys)
+    }
+
+    /// A map with the configuration `C` from each of `keys` to itself.
+    pub(crate) fn build_with<C: HamtConfig>(keys: impl IntoIterator<Item = i32>) -> HAMT<i32, i32, C> {
+        keys.into_iter().fold(HAMT::with_config(), |map, k| map.insert(k, k))
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use crate::HAMT;
+    use crate::test_util::Colliding;
+    use crate::{Config, HAMT};
 
     fn setup_big_map() -> (i32, HAMT<i32, i32>) {
         let num_keys = 10000;
@@ -581,4 +957,145 @@ mod tests {
         }
     }
 
+    #[test]
+    fn full_collisions_chain() {
+        let mut map = HAMT::new();
+        for id in 0..50 {
+            map = map.insert(Colliding { bucket: id as u8 % 2, id }, id);
+        }
+        for id in 0..50 {
+            assert_eq!(map.get(Colliding { bucket: id as u8 % 2, id }), Some(&id));
+        }
+        // Same hash as present keys, but not in the chain.
+        assert_eq!(map.get(Colliding { bucket: 0, id: 51 }), None);
+        assert!(!map.contains_key(Colliding { bucket: 1, id: 50 }));
+        assert_eq!(map.height(), 14);
+
+        for id in (0..50).step_by(2) {
+            map = map.remove(Colliding { bucket: 0, id });
+        }
+        assert!(!map.contains_key(Colliding { bucket: 0, id: 0 }));
+        assert!(map.contains_key(Colliding { bucket: 1, id: 1 }));
+    }
+
+    fn check_config<C: crate::HamtConfig>() {
+        let mut map: HAMT<i32, i32, C> = HAMT::with_config();
+        for k in 1..5000 {
+            map = map.insert(k, -k);
+        }
+        for k in (1..5000).step_by(3) {
+            map = map.remove(k);
+        }
+        for k in 1..5000 {
+            if k % 3 == 1 {
+                assert!(!map.contains_key(k));
+            } else {
+                assert_eq!(map.get(k), Some(&-k));
+            }
+        }
+    }
+
+    #[test]
+    fn configs() {
+        check_config::<Config<4>>();
+        check_config::<Config<5>>();
+        check_config::<Config<6>>();
+        check_config::<Config<4, u128>>();
+        check_config::<Config<5, u128>>();
+        check_config::<Config<6, u128>>();
+    }
+
+    #[test]
+    fn wide_hash_chains_at_depth() {
+        let mut map: HAMT<Colliding, i32, Config<6, u128>> = HAMT::with_config();
+        map = map.insert(Colliding { bucket: 3, id: 1 }, 1);
+        map = map.insert(Colliding { bucket: 3, id: 2 }, 2);
+        // 128 bits over 6 bits per level gives 22 levels of nodes, then a chain.
+        assert_eq!(map.height(), 23);
+        assert_eq!(map.get(Colliding { bucket: 3, id: 2 }), Some(&2));
+    }
+
+    #[test]
+    fn hashed_api() {
+        let (n, map) = setup_big_map();
+        let other: HAMT<i32, &str> = HAMT::new();
+        let hash = map.hash_of(&42);
+        // Hashes can be shared between maps with the same configuration.
+        let other = other.insert_hashed(hash, 42, "forty-two");
+        assert_eq!(other.get(42), Some(&"forty-two"));
+        assert_eq!(map.get_hashed(hash, |k| *k == 42), Some((&42, &-42)));
+        assert_eq!(map.get_hashed(hash, |k| *k == 43), None);
+
+        let map = map.remove_hashed(hash, |k| *k == 42);
+        assert!(!map.contains_key(42));
+        assert!(map.contains_key(41));
+        assert!(map.contains_key(n - 1));
+    }
+
+    #[test]
+    fn hashed_api_chains() {
+        let mut map = HAMT::new();
+        for id in 0..10 {
+            let key = Colliding { bucket: 7, id };
+            map = map.insert_hashed(map.hash_of(&key), key, id);
+        }
+        let hash = map.hash_of(&Colliding { bucket: 7, id: 0 });
+        assert_eq!(map.get_hashed(hash, |k| k.id == 3).map(|(_, v)| *v), Some(3));
+        let map = map.remove_hashed(hash, |k| k.id == 0);
+        assert_eq!(map.get_hashed(hash, |k| k.id == 0), None);
+        assert_eq!(map.get_hashed(hash, |k| k.id == 2).map(|(_, v)| *v), Some(2));
+    }
+
+    #[test]
+    fn hashed_api_own_hashes() {
+        // Hashes that are not `hash_of` the keys, such as digests, and that share long prefixes so
+        // that inserting splits entries many levels deep.
+        let mut map = HAMT::new();
+        for i in 0..1000u64 {
+            map = map.insert_hashed(i, i, i);
+        }
+        // Keys with the same hash are chained.
+        for i in 0..10u64 {
+            map = map.insert_hashed(i, 1000 + i, i);
+        }
+        for i in 0..1000u64 {
+            assert_eq!(map.get_hashed(i, |k| *k == i), Some((&i, &i)));
+        }
+        assert_eq!(map.get_hashed(3, |k| *k == 1003), Some((&1003, &3)));
+        for i in (0..1000u64).step_by(2) {
+            map = map.remove_hashed(i, |k| *k == i);
+        }
+        for i in 0..1000u64 {
+            assert_eq!(map.get_hashed(i, |k| *k == i).is_some(), i % 2 == 1);
+        }
+        assert_eq!(map.get_hashed(4, |k| *k == 1004), Some((&1004, &4)));
+        assert_eq!(map.iter().count(), 510);
+    }
+
+    #[test]
+    fn equality() {
+        let (n, map) = setup_big_map();
+        let (_, same) = setup_big_map();
+        assert!(!map.ptr_eq(&same));
+        assert_eq!(map, same);
+        assert_eq!(map, map.clone());
+        assert_ne!(map, map.insert(1, 1));
+        assert_ne!(map, map.remove(1));
+        // Removing a key again does not always restore the original shape of the trie.
+        assert_eq!(map, map.insert(n, 0).remove(n));
+        assert_eq!(map.remove(1), map.remove(1).insert(n, 0).remove(n));
+    }
+
+    #[test]
+    fn equality_chains() {
+        let mut forward = HAMT::new();
+        let mut backward = HAMT::new();
+        for id in 0..10 {
+            forward = forward.insert(Colliding { bucket: 0, id }, id);
+            backward = backward.insert(Colliding { bucket: 0, id: 9 - id }, 9 - id);
+        }
+        assert_eq!(forward, backward);
+        assert_ne!(forward, backward.insert(Colliding { bucket: 0, id: 3 }, 0));
+        assert_ne!(forward, backward.remove(Colliding { bucket: 0, id: 3 }));
+    }
 }
@@ -0,0 +1,424 @@
+//! Content digests of the nodes of maps, for comparing replicas of a map subtree by subtree.
+//!
+//! A [`Merkle`] table gives every node a digest of the encoded keys and values of its entries and of
+//! the digests of its child nodes, as in a Merkle tree. Two replicas can compare their root digests,
+//! and descend only into the entries whose digests differ, to find what changed without sending
+//! the rest of the map.
+//!
+//! Digests only depend on the contents of the map, not on how it was built: the entries of a chain
+//! are digested in the order of their encodings, and a child node holding a single value or chain
+//! has the digest of that entry, as removals can leave such nodes where inserting the same keys
+//! would have stored the entry inline.
+//!
+//! Digests are computed on demand and remembered by node, so a node shared by several versions of a
+//! map is digested once. Like [`NodeInterner`](crate::NodeInterner), the table only keeps weak
+//! references to the nodes, and [`purge`](Merkle::purge) forgets the nodes that were dropped.
+
+use std::fmt;
+use std::hash::Hash;
+
+use crate::cache::NodeCache;
+use crate::codec::Codec;
+use crate::view::{EntryRef, NodeRef};
+use crate::{Bitmap, DefaultConfig, HamtConfig, NodePtr, HAMT};
+
+const TAG_VALUE: u8 = 0;
+const TAG_CHAINED: u8 = 1;
+const TAG_NODE: u8 = 2;
+
+/// A hash function for the digests of nodes.
+pub trait Digest {
+    /// The digest of some bytes.
+    type Output: Copy + Eq + Hash + fmt::Debug + AsRef<[u8]>;
+
+    /// Compute the digest of `bytes`.
+    fn digest(bytes: &[u8]) -> Self::Output;
+}
+
+/// The SHA-256 hash function.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Sha256;
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01,
+    0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc,
+    0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+    0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116, 0x1e376c08,
+    0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+impl Digest for Sha256 {
+    type Output = [u8; 32];
+
+    fn digest(bytes: &[u8]) -> [u8; 32] {
+        let mut state: [u32; 8] = [
+            0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+        ];
+        // Pad with a one bit, zeros, and the length in bits, to a whole number of 64-byte blocks.
+        let mut message = bytes.to_vec();
+        message.push(0x80);
+        while message.len() % 64 != 56 {
+            message.push(0);
+        }
+        message.extend_from_slice(&(bytes.len() as u64).wrapping_mul(8).to_be_bytes());
+
+        for block in message.chunks(64) {
+            let mut schedule = [0u32; 64];
+            for (word, bytes) in schedule.iter_mut().zip(block.chunks(4)) {
+                *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+            for i in 16..64 {
+                let s0 = schedule[i - 15].rotate_right(7) ^ schedule[i - 15].rotate_right(18) ^ (schedule[i - 15] >> 3);
+                let s1 = schedule[i - 2].rotate_right(17) ^ schedule[i - 2].rotate_right(19) ^ (schedule[i - 2] >> 10);
+                schedule[i] = schedule[i - 16].wrapping_add(s0).wrapping_add(schedule[i - 7]).wrapping_add(s1);
+            }
+            let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+            for (constant, word) in ROUND_CONSTANTS.iter().zip(schedule.iter()) {
+                let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+                let choice = (e & f) ^ (!e & g);
+                let t1 = h.wrapping_add(s1).wrapping_add(choice).wrapping_add(*constant).wrapping_add(*word);
+                let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+                let majority = (a & b) ^ (a & c) ^ (b & c);
+                let t2 = s0.wrapping_add(majority);
+                h = g;
+                g = f;
+                f = e;
+                e = d.wrapping_add(t1);
+                d = c;
+                c = b;
+                b = a;
+                a = t1.wrapping_add(t2);
+            }
+            for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+                *word = word.wrapping_add(value);
+            }
+        }
+
+        let mut digest = [0; 32];
+        for (bytes, word) in digest.chunks_mut(4).zip(state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+/// A table of the digests of the nodes of maps, computed with `D`.
+pub struct Merkle<K, V, C: HamtConfig = DefaultConfig, D: Digest = Sha256> {
+    /// The digest of each node, and whether it is the digest of the single value or chain under the node.
+    digests: NodeCache<K, V, C, (D::Output, bool)>,
+}
+
+impl<K, V> Merkle<K, V> {
+    /// Construct an empty table, for maps of the default configuration digested with SHA-256.
+    pub fn new() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K, V, C: HamtConfig, D: Digest> Merkle<K, V, C, D> {
+    /// Construct an empty table for maps with the trie shape `C`, digested with `D`.
+    pub fn with_config() -> Self {
+        Merkle {
+            digests: NodeCache::new(),
+        }
+    }
+
+    /// Number of digests of nodes that are still in use by some map.
+    pub fn len(&self) -> usize {
+        self.digests.len()
+    }
+
+    /// Check if no digest of a node in use is remembered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget the digests of the nodes that are no longer used by any map.
+    pub fn purge(&mut self) {
+        self.digests.purge();
+    }
+}
+
+impl<K, V, C: HamtConfig, D: Digest> Default for Merkle<K, V, C, D> {
+    fn default() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K: Codec, V: Codec, C: HamtConfig, D: Digest> Merkle<K, V, C, D> {
+    /// The digest of the root node of `map`. Maps with the same entries have the same root digest.
+    pub fn root_digest(&mut self, map: &HAMT<K, V, C>) -> D::Output {
+        self.digest_node(&map.root).0
+    }
+
+    /// The digest of a node, which covers every entry under it.
+    pub fn node_digest(&mut self, node: NodeRef<'_, K, V, C>) -> D::Output {
+        self.digest_node(node.node()).0
+    }
+
+    /// The digest of an entry of a node: of its key and value, of the keys and values of its chain,
+    /// or of its child node.
+    pub fn entry_digest(&mut self, entry: EntryRef<'_, K, V, C>) -> D::Output {
+        self.digest_entry(entry).0
+    }
+
+    /// The digest of the entry, and whether it is the digest of a single value or chain.
+    fn digest_entry(&mut self, entry: EntryRef<'_, K, V, C>) -> (D::Output, bool) {
+        match entry {
+            EntryRef::Value(k, v) => {
+                let mut bytes = vec![TAG_VALUE];
+                k.encode(&mut bytes);
+                v.encode(&mut bytes);
+                (D::digest(&bytes), true)
+            }
+            // A chain of one key is the same contents as a value, so it gets the same digest.
+            EntryRef::Chained([(k, v)]) => self.digest_entry(EntryRef::Value(k, v)),
+            EntryRef::Chained(chain) => {
+                let mut pairs: Vec<Vec<u8>> = chain
+                    .iter()
+                    .map(|(k, v)| {
+                        let mut bytes = Vec::new();
+                        k.encode(&mut bytes);
+                        v.encode(&mut bytes);
+                        bytes
+                    })
+                    .collect();
+                pairs.sort();
+                let mut bytes = vec![TAG_CHAINED];
+                for pair in pairs {
+                    pair.len().encode(&mut bytes);
+                    bytes.extend_from_slice(&pair);
+                }
+                (D::digest(&bytes), true)
+            }
+            EntryRef::Node(node) => self.digest_node(node.node()),
+        }
+    }
+
+    fn digest_node(&mut self, node: &NodePtr<K, V, C>) -> (D::Output, bool) {
+        if let Some(cached) = self.digests.get(node) {
+            return *cached;
+        }
+        let entries: Vec<(D::Output, bool)> = node
+            .entries
+            .iter()
+            .map(|entry| self.digest_entry(EntryRef::new(entry, 0)))
+            .collect();
+        let (digest, leaf) = match entries[..] {
+            [(digest, true)] => (digest, true),
+            _ => {
+                let mut bytes = vec![TAG_NODE];
+                node.presence_map.to_u64().encode(&mut bytes);
+                for (digest, _) in &entries {
+                    bytes.extend_from_slice(digest.as_ref());
+                }
+                (D::digest(&bytes), false)
+            }
+        };
+        self.digests.insert(node, (digest, leaf));
+        (digest, leaf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Digest, Merkle, Sha256};
+    use crate::test_util::Colliding;
+    use crate::{Bitmap, Config, EntryRef, NodeRef, HAMT};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn hex(digest: [u8; 32]) -> String {
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn sha256() {
+        assert_eq!(hex(Sha256::digest(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(Sha256::digest(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(
+            hex(Sha256::digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn digests_depend_on_contents_only() {
+        let mut merkle = Merkle::new();
+        let map = (0..2000u32).fold(HAMT::new(), |map, k| map.insert(k, k));
+        let reversed = (0..2000u32).rev().fold(HAMT::new(), |map, k| map.insert(k, k));
+        assert_eq!(merkle.root_digest(&map), merkle.root_digest(&reversed));
+
+        // Removing keys leaves nodes with a single entry, which inserting the rest does not create.
+        let removed = (100..2000u32).fold(map.clone(), |map, k| map.remove(k));
+        let inserted = (0..100u32).fold(HAMT::new(), |map, k| map.insert(k, k));
+        assert!(removed.height() > inserted.height());
+        assert_eq!(merkle.root_digest(&removed), merkle.root_digest(&inserted));
+
+        let changed = map.insert(7, 8);
+        assert_ne!(merkle.root_digest(&map), merkle.root_digest(&changed));
+        assert_eq!(merkle.root_digest(&map), merkle.root_digest(&changed.insert(7, 7)));
+        let empty = HAMT::new();
+        assert_ne!(merkle.root_digest(&empty), merkle.root_digest(&empty.insert(0, 0)));
+
+        // The shared nodes of the versions were digested once.
+        let nodes = merkle.len();
+        drop((reversed, removed, inserted, changed));
+        merkle.purge();
+        assert!(merkle.len() < nodes);
+
+        // Chains are digested regardless of the order of their entries.
+        let mut merkle = Merkle::<u64, u8, Config<4, u128>>::with_config();
+        let forward = HAMT::with_config().insert(1, 1).insert(2, 2);
+        let backward = HAMT::with_config().insert(2, 2).insert(1, 1);
+        assert_eq!(merkle.root_digest(&forward), merkle.root_digest(&backward));
+
+        // A chain of one key is digested like the key on its own.
+        let mut merkle = Merkle::<Colliding, i32, Config<4, u128>>::with_config();
+        let key = |id| Colliding { bucket: 0, id };
+        let chained = HAMT::with_config().insert(key(1), 1).insert(key(2), 2).remove(key(2));
+        assert_eq!(merkle.root_digest(&chained), merkle.root_digest(&HAMT::with_config().insert(key(1), 1)));
+        assert_eq!(
+            merkle.entry_digest(EntryRef::Chained(&[(key(1), 1)])),
+            merkle.entry_digest(EntryRef::Value(&key(1), &1))
+        );
+    }
+
+    /// What a peer tells about an entry of one of its nodes.
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    enum Summary {
+        Node([u8; 32]),
+        Leaf([u8; 32]),
+    }
+
+    impl Summary {
+        fn digest(self) -> [u8; 32] {
+            match self {
+                Summary::Node(digest) | Summary::Leaf(digest) => digest,
+            }
+        }
+    }
+
+    /// A replica of a map, answering the requests of another replica.
+    struct Peer {
+        map: HAMT<u32, String>,
+        merkle: Merkle<u32, String>,
+        /// Number of entries sent in answers.
+        sent: usize,
+    }
+
+    fn node_at<'a>(map: &'a HAMT<u32, String>, path: &[u32]) -> NodeRef<'a, u32, String, crate::DefaultConfig> {
+        path.iter().fold(map.root_view(), |node, frag| match node.entry(*frag) {
+            Some(EntryRef::Node(child)) => child,
+            _ => panic!("no node at {:?}", path),
+        })
+    }
+
+    fn collect(entry: EntryRef<'_, u32, String, crate::DefaultConfig>, out: &mut Vec<(u32, String)>) {
+        match entry {
+            EntryRef::Value(k, v) => out.push((*k, v.clone())),
+            EntryRef::Chained(chain) => out.extend(chain.iter().cloned()),
+            EntryRef::Node(node) => node.children().for_each(|entry| collect(entry, out)),
+        }
+    }
+
+    impl Peer {
+        fn root(&mut self) -> [u8; 32] {
+            self.merkle.root_digest(&self.map)
+        }
+
+        /// The digests of the entries of the node at `path`, by fragment.
+        fn summaries(&mut self, path: &[u32]) -> BTreeMap<u32, Summary> {
+            let node = node_at(&self.map, path);
+            let mut present = node.bitmap().to_u64();
+            let mut summaries = BTreeMap::new();
+            for entry in node.children() {
+                let frag = present.trailing_zeros();
+                present &= present - 1;
+                let digest = self.merkle.entry_digest(entry);
+                summaries.insert(
+                    frag,
+                    match entry {
+                        EntryRef::Node(_) => Summary::Node(digest),
+                        _ => Summary::Leaf(digest),
+                    },
+                );
+            }
+            summaries
+        }
+
+        /// Every entry under the entry for `frag` of the node at `path`.
+        fn entries(&mut self, path: &[u32], frag: u32) -> Vec<(u32, String)> {
+            let mut entries = Vec::new();
+            collect(node_at(&self.map, path).entry(frag).unwrap(), &mut entries);
+            self.sent += entries.len();
+            entries
+        }
+    }
+
+    /// Bring `local` up to date with `remote`, descending only into the subtrees whose digests differ.
+    /// Return the number of requests made.
+    fn pull(local: &mut Peer, remote: &mut Peer) -> usize {
+        let mut requests = 1;
+        if local.root() == remote.root() {
+            return requests;
+        }
+        let mut updated = local.map.clone();
+        let mut pending = vec![Vec::new()];
+        while let Some(path) = pending.pop() {
+            requests += 1;
+            let theirs = remote.summaries(&path);
+            let ours = local.summaries(&path);
+            let frags: BTreeSet<u32> = ours.keys().chain(theirs.keys()).copied().collect();
+            for frag in frags {
+                match (ours.get(&frag), theirs.get(&frag)) {
+                    (a, b) if a.map(|a| a.digest()) == b.map(|b| b.digest()) => {}
+                    (Some(Summary::Node(_)), Some(Summary::Node(_))) => {
+                        let mut child = path.clone();
+                        child.push(frag);
+                        pending.push(child);
+                    }
+                    (a, b) => {
+                        if a.is_some() {
+                            updated = local.entries(&path, frag).into_iter().fold(updated, |map, (k, _)| map.remove(k));
+                        }
+                        if b.is_some() {
+                            requests += 1;
+                            updated = remote.entries(&path, frag).into_iter().fold(updated, |map, (k, v)| map.insert(k, v));
+                        }
+                    }
+                }
+            }
+        }
+        local.map = updated;
+        requests
+    }
+
+    #[test]
+    fn sync_replicas() {
+        let map = (0..10_000u32).fold(HAMT::new(), |map, k| map.insert(k, k.to_string()));
+        let mut a = Peer {
+            map: map.clone(),
+            merkle: Merkle::new(),
+            sent: 0,
+        };
+        let mut b = Peer {
+            map: map.insert(3, "three".to_string()).remove(4000).insert(20_000, "new".to_string()),
+            merkle: Merkle::new(),
+            sent: 0,
+        };
+        a.map = a.map.remove(9999).insert(10_001, "a only".to_string());
+
+        let requests = pull(&mut a, &mut b);
+        assert_eq!(a.map, b.map);
+        assert_eq!(a.root(), b.root());
+        assert!(b.sent < 50, "sent {} entries", b.sent);
+        assert!(requests < 100, "made {} requests", requests);
+
+        // Once in sync, comparing the roots is enough.
+        let sent = a.sent;
+        assert_eq!(pull(&mut b, &mut a), 1);
+        assert_eq!(a.sent, sent);
+    }
+}
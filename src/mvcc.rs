@@ -0,0 +1,291 @@
+//! Multi-version concurrency control over versions of a map.
+//!
+//! A [`VersionedHamt`] numbers each committed version of a map. Reading an old version is reading
+//! the map of that version, which shares all its unchanged nodes with the others, so keeping
+//! versions around only costs the nodes they do not share. A [`Transaction`] reads the version it
+//! started at, buffers its writes, and at commit applies them to the latest version, unless a
+//! version committed in the meantime wrote one of the same keys.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+
+use crate::{DefaultConfig, HamtConfig, HAMT};
+
+/// A committed version: its map, and the keys its commit wrote.
+struct Version<K, V, C: HamtConfig> {
+    map: HAMT<K, V, C>,
+    writes: HAMT<K, (), C>,
+}
+
+/// Versions of a map, numbered from 0 for the empty map.
+pub struct VersionedHamt<K, V, C: HamtConfig = DefaultConfig> {
+    versions: BTreeMap<u64, Version<K, V, C>>,
+    latest: u64,
+}
+
+/// Reads of a version of a map, and writes to commit on top of the latest version.
+pub struct Transaction<K, V, C: HamtConfig = DefaultConfig> {
+    start: u64,
+    snapshot: HAMT<K, V, C>,
+    /// The value written for each key, or `None` for a removal.
+    writes: HAMT<K, Option<V>, C>,
+}
+
+/// Why a transaction could not be committed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommitError {
+    /// The version committed after the transaction started wrote one of the keys it wrote.
+    Conflict { version: u64 },
+    /// The versions committed after the transaction started were collected, so conflicts cannot be
+    /// checked: the transaction started at `start`, and the oldest version left is `oldest`.
+    SnapshotTooOld { start: u64, oldest: u64 },
+}
+
+impl fmt::Display for CommitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommitError::Conflict { version } => write!(f, "write-write conflict with version {}", version),
+            CommitError::SnapshotTooOld { start, oldest } => write!(
+                f,
+                "transaction started at version {}, but versions before {} were collected",
+                start, oldest
+            ),
+        }
+    }
+}
+
+impl Error for CommitError {}
+
+impl<K, V> VersionedHamt<K, V> {
+    /// Construct a store whose version 0 is the empty map.
+    pub fn new() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K, V, C: HamtConfig> VersionedHamt<K, V, C> {
+    /// Construct a store for maps with the trie shape `C`, whose version 0 is the empty map.
+    pub fn with_config() -> Self {
+        let mut versions = BTreeMap::new();
+        versions.insert(
+            0,
+            Version {
+                map: HAMT::with_config(),
+                writes: HAMT::with_config(),
+            },
+        );
+        VersionedHamt { versions, latest: 0 }
+    }
+
+    /// Number of the latest committed version.
+    pub fn version(&self) -> u64 {
+        self.latest
+    }
+
+    /// The map of the latest committed version.
+    pub fn latest(&self) -> &HAMT<K, V, C> {
+        &self.versions[&self.latest].map
+    }
+
+    /// The map as of `version`, unless that version was collected or is not committed yet.
+    pub fn as_of(&self, version: u64) -> Option<&HAMT<K, V, C>> {
+        self.versions.get(&version).map(|version| &version.map)
+    }
+
+    /// Number of the oldest version that was not collected.
+    pub fn oldest(&self) -> u64 {
+        *self.versions.keys().next().unwrap()
+    }
+
+    /// Release the versions before `oldest_live_version`, and the nodes only they used. The latest
+    /// version is always kept.
+    ///
+    /// Transactions that started before `oldest_live_version` can still read their version, but fail
+    /// to commit if they wrote keys and a collected version was committed after they started.
+    pub fn gc(&mut self, oldest_live_version: u64) {
+        let oldest = oldest_live_version.min(self.latest);
+        self.versions = self.versions.split_off(&oldest);
+    }
+
+    /// Start a transaction reading the latest version.
+    pub fn begin(&self) -> Transaction<K, V, C> {
+        self.begin_at(self.latest).unwrap()
+    }
+
+    /// Start a transaction reading `version`, unless it was collected or is not committed yet.
+    pub fn begin_at(&self, version: u64) -> Option<Transaction<K, V, C>> {
+        self.as_of(version).map(|map| Transaction {
+            start: version,
+            snapshot: map.clone(),
+            writes: HAMT::with_config(),
+        })
+    }
+}
+
+impl<K, V, C: HamtConfig> Default for VersionedHamt<K, V, C> {
+    fn default() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K, V, C> VersionedHamt<K, V, C>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    C: HamtConfig,
+{
+    /// Commit the writes of `txn` on top of the latest version, and return the number of the new version.
+    /// A transaction without writes commits nothing, and returns the latest version.
+    ///
+    /// Fails if a version committed after the transaction started wrote one of the keys it wrote.
+    pub fn commit(&mut self, txn: Transaction<K, V, C>) -> Result<u64, CommitError> {
+        if txn.writes.iter().next().is_none() {
+            return Ok(self.latest);
+        }
+        let oldest = self.oldest();
+        if txn.start + 1 < oldest {
+            return Err(CommitError::SnapshotTooOld { start: txn.start, oldest });
+        }
+        for (number, version) in self.versions.range(txn.start + 1..) {
+            if txn.writes.iter().any(|(k, _)| version.writes.contains_key(k.clone())) {
+                return Err(CommitError::Conflict { version: *number });
+            }
+        }
+
+        let mut map = self.latest().clone();
+        let mut writes = HAMT::with_config();
+        for (k, value) in txn.writes.iter() {
+            map = match value {
+                Some(v) => map.insert(k.clone(), v.clone()),
+                None => map.remove(k.clone()),
+            };
+            writes = writes.insert(k.clone(), ());
+        }
+        self.latest += 1;
+        self.versions.insert(self.latest, Version { map, writes });
+        Ok(self.latest)
+    }
+}
+
+impl<K, V, C: HamtConfig> Transaction<K, V, C> {
+    /// The version the transaction reads.
+    pub fn version(&self) -> u64 {
+        self.start
+    }
+}
+
+impl<K, V, C> Transaction<K, V, C>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    C: HamtConfig,
+{
+    /// Get the value of `key`, as written by the transaction, or in the version it reads otherwise.
+    pub fn get(&self, key: K) -> Option<&V> {
+        match self.writes.get(key.clone()) {
+            Some(written) => written.as_ref(),
+            None => self.snapshot.get(key),
+        }
+    }
+
+    /// Write `value` for `key`.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.writes = self.writes.insert(key, Some(value));
+    }
+
+    /// Remove `key`.
+    pub fn remove(&mut self, key: K) {
+        self.writes = self.writes.insert(key, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommitError, VersionedHamt};
+    use std::rc::Rc;
+
+    #[test]
+    fn snapshot_reads() {
+        let mut store = VersionedHamt::new();
+        let mut txn = store.begin();
+        for k in 0..100 {
+            txn.insert(k, k);
+        }
+        assert_eq!(store.commit(txn), Ok(1));
+        let reader = store.begin();
+        let mut txn = store.begin();
+        txn.insert(0, -1);
+        txn.remove(1);
+        assert_eq!(txn.get(0), Some(&-1));
+        assert_eq!(txn.get(1), None);
+        assert_eq!(store.commit(txn), Ok(2));
+
+        assert_eq!(reader.get(0), Some(&0));
+        assert_eq!(reader.get(1), Some(&1));
+        assert_eq!(store.as_of(1).unwrap().get(1), Some(&1));
+        assert_eq!(store.latest().get(0), Some(&-1));
+        assert_eq!(store.latest().get(1), None);
+        assert!(store.as_of(0).unwrap().iter().next().is_none());
+        assert!(store.as_of(3).is_none());
+        // Reading commits nothing.
+        assert_eq!(store.commit(reader), Ok(2));
+    }
+
+    #[test]
+    fn write_write_conflicts() {
+        let mut store = VersionedHamt::new();
+        let mut a = store.begin();
+        let mut b = store.begin();
+        let mut c = store.begin();
+        a.insert("x", 1);
+        b.insert("y", 2);
+        c.remove("x");
+        assert_eq!(store.commit(a), Ok(1));
+        // Disjoint writes commit on top of each other.
+        assert_eq!(store.commit(b), Ok(2));
+        assert_eq!(store.commit(c), Err(CommitError::Conflict { version: 1 }));
+        assert_eq!(store.latest().get("x"), Some(&1));
+        assert_eq!(store.latest().get("y"), Some(&2));
+
+        // A transaction that starts after the conflicting commit sees it.
+        let mut c = store.begin();
+        c.remove("x");
+        assert_eq!(store.commit(c), Ok(3));
+    }
+
+    #[test]
+    fn gc_frees_old_versions() {
+        let mut store = VersionedHamt::new();
+        let value = Rc::new(0);
+        let old = Rc::downgrade(&value);
+        let mut txn = store.begin();
+        txn.insert(0, value);
+        store.commit(txn).unwrap();
+        let stale = store.begin();
+        for version in 1..5 {
+            let mut txn = store.begin();
+            txn.insert(0, Rc::new(version));
+            txn.insert(version, Rc::new(version));
+            store.commit(txn).unwrap();
+        }
+        assert!(old.upgrade().is_some());
+
+        store.gc(3);
+        assert_eq!(store.oldest(), 3);
+        assert!(store.as_of(1).is_none());
+        assert_eq!(**store.as_of(3).unwrap().get(0).unwrap(), 2);
+        // The stale transaction still reads its version, and keeps it alive, but cannot commit.
+        assert_eq!(**stale.get(0).unwrap(), 0);
+        assert!(old.upgrade().is_some());
+        let mut stale = stale;
+        stale.insert(9, Rc::new(9));
+        assert_eq!(store.commit(stale), Err(CommitError::SnapshotTooOld { start: 1, oldest: 3 }));
+        assert!(old.upgrade().is_none());
+
+        store.gc(100);
+        assert_eq!(store.oldest(), 5);
+        assert_eq!(**store.latest().get(4).unwrap(), 4);
+    }
+}
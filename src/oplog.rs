@@ -0,0 +1,381 @@
+//! An operation log, to replicate a map from a leader to followers.
+//!
+//! A [`LoggedHAMT`] records every update as an [`Op`] numbered by a sequence number, starting at 1.
+//! Followers apply the entries of the log they have not seen yet, in order, to reach the same
+//! version of the map. A follower that is too far behind, whose entries were truncated from the log,
+//! bootstraps from a snapshot of the map at some sequence number and the tail of the log after it.
+//! Leader and follower check that they match by comparing [`Checkpoint`]s, which hold the root digest
+//! of the map.
+
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::io::{self, Read, Write};
+
+use crate::codec::{take, Codec, DecodeError};
+use crate::snapshot::{read_snapshot, write_snapshot, SnapshotError};
+use crate::{DefaultConfig, HamtConfig, Merkle, HAMT};
+
+/// An update of a map.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op<K, V> {
+    /// Insert a key and value.
+    Insert(K, V),
+    /// Remove a key.
+    Remove(K),
+}
+
+/// An update of a map with its sequence number in the log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry<K, V> {
+    pub seq: u64,
+    pub op: Op<K, V>,
+}
+
+/// The version of a replicated map: its sequence number, and the digest of its root.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub seq: u64,
+    pub digest: [u8; 32],
+}
+
+/// Why entries of a log could not be applied, or a replica does not match a checkpoint.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplayError {
+    /// An entry was missing: the sequence number that was expected, and the one found instead.
+    Gap { expected: u64, found: u64 },
+    /// The entries after `seq` were truncated from the log, which starts after `base`.
+    /// The follower must bootstrap from a snapshot.
+    Truncated { seq: u64, base: u64 },
+    /// The replica is at another sequence number than the checkpoint.
+    SeqMismatch { seq: u64, expected: u64 },
+    /// The replica is at the sequence number of the checkpoint, but its map is not the same.
+    Diverged { seq: u64 },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Gap { expected, found } => write!(f, "expected log entry {}, found {}", expected, found),
+            ReplayError::Truncated { seq, base } => {
+                write!(f, "log entries after {} were truncated, the log starts after {}", seq, base)
+            }
+            ReplayError::SeqMismatch { seq, expected } => write!(f, "replica is at {}, expected {}", seq, expected),
+            ReplayError::Diverged { seq } => write!(f, "replica diverged at {}", seq),
+        }
+    }
+}
+
+impl Error for ReplayError {}
+
+impl<K: Codec, V: Codec> Codec for Op<K, V> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Op::Insert(k, v) => {
+                out.push(0);
+                k.encode(out);
+                v.encode(out);
+            }
+            Op::Remove(k) => {
+                out.push(1);
+                k.encode(out);
+            }
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match take(input, 1)?[0] {
+            0 => Ok(Op::Insert(K::decode(input)?, V::decode(input)?)),
+            1 => Ok(Op::Remove(K::decode(input)?)),
+            _ => Err(DecodeError::Invalid("unknown operation tag")),
+        }
+    }
+}
+
+impl<K: Codec, V: Codec> Codec for LogEntry<K, V> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.seq.encode(out);
+        self.op.encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(LogEntry {
+            seq: u64::decode(input)?,
+            op: Op::decode(input)?,
+        })
+    }
+}
+
+impl<K, V> Op<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    /// Apply the update to `map`, and return the updated map.
+    pub fn apply<C: HamtConfig>(&self, map: &HAMT<K, V, C>) -> HAMT<K, V, C> {
+        match self {
+            Op::Insert(k, v) => map.insert(k.clone(), v.clone()),
+            Op::Remove(k) => map.remove(k.clone()),
+        }
+    }
+}
+
+/// A map that records its updates in a log, for followers to replay.
+pub struct LoggedHAMT<K, V, C: HamtConfig = DefaultConfig> {
+    map: HAMT<K, V, C>,
+    /// Sequence number of the last update.
+    seq: u64,
+    /// The updates after `base`, up to `seq`.
+    log: Vec<LogEntry<K, V>>,
+    base: u64,
+    merkle: Merkle<K, V, C>,
+}
+
+impl<K, V> LoggedHAMT<K, V> {
+    /// Construct an empty map, with an empty log.
+    pub fn new() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K, V, C: HamtConfig> LoggedHAMT<K, V, C> {
+    /// Construct an empty map with the trie shape `C`, with an empty log.
+    pub fn with_config() -> Self {
+        Self::from_map(HAMT::with_config(), 0)
+    }
+
+    /// Start logging the updates of `map`, the version at sequence number `seq`, e.g. read from a snapshot.
+    pub fn from_map(map: HAMT<K, V, C>, seq: u64) -> Self {
+        LoggedHAMT {
+            map,
+            seq,
+            log: Vec::new(),
+            base: seq,
+            merkle: Merkle::with_config(),
+        }
+    }
+
+    /// The current version of the map.
+    pub fn map(&self) -> &HAMT<K, V, C> {
+        &self.map
+    }
+
+    /// Sequence number of the last update, or of the snapshot the map started from.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The entries of the log after `seq`, for a follower at `seq` to apply.
+    pub fn since(&self, seq: u64) -> Result<&[LogEntry<K, V>], ReplayError> {
+        if seq < self.base {
+            return Err(ReplayError::Truncated { seq, base: self.base });
+        }
+        let start = (seq - self.base).min(self.log.len() as u64) as usize;
+        Ok(&self.log[start..])
+    }
+
+    /// Drop the entries of the log up to `seq`, once every follower has applied them or a snapshot
+    /// at `seq` or later was taken.
+    pub fn truncate(&mut self, seq: u64) {
+        let seq = seq.min(self.seq);
+        if seq > self.base {
+            self.log.drain(..(seq - self.base) as usize);
+            self.base = seq;
+        }
+    }
+}
+
+impl<K, V, C: HamtConfig> Default for LoggedHAMT<K, V, C> {
+    fn default() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K, V, C> LoggedHAMT<K, V, C>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    C: HamtConfig,
+{
+    /// Insert the given key and value, and return the sequence number of the update.
+    pub fn insert(&mut self, key: K, value: V) -> u64 {
+        self.record(Op::Insert(key, value))
+    }
+
+    /// Remove the given key, if it is present, and return the sequence number of the update.
+    pub fn remove(&mut self, key: K) -> u64 {
+        self.record(Op::Remove(key))
+    }
+
+    fn record(&mut self, op: Op<K, V>) -> u64 {
+        self.map = op.apply(&self.map);
+        self.seq += 1;
+        self.log.push(LogEntry { seq: self.seq, op });
+        self.seq
+    }
+
+    /// Apply the entries of the log of a leader, in order. Entries this map already applied are skipped,
+    /// and the applied entries are logged, so that this map can serve as a leader in turn.
+    ///
+    /// On a gap in the sequence numbers, the entries before it stay applied.
+    pub fn apply(&mut self, entries: &[LogEntry<K, V>]) -> Result<(), ReplayError> {
+        for entry in entries {
+            if entry.seq <= self.seq {
+                continue;
+            }
+            if entry.seq != self.seq + 1 {
+                return Err(ReplayError::Gap {
+                    expected: self.seq + 1,
+                    found: entry.seq,
+                });
+            }
+            self.record(entry.op.clone());
+        }
+        Ok(())
+    }
+}
+
+impl<K, V, C> LoggedHAMT<K, V, C>
+where
+    K: Codec,
+    V: Codec,
+    C: HamtConfig,
+{
+    /// Append the entries of the log after `seq` to `out`, to be decoded as a `Vec<LogEntry<K, V>>`.
+    pub fn encode_since(&self, seq: u64, out: &mut Vec<u8>) -> Result<(), ReplayError> {
+        let entries = self.since(seq)?;
+        entries.len().encode(out);
+        for entry in entries {
+            entry.encode(out);
+        }
+        Ok(())
+    }
+
+    /// The version of the map, for followers to verify that they reached it.
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        let digest = self.merkle.root_digest(&self.map);
+        // Only the digests of the current version are reused by the next checkpoint.
+        self.merkle.purge();
+        Checkpoint { seq: self.seq, digest }
+    }
+
+    /// Check that this map is at the version of `checkpoint`.
+    pub fn verify(&mut self, checkpoint: &Checkpoint) -> Result<(), ReplayError> {
+        let own = self.checkpoint();
+        if own.seq != checkpoint.seq {
+            Err(ReplayError::SeqMismatch {
+                seq: own.seq,
+                expected: checkpoint.seq,
+            })
+        } else if own.digest != checkpoint.digest {
+            Err(ReplayError::Diverged { seq: own.seq })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Write the sequence number and a snapshot of the map to `out`, for a follower to bootstrap from
+    /// with [`read_snapshot`](LoggedHAMT::read_snapshot).
+    pub fn write_snapshot<W: Write>(&self, mut out: W) -> io::Result<()> {
+        out.write_all(&self.seq.to_le_bytes())?;
+        write_snapshot(&[&self.map], out)
+    }
+
+    /// Read a map written by [`write_snapshot`](LoggedHAMT::write_snapshot), with an empty log starting
+    /// after its sequence number.
+    pub fn read_snapshot<R: Read>(mut input: R) -> Result<Self, SnapshotError> {
+        let mut seq = [0; 8];
+        input.read_exact(&mut seq)?;
+        let mut maps = read_snapshot(input)?;
+        if maps.len() != 1 {
+            return Err(DecodeError::Invalid("snapshot of a logged map holds one map").into());
+        }
+        Ok(Self::from_map(maps.remove(0), u64::from_le_bytes(seq)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogEntry, LoggedHAMT, Op, ReplayError};
+    use crate::codec::Codec;
+
+    fn ship(leader: &LoggedHAMT<u32, String>, follower: &mut LoggedHAMT<u32, String>) -> Result<(), ReplayError> {
+        let mut bytes = Vec::new();
+        leader.encode_since(follower.seq(), &mut bytes)?;
+        let entries = Vec::<LogEntry<u32, String>>::decode(&mut &bytes[..]).unwrap();
+        follower.apply(&entries)
+    }
+
+    #[test]
+    fn followers_replay_the_log() {
+        let mut leader = LoggedHAMT::new();
+        let mut follower = LoggedHAMT::new();
+        for k in 0..500 {
+            assert_eq!(leader.insert(k, k.to_string()), k as u64 + 1);
+        }
+        ship(&leader, &mut follower).unwrap();
+        for k in (0..500).step_by(2) {
+            leader.remove(k);
+        }
+        leader.insert(1, "one".to_string());
+        ship(&leader, &mut follower).unwrap();
+        // Shipping again applies nothing.
+        ship(&leader, &mut follower).unwrap();
+
+        assert_eq!(follower.seq(), 751);
+        assert_eq!(follower.map(), leader.map());
+        let checkpoint = leader.checkpoint();
+        assert_eq!(follower.verify(&checkpoint), Ok(()));
+        assert_eq!(follower.since(0).unwrap(), leader.since(0).unwrap());
+        assert_eq!(leader.since(750).unwrap(), &[LogEntry { seq: 751, op: Op::Insert(1, "one".to_string()) }]);
+    }
+
+    #[test]
+    fn bootstrap_from_snapshot_and_tail() {
+        let mut leader = LoggedHAMT::new();
+        for k in 0..300 {
+            leader.insert(k, k.to_string());
+        }
+        let mut snapshot = Vec::new();
+        leader.write_snapshot(&mut snapshot).unwrap();
+        leader.truncate(leader.seq());
+        for k in 0..100 {
+            leader.remove(k * 3);
+        }
+        leader.truncate(350);
+        assert_eq!(leader.since(300), Err(ReplayError::Truncated { seq: 300, base: 350 }));
+
+        // The snapshot is too old for the log, so a newer one is taken.
+        let mut stale = LoggedHAMT::read_snapshot(&snapshot[..]).unwrap();
+        assert_eq!(ship(&leader, &mut stale), Err(ReplayError::Truncated { seq: 300, base: 350 }));
+        let mut snapshot = Vec::new();
+        leader.write_snapshot(&mut snapshot).unwrap();
+        for k in 0..50 {
+            leader.insert(k, "again".to_string());
+        }
+        let mut follower = LoggedHAMT::read_snapshot(&snapshot[..]).unwrap();
+        assert_eq!(follower.seq(), 400);
+        ship(&leader, &mut follower).unwrap();
+        assert_eq!(follower.verify(&leader.checkpoint()), Ok(()));
+        assert_eq!(follower.map().get(0), Some(&"again".to_string()));
+    }
+
+    #[test]
+    fn mismatches_are_detected() {
+        let mut leader = LoggedHAMT::new();
+        let mut follower = LoggedHAMT::new();
+        for k in 0..10 {
+            leader.insert(k, k.to_string());
+        }
+        let entries = leader.since(0).unwrap().to_vec();
+        assert_eq!(follower.apply(&entries[..3]), Ok(()));
+        assert_eq!(follower.apply(&entries[5..]), Err(ReplayError::Gap { expected: 4, found: 6 }));
+        assert_eq!(follower.verify(&leader.checkpoint()), Err(ReplayError::SeqMismatch { seq: 3, expected: 10 }));
+
+        // A follower that was updated on its own diverges.
+        follower.apply(&entries[3..]).unwrap();
+        follower.insert(0, "x".to_string());
+        leader.insert(0, "y".to_string());
+        assert_eq!(follower.verify(&leader.checkpoint()), Err(ReplayError::Diverged { seq: 11 }));
+    }
+}
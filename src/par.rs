@@ -0,0 +1,503 @@
+//! Parallel bulk operations on maps whose nodes are shared through `Arc`.
+//!
+//! The fan-out of the trie partitions a map naturally: the entries of the root (or, one level
+//! down, the entries of the nodes it points to) store disjoint sets of keys, and can be processed
+//! independently. Each operation splits these entries between threads, and assembles the results
+//! into the top levels of the new trie.
+//!
+//! Work runs on scoped threads (`std::thread::scope`), one per available core. With the `rayon`
+//! feature, it runs on the rayon thread pool instead.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::iter::Iter;
+use crate::{
+    build_entry, for_each_in_entry, fragment, get_entries_index, hash_key, push_fragment, union_entries, ArcPointer,
+    Bitmap, HAMTNode, HAMTNodeEntry, HamtConfig, HashWord, NodePtr, HAMT,
+};
+
+/// Number of threads to split work between.
+#[cfg(not(feature = "rayon"))]
+fn threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+#[cfg(feature = "rayon")]
+fn threads() -> usize {
+    rayon::current_num_threads()
+}
+
+/// Apply `f` to every unit in parallel, keeping the results in order.
+#[cfg(not(feature = "rayon"))]
+fn par_map<U: Send, T: Send, F: Fn(U) -> T + Sync>(mut units: Vec<U>, f: F) -> Vec<T> {
+    let chunk_len = units.len().div_ceil(threads()).max(1);
+    if units.len() <= chunk_len {
+        return units.into_iter().map(f).collect();
+    }
+    let mut chunks = Vec::new();
+    while units.len() > chunk_len {
+        chunks.push(units.split_off(units.len() - chunk_len));
+    }
+    chunks.push(units);
+    chunks.reverse();
+    let f = &f;
+    std::thread::scope(|s| {
+        let handles: Vec<_> = chunks
+            .into_iter()
+            .map(|chunk| s.spawn(move || chunk.into_iter().map(f).collect::<Vec<T>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+#[cfg(feature = "rayon")]
+fn par_map<U: Send, T: Send, F: Fn(U) -> T + Sync>(units: Vec<U>, f: F) -> Vec<T> {
+    use rayon::prelude::*;
+    units.into_par_iter().map(&f).collect()
+}
+
+/// The units of work of a map: the entries of the root, with each `Node` entry replaced by the
+/// entries of the node it points to.
+fn units<K, V, C: HamtConfig>(root: &HAMTNode<K, V, C>) -> Vec<&HAMTNodeEntry<K, V, C>> {
+    let mut units = Vec::new();
+    for entry in root.entries.iter() {
+        match entry {
+            HAMTNodeEntry::Node(node) => units.extend(node.entries.iter()),
+            _ => units.push(entry),
+        }
+    }
+    units
+}
+
+/// The hash bits of the path to each unit, with the level of the unit, in the order of `units`.
+fn unit_paths<K, V, C: HamtConfig>(root: &HAMTNode<K, V, C>) -> Vec<(u128, u32)> {
+    let mut paths = Vec::new();
+    for (frag, entry) in fragments::<C>(root.presence_map).zip(root.entries.iter()) {
+        let path = push_fragment::<C>(0, frag, 0);
+        match entry {
+            HAMTNodeEntry::Node(node) => {
+                paths.extend(fragments::<C>(node.presence_map).map(|frag| (push_fragment::<C>(path, frag, 1), 1)))
+            }
+            _ => paths.push((path, 0)),
+        }
+    }
+    paths
+}
+
+/// The fragments present in `presence_map`, in order.
+fn fragments<C: HamtConfig>(presence_map: C::Bitmap) -> impl Iterator<Item = u32> {
+    let mut present = presence_map.to_u64();
+    std::iter::from_fn(move || {
+        (present != 0).then(|| {
+            let frag = present.trailing_zeros();
+            present &= present - 1;
+            frag
+        })
+    })
+}
+
+/// Build a node from the entries for the fragments present in `presence_map`, in order,
+/// leaving out the missing ones.
+fn assemble<K, V, C, I>(presence_map: C::Bitmap, entries: I) -> HAMTNode<K, V, C>
+where
+    C: HamtConfig,
+    I: Iterator<Item = Option<HAMTNodeEntry<K, V, C>>>,
+{
+    let mut present = presence_map.to_u64();
+    let mut node: HAMTNode<K, V, C> = HAMTNode {
+        presence_map: C::Bitmap::EMPTY,
+        entries: Vec::new(),
+    };
+    for entry in entries {
+        let frag = present.trailing_zeros();
+        present &= present - 1;
+        if let Some(entry) = entry {
+            node.presence_map = node.presence_map.with(frag);
+            node.entries.push(entry);
+        }
+    }
+    node
+}
+
+/// Apply `f` to the units of the map in parallel, and assemble the top two levels of the new
+/// trie from the results. `None` results are left out.
+fn par_rebuild<K, V, W, C, F>(root: &HAMTNode<K, V, C>, f: F) -> HAMTNode<K, W, C>
+where
+    K: Send + Sync,
+    V: Send + Sync,
+    W: Send + Sync,
+    C: HamtConfig<Pointer = ArcPointer>,
+    F: Fn(&HAMTNodeEntry<K, V, C>) -> Option<HAMTNodeEntry<K, W, C>> + Sync,
+{
+    let mut results = par_map(units(root), f).into_iter();
+    let entries = root.entries.iter().map(|entry| match entry {
+        HAMTNodeEntry::Node(node) => {
+            let node = assemble(node.presence_map, results.by_ref().take(node.entries.len()));
+            (node.presence_map != C::Bitmap::EMPTY).then(|| HAMTNodeEntry::Node(NodePtr::new(node)))
+        }
+        _ => results.next().unwrap(),
+    });
+    assemble(root.presence_map, entries)
+}
+
+/// Apply `f` to every value of the entry.
+fn map_entry<K: Clone, V, W, C: HamtConfig, F: Fn(&K, &V) -> W>(
+    entry: &HAMTNodeEntry<K, V, C>,
+    f: &F,
+) -> HAMTNodeEntry<K, W, C> {
+    match entry {
+        HAMTNodeEntry::Value(k, v, hash) => HAMTNodeEntry::Value(k.clone(), f(k, v), *hash),
+        HAMTNodeEntry::Chained(vec) => HAMTNodeEntry::Chained(vec.iter().map(|(k, v)| (k.clone(), f(k, v))).collect()),
+        HAMTNodeEntry::Node(node) => HAMTNodeEntry::Node(NodePtr::new(HAMTNode {
+            presence_map: node.presence_map,
+            entries: node.entries.iter().map(|entry| map_entry(entry, f)).collect(),
+        })),
+    }
+}
+
+/// The outcome of filtering an entry.
+enum Filtered<K, V, C: HamtConfig> {
+    /// Everything was kept, so the entry can be shared.
+    Kept,
+    Removed,
+    Changed(HAMTNodeEntry<K, V, C>),
+}
+
+impl<K: Clone, V: Clone, C: HamtConfig> Filtered<K, V, C> {
+    /// The entry that replaces `entry`, given that this is the outcome of filtering it.
+    fn apply(self, entry: &HAMTNodeEntry<K, V, C>) -> Option<HAMTNodeEntry<K, V, C>> {
+        match self {
+            Filtered::Kept => Some(entry.clone()),
+            Filtered::Removed => None,
+            Filtered::Changed(entry) => Some(entry),
+        }
+    }
+}
+
+/// Keep the keys and values of the entry that `f` accepts. `path` holds the hash bits of the path
+/// to the entry, which is at `level`.
+fn filter_entry<K: Clone, V: Clone, C: HamtConfig, F: Fn(&K, &V) -> bool>(
+    entry: &HAMTNodeEntry<K, V, C>,
+    (path, level): (u128, u32),
+    f: &F,
+) -> Filtered<K, V, C> {
+    match entry {
+        HAMTNodeEntry::Value(k, v, _) => {
+            if f(k, v) {
+                Filtered::Kept
+            } else {
+                Filtered::Removed
+            }
+        }
+        HAMTNodeEntry::Chained(vec) => {
+            let kept: Vec<(K, V)> = vec.iter().filter(|(k, v)| f(k, v)).cloned().collect();
+            if kept.len() == vec.len() {
+                Filtered::Kept
+            } else if kept.is_empty() {
+                Filtered::Removed
+            } else if kept.len() == 1 {
+                // A single key becomes a value again, with the hash of the chain's path.
+                let (k, v) = kept.into_iter().next().unwrap();
+                Filtered::Changed(HAMTNodeEntry::Value(k, v, C::Hash::from_u128(path).unwrap()))
+            } else {
+                Filtered::Changed(HAMTNodeEntry::Chained(kept))
+            }
+        }
+        HAMTNodeEntry::Node(node) => {
+            let filtered: Vec<Filtered<K, V, C>> = fragments::<C>(node.presence_map)
+                .zip(node.entries.iter())
+                .map(|(frag, entry)| filter_entry(entry, (push_fragment::<C>(path, frag, level + 1), level + 1), f))
+                .collect();
+            if filtered.iter().all(|entry| matches!(entry, Filtered::Kept)) {
+                return Filtered::Kept;
+            }
+            let entries = node.entries.iter().zip(filtered).map(|(entry, filtered)| filtered.apply(entry));
+            let node = assemble(node.presence_map, entries);
+            if node.presence_map == C::Bitmap::EMPTY {
+                Filtered::Removed
+            } else {
+                Filtered::Changed(HAMTNodeEntry::Node(NodePtr::new(node)))
+            }
+        }
+    }
+}
+
+/// Iterate over a map in parallel, see [`HAMT::par_iter`](HAMT::par_iter).
+pub struct ParIter<'a, K, V, C: HamtConfig> {
+    units: Vec<&'a HAMTNodeEntry<K, V, C>>,
+}
+
+impl<'a, K, V, C> ParIter<'a, K, V, C>
+where
+    K: Send + Sync,
+    V: Send + Sync,
+    C: HamtConfig<Pointer = ArcPointer>,
+{
+    /// Split the map into one sequential iterator per thread, covering disjoint parts of the map.
+    pub fn into_parts(self) -> Vec<Iter<'a, K, V, C>> {
+        let chunk_len = self.units.len().div_ceil(threads()).max(1);
+        self.units.chunks(chunk_len).map(Iter::over).collect()
+    }
+
+    /// Call `f` on every key and value, from several threads.
+    pub fn for_each<F: Fn(&'a K, &'a V) + Sync>(self, f: F) {
+        par_map(self.units, |entry| for_each_in_entry(entry, &mut |k, v| f(k, v)));
+    }
+
+    /// Collect `f` of every key and value, in the order of [`HAMT::iter`](HAMT::iter).
+    pub fn map<T: Send, F: Fn(&'a K, &'a V) -> T + Sync>(self, f: F) -> Vec<T> {
+        let parts = par_map(self.units, |entry| {
+            let mut part = Vec::new();
+            for_each_in_entry(entry, &mut |k, v| part.push(f(k, v)));
+            part
+        });
+        parts.into_iter().flatten().collect()
+    }
+
+    /// Fold the keys and values of each part of the map, starting from `identity()`, then combine
+    /// the results of the parts with `reduce`.
+    pub fn fold<T, I, F, R>(self, identity: I, fold: F, reduce: R) -> T
+    where
+        T: Send,
+        I: Fn() -> T + Sync,
+        F: Fn(T, &'a K, &'a V) -> T + Sync,
+        R: Fn(T, T) -> T,
+    {
+        let parts = par_map(self.units, |entry| {
+            let mut acc = Some(identity());
+            for_each_in_entry(entry, &mut |k, v| acc = Some(fold(acc.take().unwrap(), k, v)));
+            acc.unwrap()
+        });
+        parts.into_iter().fold(identity(), reduce)
+    }
+}
+
+impl<K, V, C> HAMT<K, V, C>
+where
+    K: Send + Sync,
+    V: Send + Sync,
+    C: HamtConfig<Pointer = ArcPointer>,
+{
+    /// Iterate over the map from several threads.
+    pub fn par_iter(&self) -> ParIter<'_, K, V, C> {
+        ParIter {
+            units: units(&self.root),
+        }
+    }
+
+    /// Call `f` on every key and value, from several threads.
+    pub fn par_for_each<F: Fn(&K, &V) + Sync>(&self, f: F) {
+        self.par_iter().for_each(f)
+    }
+
+    /// Return a map with the same keys, and values computed by `f`, which is called from several threads.
+    /// The new map has the same shape as this one.
+    pub fn par_map_values<W, F>(&self, f: F) -> HAMT<K, W, C>
+    where
+        K: Clone,
+        W: Send + Sync,
+        F: Fn(&K, &V) -> W + Sync,
+    {
+        let root = par_rebuild(&self.root, |entry| Some(map_entry(entry, &f)));
+        HAMT { root: NodePtr::new(root) }
+    }
+
+    /// Return a map with the keys and values that `f` accepts, calling `f` from several threads.
+    /// Subtrees in which every key is kept are shared with this map.
+    pub fn par_filter<F>(&self, f: F) -> Self
+    where
+        K: Clone,
+        V: Clone,
+        F: Fn(&K, &V) -> bool + Sync,
+    {
+        let units: Vec<_> = units(&self.root).into_iter().zip(unit_paths(&self.root)).collect();
+        let filtered = par_map(units, |(entry, path)| filter_entry(entry, path, &f));
+        if filtered.iter().all(|entry| matches!(entry, Filtered::Kept)) {
+            return self.clone();
+        }
+        // Assemble the top two levels like `par_rebuild`, sharing the nodes in which every key is kept.
+        let mut results = filtered.into_iter();
+        let entries = self.root.entries.iter().map(|entry| match entry {
+            HAMTNodeEntry::Node(node) => {
+                let filtered: Vec<_> = results.by_ref().take(node.entries.len()).collect();
+                if filtered.iter().all(|entry| matches!(entry, Filtered::Kept)) {
+                    return Some(entry.clone());
+                }
+                let entries = node.entries.iter().zip(filtered).map(|(entry, filtered)| filtered.apply(entry));
+                let node = assemble(node.presence_map, entries);
+                (node.presence_map != C::Bitmap::EMPTY).then(|| HAMTNodeEntry::Node(NodePtr::new(node)))
+            }
+            _ => results.next().unwrap().apply(entry),
+        });
+        HAMT {
+            root: NodePtr::new(assemble(self.root.presence_map, entries)),
+        }
+    }
+}
+
+impl<K, V, C> HAMT<K, V, C>
+where
+    K: Hash + Eq + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+    C: HamtConfig<Pointer = ArcPointer>,
+{
+    /// Build a map from the given pairs, hashing them and building the subtrees of the root from
+    /// several threads. If a key appears several times, its last value is kept.
+    pub fn par_from_iter<I: IntoIterator<Item = (K, V)>>(items: I) -> Self {
+        let items: Vec<(K, V)> = items.into_iter().collect();
+        let chunk_len = items.len().div_ceil(threads()).max(1);
+        let hashes: Vec<C::Hash> = par_map(items.chunks(chunk_len).collect(), |chunk| {
+            chunk.iter().map(|(k, _)| hash_key::<C, K>(k)).collect::<Vec<_>>()
+        })
+        .into_iter()
+        .flatten()
+        .collect();
+
+        let mut buckets: Vec<Vec<(C::Hash, K, V)>> = (0..1usize << C::BITS).map(|_| Vec::new()).collect();
+        for (hash, (k, v)) in hashes.into_iter().zip(items) {
+            buckets[fragment::<C>(hash) as usize].push((hash, k, v));
+        }
+        let presence_map = buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| !bucket.is_empty())
+            .fold(C::Bitmap::EMPTY, |map, (frag, _)| map.with(frag as u32));
+        let buckets: Vec<_> = buckets.into_iter().filter(|bucket| !bucket.is_empty()).collect();
+        let entries = par_map(buckets, |bucket| {
+            // Keep the last value of every key.
+            let last: HashMap<&K, usize> = bucket.iter().enumerate().map(|(i, (_, k, _))| (k, i)).collect();
+            let keep: Vec<bool> = (0..bucket.len()).map(|i| last[&bucket[i].1] == i).collect();
+            let bucket = bucket.into_iter().zip(keep).filter(|(_, keep)| *keep).map(|(item, _)| item).collect();
+            Some(build_entry(bucket, 1))
+        });
+        HAMT {
+            root: NodePtr::new(assemble(presence_map, entries.into_iter())),
+        }
+    }
+
+    /// Return a map with the keys of both maps, merging the subtrees of the root from several threads.
+    /// For keys in both maps, the value of `self` is kept. Subtrees the maps share are not visited.
+    pub fn par_union(&self, other: &Self) -> Self {
+        let (a, b) = (&self.root, &other.root);
+        if NodePtr::ptr_eq(a, b) {
+            return self.clone();
+        }
+        let present = a.presence_map.to_u64() | b.presence_map.to_u64();
+        let pairs: Vec<_> = (0..64)
+            .filter(|frag| present & (1 << frag) != 0)
+            .map(|frag| {
+                let a_entry = a.presence_map.contains(frag).then(|| &a.entries[get_entries_index(a.presence_map, frag)]);
+                let b_entry = b.presence_map.contains(frag).then(|| &b.entries[get_entries_index(b.presence_map, frag)]);
+                (a_entry, b_entry)
+            })
+            .collect();
+        let entries = par_map(pairs, |pair| {
+            Some(match pair {
+                (Some(a), Some(b)) => union_entries(a, b, 1),
+                (Some(entry), None) | (None, Some(entry)) => entry.clone(),
+                (None, None) => unreachable!(),
+            })
+        });
+        let presence_map = C::Bitmap::from_u64(present).unwrap();
+        HAMT {
+            root: NodePtr::new(assemble(presence_map, entries.into_iter())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{build_with, Colliding};
+    use crate::{ArcPointer, Config, HAMTNodeEntry, NodePtr, SyncConfig, SyncHAMT, HAMT};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    #[test]
+    fn par_iteration() {
+        let map = build_with::<SyncConfig>(0..20000);
+        let sum = AtomicI64::new(0);
+        map.par_for_each(|_, v| {
+            sum.fetch_add(*v as i64, Ordering::Relaxed);
+        });
+        assert_eq!(sum.into_inner(), 19999 * 20000 / 2);
+
+        let sequential: Vec<i32> = map.iter().map(|(k, _)| *k).collect();
+        assert_eq!(map.par_iter().map(|k, _| *k), sequential);
+        let parts: Vec<i32> = map.par_iter().into_parts().into_iter().flatten().map(|(k, _)| *k).collect();
+        assert_eq!(parts, sequential);
+        assert_eq!(map.par_iter().fold(|| 0, |n, _, _| n + 1, |a, b| a + b), 20000);
+        assert_eq!(SyncHAMT::<i32, i32>::with_config().par_iter().map(|k, _| *k), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn par_map_and_filter() {
+        let map = build_with::<SyncConfig>(0..20000);
+        let doubled = map.par_map_values(|k, v| (k + v) as i64);
+        let evens = map.par_filter(|k, _| k % 2 == 0);
+        let small = map.par_filter(|k, _| *k < 10);
+        for k in 0..20000 {
+            assert_eq!(doubled.get(k), Some(&(2 * k as i64)));
+            assert_eq!(evens.get(k), if k % 2 == 0 { Some(&k) } else { None });
+            assert_eq!(small.contains_key(k), k < 10);
+        }
+        assert!(NodePtr::ptr_eq(&map.par_filter(|_, _| true).root, &map.root));
+        // Filtering out one key shares the nodes off its path.
+        let one_less = map.par_filter(|k, _| *k != 0);
+        let shared = one_less.root.entries.iter().zip(map.root.entries.iter()).filter(|entries| {
+            matches!(entries, (HAMTNodeEntry::Node(x), HAMTNodeEntry::Node(y)) if NodePtr::ptr_eq(x, y))
+        });
+        assert_eq!(shared.count(), map.root.entries.len() - 1);
+        assert_eq!(one_less, map.remove(0));
+        assert_eq!(map.par_filter(|_, _| false), SyncHAMT::with_config());
+    }
+
+    #[test]
+    fn par_from_iter_and_union() {
+        let items: Vec<(i32, i32)> = (0..20000).chain(0..100).map(|k| (k, k)).collect();
+        let mut expected = build_with::<SyncConfig>(0..20000);
+        assert_eq!(SyncHAMT::par_from_iter(items), expected);
+        assert_eq!(SyncHAMT::<i32, i32>::par_from_iter(vec![]), SyncHAMT::with_config());
+
+        let a = build_with::<SyncConfig>(0..15000);
+        let b = build_with::<SyncConfig>(5000..20000).insert(0, -1).insert(19999, -1);
+        expected = expected.insert(19999, -1);
+        assert_eq!(a.par_union(&b), expected);
+        assert_eq!(a.par_union(&a), a);
+        // A derived version shares most of its subtrees with the original.
+        assert_eq!(a.par_union(&a.insert(20000, 0).remove(3)), a.insert(20000, 0));
+    }
+
+    #[test]
+    fn par_chains() {
+        type Map = HAMT<Colliding, i32, Config<5, u64, ArcPointer>>;
+        let key = |id| Colliding { bucket: (id % 4) as u8, id };
+        let map = Map::par_from_iter((0..40).map(|id| (key(id), id)));
+        let sequential = (0..40).fold(Map::with_config(), |map, id| map.insert(key(id), id));
+        assert_eq!(map, sequential);
+        assert_eq!(map.height(), sequential.height());
+
+        let other = (40..50).fold(Map::with_config(), |map, id| map.insert(key(id), id)).insert(key(0), -1);
+        let union = map.par_union(&other);
+        let items: HashMap<i32, i32> = union.iter().map(|(k, v)| (k.id, *v)).collect();
+        assert_eq!(items, (0..50).map(|id| (id, id)).collect());
+        assert_eq!(map.par_filter(|k, _| k.id >= 20).par_map_values(|_, v| v * 2).get(key(25)), Some(&50));
+    }
+
+    #[test]
+    fn one_key_chains() {
+        // Chains left with one key by a removal or a filter keep the hash the keys were inserted with.
+        let hash = 0x1234_5678_9abc_def0;
+        let build = || SyncHAMT::with_config().insert_hashed(hash, "a", 1).insert_hashed(hash, "b", 2);
+        let removed = build().remove_hashed(hash, |k| *k == "b");
+        let filtered = build().par_filter(|k, _| *k == "a");
+        assert_eq!(removed, filtered);
+        for map in [removed.par_union(&build().remove_hashed(hash, |k| *k == "b")), filtered.par_union(&filtered)] {
+            assert_eq!(map.range_by_hash(hash..hash + 1).count(), 1);
+            assert_eq!(map.get_hashed(hash, |k| *k == "a"), Some((&"a", &1)));
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! Recycling of node storage.
+//!
+//! Every `insert` and `remove` copies the entries of each node on the path to the key, and the
+//! old copies are freed as soon as the previous version of the map is dropped. For workloads that
+//! churn through versions quickly, a [`NodePool`] keeps the freed entries buffers of the current
+//! thread in free-lists, one per size class, and hands them out again for new nodes instead of
+//! going through the global allocator.
+//!
+//! A size class is the element layout together with the buffer's capacity, and new nodes
+//! always ask for exactly as many entries as they store, so buffers are reused only by nodes
+//! with the same number of entries. The `Rc` allocation holding each node is not pooled.
+
+use std::alloc::{dealloc, Layout};
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::ptr::NonNull;
+
+/// Counters describing how much a [`NodePool`](NodePool) helped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Buffers handed out from a free-list, each saving an allocation.
+    pub reused: u64,
+    /// Buffers that had to be allocated because their free-list was empty.
+    pub allocated: u64,
+    /// Freed buffers that were put on a free-list, each saving a deallocation.
+    pub recycled: u64,
+    /// Freed buffers that were deallocated because their free-list was full.
+    pub discarded: u64,
+    /// Buffers currently on the free-lists.
+    pub free_buffers: u64,
+    /// Bytes held by the buffers currently on the free-lists.
+    pub free_bytes: u64,
+}
+
+/// The free-lists for buffers of one element layout, indexed by capacity.
+struct LayoutClass {
+    size: usize,
+    align: usize,
+    free: Vec<Vec<NonNull<u8>>>,
+}
+
+struct Pool {
+    max_per_class: usize,
+    // There are only a few element layouts in use at once (one per map type), so these are scanned.
+    layouts: Vec<LayoutClass>,
+    stats: PoolStats,
+}
+
+impl Pool {
+    /// The free-list for buffers of `T` with the given capacity.
+    fn free_list<T>(&mut self, capacity: usize) -> &mut Vec<NonNull<u8>> {
+        let (size, align) = (mem::size_of::<T>(), mem::align_of::<T>());
+        let position = match self.layouts.iter().position(|class| class.size == size && class.align == align) {
+            Some(position) => position,
+            None => {
+                self.layouts.push(LayoutClass {
+                    size,
+                    align,
+                    free: Vec::new(),
+                });
+                self.layouts.len() - 1
+            }
+        };
+        let free = &mut self.layouts[position].free;
+        if free.len() <= capacity {
+            free.resize_with(capacity + 1, Vec::new);
+        }
+        &mut free[capacity]
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        for class in self.layouts.drain(..) {
+            for (capacity, buffers) in class.free.into_iter().enumerate() {
+                // This is the layout `Vec` allocated the buffer with.
+                let layout = Layout::from_size_align(class.size * capacity, class.align).unwrap();
+                for buffer in buffers {
+                    unsafe { dealloc(buffer.as_ptr(), layout) };
+                }
+            }
+        }
+    }
+}
+
+thread_local! {
+    static POOL: RefCell<Option<Pool>> = const { RefCell::new(None) };
+    // Whether `POOL` holds a pool, checked first so that nodes created and freed without a pool
+    // installed do not borrow it.
+    static INSTALLED: Cell<bool> = const { Cell::new(false) };
+}
+
+fn installed() -> bool {
+    INSTALLED.with(Cell::get)
+}
+
+/// Get an empty vector with a capacity of exactly `capacity`, reusing a pooled buffer if possible.
+pub(crate) fn alloc_vec<T>(capacity: usize) -> Vec<T> {
+    if capacity == 0 || mem::size_of::<T>() == 0 || !installed() {
+        return Vec::with_capacity(capacity);
+    }
+    let reused = POOL
+        .try_with(|pool| {
+            let mut pool = pool.borrow_mut();
+            let pool = pool.as_mut()?;
+            match pool.free_list::<T>(capacity).pop() {
+                Some(buffer) => {
+                    pool.stats.reused += 1;
+                    pool.stats.free_buffers -= 1;
+                    pool.stats.free_bytes -= (mem::size_of::<T>() * capacity) as u64;
+                    Some(buffer)
+                }
+                None => {
+                    pool.stats.allocated += 1;
+                    None
+                }
+            }
+        })
+        .ok()
+        .flatten();
+    match reused {
+        // The buffer was allocated by a `Vec` of elements with the same size and alignment,
+        // and the same capacity, and it holds no elements.
+        Some(buffer) => unsafe { Vec::from_raw_parts(buffer.as_ptr() as *mut T, 0, capacity) },
+        None => Vec::with_capacity(capacity),
+    }
+}
+
+/// Drop the elements of the vector, and put its buffer on a free-list if a pool is installed
+/// and the free-list has room.
+pub(crate) fn release_vec<T>(mut vec: Vec<T>) {
+    // Drop the elements first: they may release buffers of their own.
+    vec.clear();
+    let capacity = vec.capacity();
+    if capacity == 0 || mem::size_of::<T>() == 0 || !installed() {
+        return;
+    }
+    let mut vec = ManuallyDrop::new(vec);
+    let kept = POOL
+        .try_with(|pool| {
+            let mut pool = pool.borrow_mut();
+            let pool = match pool.as_mut() {
+                Some(pool) => pool,
+                None => return false,
+            };
+            let max_per_class = pool.max_per_class;
+            let free = pool.free_list::<T>(capacity);
+            if free.len() < max_per_class {
+                free.push(NonNull::new(vec.as_mut_ptr() as *mut u8).unwrap());
+                pool.stats.recycled += 1;
+                pool.stats.free_buffers += 1;
+                pool.stats.free_bytes += (mem::size_of::<T>() * capacity) as u64;
+                true
+            } else {
+                pool.stats.discarded += 1;
+                false
+            }
+        })
+        .unwrap_or(false);
+    if !kept {
+        unsafe { ManuallyDrop::drop(&mut vec) };
+    }
+}
+
+/// Copy the entries into a new vector, leaving room for `extra` more.
+pub(crate) fn copy_entries<T: Clone>(entries: &[T], extra: usize) -> Vec<T> {
+    let mut vec = alloc_vec(entries.len() + extra);
+    vec.extend_from_slice(entries);
+    vec
+}
+
+/// Copy the entries into a new vector, leaving out the one at `index`.
+pub(crate) fn copy_entries_without<T: Clone>(entries: &[T], index: usize) -> Vec<T> {
+    let mut vec = alloc_vec(entries.len() - 1);
+    vec.extend_from_slice(&entries[..index]);
+    vec.extend_from_slice(&entries[index + 1..]);
+    vec
+}
+
+/// A per-thread pool of node storage.
+///
+/// While the guard returned by [`install`](NodePool::install) is alive, nodes of every map that are
+/// freed on the current thread give their entries buffer to the pool, and nodes created on the
+/// current thread take their buffer from it. Dropping the guard frees the pooled buffers and stops pooling.
+pub struct NodePool {
+    /// Whether this guard installed the pool, rather than finding one already installed.
+    owner: bool,
+    // The pool is per-thread, so the guard must stay on its thread.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl NodePool {
+    /// Start pooling on the current thread, keeping at most `max_per_class` free buffers per size class.
+    /// If a pool is already installed on the thread, it is kept as is, and dropping the
+    /// returned guard does not uninstall it.
+    pub fn install(max_per_class: usize) -> NodePool {
+        let owner = POOL.with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.is_some() {
+                false
+            } else {
+                *pool = Some(Pool {
+                    max_per_class,
+                    layouts: Vec::new(),
+                    stats: PoolStats::default(),
+                });
+                true
+            }
+        });
+        INSTALLED.with(|installed| installed.set(true));
+        NodePool {
+            owner,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Counters for the pool installed on the current thread.
+    pub fn stats(&self) -> PoolStats {
+        POOL.with(|pool| pool.borrow().as_ref().map(|pool| pool.stats).unwrap_or_default())
+    }
+
+    /// Free all the buffers currently on the free-lists, keeping the pool installed.
+    pub fn trim(&self) {
+        let layouts = POOL.with(|pool| {
+            let mut pool = pool.borrow_mut();
+            pool.as_mut().map(|pool| {
+                pool.stats.free_buffers = 0;
+                pool.stats.free_bytes = 0;
+                mem::take(&mut pool.layouts)
+            })
+        });
+        if let Some(layouts) = layouts {
+            // Deallocate through a temporary pool, outside of the borrow.
+            drop(Pool {
+                max_per_class: 0,
+                layouts,
+                stats: PoolStats::default(),
+            });
+        }
+    }
+}
+
+impl Drop for NodePool {
+    fn drop(&mut self) {
+        if self.owner {
+            INSTALLED.with(|installed| installed.set(false));
+            let pool = POOL.with(|pool| pool.borrow_mut().take());
+            drop(pool);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NodePool;
+    use crate::HAMT;
+
+    fn churn(rounds: i32) -> HAMT<i32, i32> {
+        let mut map = HAMT::new();
+        for k in 0..rounds {
+            map = map.insert(k % 500, k);
+        }
+        map
+    }
+
+    #[test]
+    fn pool_reuses_buffers() {
+        let pool = NodePool::install(64);
+        let map = churn(5000);
+        let stats = pool.stats();
+        assert!(stats.reused > 0);
+        assert!(stats.recycled > 0);
+        // Every buffer on a free-list was recycled and not reused yet.
+        assert_eq!(stats.free_buffers, stats.recycled - stats.reused);
+        for k in 0..500 {
+            assert_eq!(map.get(k), Some(&(4500 + k)));
+        }
+        drop(map);
+        assert!(pool.stats().free_buffers > 0);
+        pool.trim();
+        assert_eq!(pool.stats().free_buffers, 0);
+        assert_eq!(pool.stats().free_bytes, 0);
+    }
+
+    #[test]
+    fn nested_install_keeps_outer_pool() {
+        let outer = NodePool::install(8);
+        {
+            let inner = NodePool::install(8);
+            churn(100);
+            assert!(inner.stats().recycled > 0);
+        }
+        assert!(outer.stats().recycled > 0);
+        drop(outer);
+        assert_eq!(NodePool::install(8).stats().recycled, 0);
+    }
+
+    #[test]
+    fn without_pool() {
+        // Nodes created and freed without a pool, or after it was dropped, are unaffected.
+        let map = {
+            let _pool = NodePool::install(4);
+            churn(1000)
+        };
+        let map = map.insert(1000, 0).remove(0);
+        assert_eq!(map.get(1), Some(&501));
+        assert!(!map.contains_key(0));
+    }
+}
@@ -0,0 +1,458 @@
+//! A repository of versions of a map, with commits, branches and tags, in the manner of git.
+//!
+//! Each commit holds a map, which shares every node its changes did not copy with the map of its
+//! parent, so the repository takes memory in proportion to the changes committed. Diffs between
+//! commits skip the shared nodes, and merges apply the changes made on one branch since the common
+//! ancestor of both branches to the other.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+
+use crate::{Change, DefaultConfig, HamtConfig, HAMT};
+
+/// The identity of a commit in a repository. Later commits have greater identities.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CommitId(u64);
+
+impl fmt::Display for CommitId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// A version of the map, with the commits it was made from and a message.
+pub struct Commit<K, V, C: HamtConfig = DefaultConfig> {
+    map: HAMT<K, V, C>,
+    parents: Vec<CommitId>,
+    message: String,
+}
+
+impl<K, V, C: HamtConfig> Commit<K, V, C> {
+    /// The map of the commit.
+    pub fn map(&self) -> &HAMT<K, V, C> {
+        &self.map
+    }
+
+    /// The commits this one was made from: none for the initial commit, two for a merge.
+    pub fn parents(&self) -> &[CommitId] {
+        &self.parents
+    }
+
+    /// The message of the commit.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+/// What `HEAD` points to.
+#[derive(Clone, Debug)]
+enum Head {
+    Branch(String),
+    Detached(CommitId),
+}
+
+/// The result of a merge.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MergeOutcome {
+    /// The merged commit was already an ancestor of `HEAD`, so nothing changed.
+    UpToDate,
+    /// `HEAD` was an ancestor of the merged commit, and was moved to it.
+    FastForward(CommitId),
+    /// A merge commit was made, after resolving the given number of conflicting keys.
+    Merged { commit: CommitId, conflicts: usize },
+}
+
+/// Why an operation on a repository failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RepoError {
+    /// No branch or tag has this name.
+    UnknownRef(String),
+    /// No commit has this identity, or it was collected.
+    UnknownCommit(CommitId),
+    /// A branch or tag already has this name.
+    RefExists(String),
+    /// The branch is checked out, so it cannot be deleted.
+    CheckedOut(String),
+}
+
+impl fmt::Display for RepoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepoError::UnknownRef(name) => write!(f, "no branch or tag named {:?}", name),
+            RepoError::UnknownCommit(id) => write!(f, "no commit {}", id),
+            RepoError::RefExists(name) => write!(f, "a branch or tag named {:?} already exists", name),
+            RepoError::CheckedOut(name) => write!(f, "branch {:?} is checked out", name),
+        }
+    }
+}
+
+impl Error for RepoError {}
+
+/// Commits of versions of a map, with named branches and tags.
+pub struct Repo<K, V, C: HamtConfig = DefaultConfig> {
+    commits: HashMap<CommitId, Commit<K, V, C>>,
+    next_id: u64,
+    branches: BTreeMap<String, CommitId>,
+    tags: BTreeMap<String, CommitId>,
+    head: Head,
+}
+
+impl<K, V> Repo<K, V> {
+    /// Construct a repository with an initial commit of the empty map, on the branch `main`.
+    pub fn new() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K, V, C: HamtConfig> Repo<K, V, C> {
+    /// Construct a repository for maps with the trie shape `C`, with an initial commit of the empty
+    /// map, on the branch `main`.
+    pub fn with_config() -> Self {
+        let mut repo = Repo {
+            commits: HashMap::new(),
+            next_id: 0,
+            branches: BTreeMap::new(),
+            tags: BTreeMap::new(),
+            head: Head::Branch("main".to_string()),
+        };
+        let initial = repo.add_commit(HAMT::with_config(), Vec::new(), "initial commit");
+        repo.branches.insert("main".to_string(), initial);
+        repo
+    }
+
+    fn add_commit(&mut self, map: HAMT<K, V, C>, parents: Vec<CommitId>, message: &str) -> CommitId {
+        let id = CommitId(self.next_id);
+        self.next_id += 1;
+        self.commits.insert(
+            id,
+            Commit {
+                map,
+                parents,
+                message: message.to_string(),
+            },
+        );
+        id
+    }
+
+    /// The commit `HEAD` points to.
+    pub fn head(&self) -> CommitId {
+        match &self.head {
+            Head::Branch(name) => self.branches[name],
+            Head::Detached(id) => *id,
+        }
+    }
+
+    /// The branch checked out, or `None` if `HEAD` is detached.
+    pub fn current_branch(&self) -> Option<&str> {
+        match &self.head {
+            Head::Branch(name) => Some(name),
+            Head::Detached(_) => None,
+        }
+    }
+
+    /// The map of the commit `HEAD` points to.
+    pub fn map(&self) -> &HAMT<K, V, C> {
+        &self.commits[&self.head()].map
+    }
+
+    /// The commit with the identity `id`.
+    pub fn get(&self, id: CommitId) -> Result<&Commit<K, V, C>, RepoError> {
+        self.commits.get(&id).ok_or(RepoError::UnknownCommit(id))
+    }
+
+    /// The commit a branch or tag points to. Branches are looked up first.
+    pub fn resolve(&self, name: &str) -> Result<CommitId, RepoError> {
+        self.branches
+            .get(name)
+            .or_else(|| self.tags.get(name))
+            .copied()
+            .ok_or_else(|| RepoError::UnknownRef(name.to_string()))
+    }
+
+    /// Commit `map` on top of `HEAD`, and move the branch checked out, or `HEAD` if it is detached, to the new commit.
+    pub fn commit(&mut self, map: HAMT<K, V, C>, message: &str) -> CommitId {
+        let parent = self.head();
+        let id = self.add_commit(map, vec![parent], message);
+        self.move_head(id);
+        id
+    }
+
+    fn move_head(&mut self, id: CommitId) {
+        match &self.head {
+            Head::Branch(name) => {
+                self.branches.insert(name.clone(), id);
+            }
+            Head::Detached(_) => self.head = Head::Detached(id),
+        }
+    }
+
+    /// Create a branch pointing to `HEAD`.
+    pub fn branch(&mut self, name: &str) -> Result<CommitId, RepoError> {
+        if self.branches.contains_key(name) || self.tags.contains_key(name) {
+            return Err(RepoError::RefExists(name.to_string()));
+        }
+        let head = self.head();
+        self.branches.insert(name.to_string(), head);
+        Ok(head)
+    }
+
+    /// Delete a branch. The commits only it reached are dropped by the next [`gc`](Repo::gc).
+    pub fn delete_branch(&mut self, name: &str) -> Result<CommitId, RepoError> {
+        if self.current_branch() == Some(name) {
+            return Err(RepoError::CheckedOut(name.to_string()));
+        }
+        self.branches.remove(name).ok_or_else(|| RepoError::UnknownRef(name.to_string()))
+    }
+
+    /// Create a tag pointing to `id`.
+    pub fn tag(&mut self, name: &str, id: CommitId) -> Result<(), RepoError> {
+        self.get(id)?;
+        if self.branches.contains_key(name) || self.tags.contains_key(name) {
+            return Err(RepoError::RefExists(name.to_string()));
+        }
+        self.tags.insert(name.to_string(), id);
+        Ok(())
+    }
+
+    /// Delete a tag. The commits only it reached are dropped by the next [`gc`](Repo::gc).
+    pub fn delete_tag(&mut self, name: &str) -> Result<CommitId, RepoError> {
+        self.tags.remove(name).ok_or_else(|| RepoError::UnknownRef(name.to_string()))
+    }
+
+    /// Check out a branch, or the commit of a tag with a detached `HEAD`, and return its map.
+    pub fn checkout(&mut self, name: &str) -> Result<&HAMT<K, V, C>, RepoError> {
+        self.head = if self.branches.contains_key(name) {
+            Head::Branch(name.to_string())
+        } else {
+            Head::Detached(self.resolve(name)?)
+        };
+        Ok(self.map())
+    }
+
+    /// Check out a commit with a detached `HEAD`, and return its map.
+    pub fn checkout_commit(&mut self, id: CommitId) -> Result<&HAMT<K, V, C>, RepoError> {
+        self.get(id)?;
+        self.head = Head::Detached(id);
+        Ok(self.map())
+    }
+
+    /// The commit `from` and all its ancestors, latest first.
+    pub fn log(&self, from: CommitId) -> Result<Vec<CommitId>, RepoError> {
+        self.get(from)?;
+        let mut ancestors: Vec<CommitId> = self.ancestors(from).into_iter().collect();
+        ancestors.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(ancestors)
+    }
+
+    fn ancestors(&self, from: CommitId) -> HashSet<CommitId> {
+        let mut seen = HashSet::new();
+        let mut pending = vec![from];
+        while let Some(id) = pending.pop() {
+            if seen.insert(id) {
+                pending.extend_from_slice(&self.commits[&id].parents);
+            }
+        }
+        seen
+    }
+
+    /// The latest common ancestor of two commits, which every commit has, as they all descend from
+    /// the initial one.
+    pub fn merge_base(&self, a: CommitId, b: CommitId) -> Result<CommitId, RepoError> {
+        self.get(a)?;
+        self.get(b)?;
+        let of_a = self.ancestors(a);
+        Ok(*self.ancestors(b).iter().filter(|id| of_a.contains(id)).max().unwrap())
+    }
+
+    /// Drop the commits that no branch, tag or `HEAD` reaches, and the nodes only their maps used.
+    /// Return the number of commits dropped.
+    pub fn gc(&mut self) -> usize {
+        let mut reachable = HashSet::new();
+        let roots: Vec<CommitId> = self
+            .branches
+            .values()
+            .chain(self.tags.values())
+            .copied()
+            .chain(Some(self.head()))
+            .collect();
+        for root in roots {
+            if !reachable.contains(&root) {
+                reachable.extend(self.ancestors(root));
+            }
+        }
+        let before = self.commits.len();
+        self.commits.retain(|id, _| reachable.contains(id));
+        before - self.commits.len()
+    }
+}
+
+impl<K, V, C: HamtConfig> Default for Repo<K, V, C> {
+    fn default() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K: Hash + Eq, V: PartialEq, C: HamtConfig> Repo<K, V, C> {
+    /// The changes that turn the map of `from` into the map of `to`.
+    pub fn diff(&self, from: CommitId, to: CommitId) -> Result<Vec<Change<'_, K, V>>, RepoError> {
+        Ok(self.get(from)?.map.diff(&self.get(to)?.map))
+    }
+}
+
+impl<K, V, C> Repo<K, V, C>
+where
+    K: Hash + Eq + Clone,
+    V: PartialEq + Clone,
+    C: HamtConfig,
+{
+    /// Merge the branch, tag or commit `name` into `HEAD`.
+    ///
+    /// The changes made on `name` since the merge base are applied to the map of `HEAD`. A key
+    /// changed on both sides to different values is a conflict, which `resolve` settles, given the
+    /// key and its values in the base, in `HEAD` and in `name`, `None` meaning absent. It returns the
+    /// value to keep, or `None` to remove the key.
+    pub fn merge<F>(&mut self, name: &str, message: &str, mut resolve: F) -> Result<MergeOutcome, RepoError>
+    where
+        F: FnMut(&K, Option<&V>, Option<&V>, Option<&V>) -> Option<V>,
+    {
+        let ours = self.head();
+        let theirs = self.resolve(name)?;
+        let base = self.merge_base(ours, theirs)?;
+        if base == theirs {
+            return Ok(MergeOutcome::UpToDate);
+        }
+        if base == ours {
+            self.move_head(theirs);
+            return Ok(MergeOutcome::FastForward(theirs));
+        }
+
+        let base_map = &self.commits[&base].map;
+        let ours_map = &self.commits[&ours].map;
+        let mut merged = ours_map.clone();
+        let mut conflicts = 0;
+        for change in base_map.diff(&self.commits[&theirs].map) {
+            let (key, base_value, their_value) = match change {
+                Change::Added(k, v) => (k, None, Some(v)),
+                Change::Removed(k, v) => (k, Some(v), None),
+                Change::Updated(k, old, new) => (k, Some(old), Some(new)),
+            };
+            let our_value = ours_map.get(key.clone());
+            let value = if our_value == base_value {
+                their_value.cloned()
+            } else if our_value == their_value {
+                continue;
+            } else {
+                conflicts += 1;
+                resolve(key, base_value, our_value, their_value)
+            };
+            merged = match value {
+                Some(v) => merged.insert(key.clone(), v),
+                None => merged.remove(key.clone()),
+            };
+        }
+        let commit = self.add_commit(merged, vec![ours, theirs], message);
+        self.move_head(commit);
+        Ok(MergeOutcome::Merged { commit, conflicts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MergeOutcome, Repo, RepoError};
+    use crate::Change;
+    use crate::test_util::build;
+    use std::rc::Rc;
+
+    #[test]
+    fn branches_and_history() {
+        let mut repo = Repo::new();
+        let initial = repo.head();
+        let first = repo.commit(repo.map().insert("port", 80), "set port");
+        repo.branch("feature").unwrap();
+        assert_eq!(repo.branch("feature"), Err(RepoError::RefExists("feature".to_string())));
+        let second = repo.commit(repo.map().insert("host", 1), "set host");
+
+        assert_eq!(repo.checkout("feature").unwrap().get("host"), None);
+        assert_eq!(repo.current_branch(), Some("feature"));
+        let third = repo.commit(repo.map().insert("port", 8080), "change port");
+        assert_eq!(repo.log(third).unwrap(), vec![third, first, initial]);
+        assert_eq!(repo.get(third).unwrap().message(), "change port");
+        assert_eq!(repo.get(third).unwrap().parents(), &[first]);
+        assert_eq!(repo.diff(second, third).unwrap().len(), 2);
+        assert_eq!(repo.diff(first, third).unwrap(), vec![Change::Updated(&"port", &80, &8080)]);
+
+        repo.tag("v1", first).unwrap();
+        assert_eq!(repo.checkout("v1").unwrap().get("port"), Some(&80));
+        assert_eq!(repo.current_branch(), None);
+        assert_eq!(repo.checkout("nope").err(), Some(RepoError::UnknownRef("nope".to_string())));
+        assert_eq!(repo.checkout("main").unwrap().get("host"), Some(&1));
+        assert_eq!(repo.delete_branch("main"), Err(RepoError::CheckedOut("main".to_string())));
+    }
+
+    #[test]
+    fn three_way_merge() {
+        let mut repo = Repo::new();
+        let base = build(0..1000);
+        repo.commit(base, "base");
+        repo.branch("other").unwrap();
+        repo.commit(repo.map().insert(1, -1).remove(2).insert(3, 33).insert(5, 5), "ours");
+        repo.checkout("other").unwrap();
+        repo.commit(repo.map().insert(3, 333).insert(4, -4).remove(5).insert(1000, 1000).insert(1, -1), "theirs");
+
+        repo.checkout("main").unwrap();
+        let mut conflicts = Vec::new();
+        let outcome = repo
+            .merge("other", "merge other", |k, base, ours, theirs| {
+                conflicts.push((*k, base.copied(), ours.copied(), theirs.copied()));
+                ours.copied()
+            })
+            .unwrap();
+        assert!(matches!(outcome, MergeOutcome::Merged { conflicts: 1, .. }));
+        assert_eq!(conflicts, vec![(3, Some(3), Some(33), Some(333))]);
+        let map = repo.map();
+        assert_eq!(map.get(1), Some(&-1));
+        assert_eq!(map.get(2), None);
+        assert_eq!(map.get(3), Some(&33));
+        assert_eq!(map.get(4), Some(&-4));
+        assert_eq!(map.get(5), None);
+        assert_eq!(map.get(1000), Some(&1000));
+        assert_eq!(repo.get(repo.head()).unwrap().parents().len(), 2);
+
+        // Merging again changes nothing, and the other branch fast-forwards to the merge.
+        assert_eq!(repo.merge("other", "again", |_, _, _, _| None), Ok(MergeOutcome::UpToDate));
+        let merge = repo.head();
+        repo.checkout("other").unwrap();
+        assert_eq!(repo.merge("main", "forward", |_, _, _, _| None), Ok(MergeOutcome::FastForward(merge)));
+        assert_eq!(repo.head(), merge);
+    }
+
+    #[test]
+    fn gc_drops_unreachable_commits() {
+        let mut repo = Repo::new();
+        repo.commit(repo.map().insert(0, Rc::new(0)), "zero");
+        repo.branch("scratch").unwrap();
+        repo.checkout("scratch").unwrap();
+        let value = Rc::new(1);
+        let weak = Rc::downgrade(&value);
+        let scratch = repo.commit(repo.map().insert(1, value), "scratch");
+        let tagged = repo.commit(repo.map().insert(2, Rc::new(2)), "tagged");
+        repo.tag("keep", tagged).unwrap();
+        repo.commit(repo.map().remove(1), "more");
+        repo.checkout("main").unwrap();
+
+        assert_eq!(repo.gc(), 0);
+        repo.delete_branch("scratch").unwrap();
+        assert_eq!(repo.gc(), 1);
+        // The tagged commit keeps its ancestors, and the value they hold.
+        assert!(repo.get(scratch).is_ok());
+        assert!(weak.upgrade().is_some());
+        assert_eq!(repo.log(tagged).unwrap().len(), 4);
+
+        assert_eq!(repo.delete_tag("keep"), Ok(tagged));
+        assert_eq!(repo.gc(), 2);
+        assert_eq!(repo.get(scratch).err(), Some(RepoError::UnknownCommit(scratch)));
+        assert!(weak.upgrade().is_none());
+    }
+}
@@ -0,0 +1,179 @@
+//! Serialization with serde, behind the `serde` feature.
+//!
+//! A map is serialized as a serde map of its keys and values, in trie order. Deserializing collects
+//! the entries and builds the trie in one pass with `build_node`, rather than inserting them one by
+//! one: keys with colliding hashes go straight into their chain, so input crafted to collide costs
+//! no more than the chains it produces.
+//!
+//! There is no separate set type: a set is a `HAMT<K, ()>`, which the [`set`] module serializes as a
+//! sequence of keys, through `#[serde(with = "hamster::serde_set")]`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{build_node, hash_key, HamtConfig, NodePtr, HAMT};
+
+/// Entries to reserve room for up front, at most, whatever length the input claims.
+const MAX_PREALLOCATED: usize = 4096;
+
+/// Build a map from its entries. For keys given more than once, the last value is kept, as with `insert`.
+fn build<K: Hash + Eq, V, C: HamtConfig>(entries: Vec<(K, V)>) -> HAMT<K, V, C> {
+    // The standard `HashMap` hashes with random keys, so collisions in the hashes of the map do not slow it down.
+    let last: HashMap<&K, usize> = entries.iter().enumerate().map(|(i, (k, _))| (k, i)).collect();
+    let keep: Vec<bool> = (0..entries.len()).map(|i| last[&entries[i].0] == i).collect();
+    drop(last);
+    let items = entries
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|((k, v), _)| (hash_key::<C, K>(&k), k, v))
+        .collect();
+    HAMT {
+        root: NodePtr::new(build_node(items, 0)),
+    }
+}
+
+impl<K: Serialize, V: Serialize, C: HamtConfig> Serialize for HAMT<K, V, C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+impl<'de, K, V, C> Deserialize<'de> for HAMT<K, V, C>
+where
+    K: Deserialize<'de> + Hash + Eq,
+    V: Deserialize<'de>,
+    C: HamtConfig,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(MapVisitor(PhantomData))
+    }
+}
+
+struct MapVisitor<K, V, C: HamtConfig>(PhantomData<HAMT<K, V, C>>);
+
+impl<'de, K, V, C> Visitor<'de> for MapVisitor<K, V, C>
+where
+    K: Deserialize<'de> + Hash + Eq,
+    V: Deserialize<'de>,
+    C: HamtConfig,
+{
+    type Value = HAMT<K, V, C>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("a map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let mut entries = Vec::with_capacity(access.size_hint().unwrap_or(0).min(MAX_PREALLOCATED));
+        while let Some(entry) = access.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(build(entries))
+    }
+}
+
+/// Serialize a set, a `HAMT<K, ()>`, as a sequence of its keys.
+/// Use it on a field with `#[serde(with = "hamster::serde_set")]`.
+pub mod set {
+    use super::*;
+
+    /// Serialize the keys of `set` as a sequence.
+    pub fn serialize<K: Serialize, C: HamtConfig, S: Serializer>(set: &HAMT<K, (), C>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(set.iter().map(|(k, _)| k))
+    }
+
+    /// Deserialize a set from a sequence of keys. Repeated keys are kept once.
+    pub fn deserialize<'de, K, C, D>(deserializer: D) -> Result<HAMT<K, (), C>, D::Error>
+    where
+        K: Deserialize<'de> + Hash + Eq,
+        C: HamtConfig,
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(SetVisitor(PhantomData))
+    }
+
+    struct SetVisitor<K, C: HamtConfig>(PhantomData<HAMT<K, (), C>>);
+
+    impl<'de, K: Deserialize<'de> + Hash + Eq, C: HamtConfig> Visitor<'de> for SetVisitor<K, C> {
+        type Value = HAMT<K, (), C>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+            formatter.write_str("a sequence")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+            let mut entries = Vec::with_capacity(access.size_hint().unwrap_or(0).min(MAX_PREALLOCATED));
+            while let Some(key) = access.next_element()? {
+                entries.push((key, ()));
+            }
+            Ok(build(entries))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, HAMT};
+    use serde::{Deserialize, Serialize};
+    use std::hash::{Hash, Hasher};
+
+    /// A key whose hash only depends on its parity. It is a plain number in JSON, so it can be a map key.
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(transparent)]
+    struct Parity(i32);
+
+    impl Hash for Parity {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            (self.0 % 2).hash(state);
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Tagged {
+        #[serde(with = "crate::serde_set")]
+        tags: HAMT<String, ()>,
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let map = (0..1000).fold(HAMT::new(), |map, k| map.insert(k, format!("v{}", k)));
+        let json = serde_json::to_string(&map).unwrap();
+        let decoded: HAMT<i32, String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, map);
+        // The bulk-built trie has the same shape as the one built by inserting.
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+
+        let empty: HAMT<i32, i32> = serde_json::from_str("{}").unwrap();
+        assert_eq!(empty, HAMT::new());
+        let repeated: HAMT<i32, i32> = serde_json::from_str(r#"{"1": 1, "2": 2, "1": 3}"#).unwrap();
+        assert_eq!(repeated, HAMT::new().insert(1, 3).insert(2, 2));
+        assert!(serde_json::from_str::<HAMT<i32, i32>>("[1, 2]").is_err());
+    }
+
+    #[test]
+    fn sets() {
+        let tags = ["a", "b", "c"].iter().fold(HAMT::new(), |set, tag| set.insert(tag.to_string(), ()));
+        let tagged = Tagged { tags };
+        let json = serde_json::to_string(&tagged).unwrap();
+        assert!(json.starts_with(r#"{"tags":["#));
+        assert_eq!(serde_json::from_str::<Tagged>(&json).unwrap(), tagged);
+        let repeated: Tagged = serde_json::from_str(r#"{"tags": ["a", "b", "a"]}"#).unwrap();
+        assert_eq!(repeated.tags.iter().count(), 2);
+    }
+
+    #[test]
+    fn colliding_keys() {
+        // Every key has one of two hashes, so they end up in two long chains.
+        let map = (0..2000).fold(HAMT::<_, _, Config<4, u128>>::with_config(), |map, k| map.insert(Parity(k), k));
+        let json = serde_json::to_string(&map).unwrap();
+        let decoded: HAMT<Parity, i32, Config<4, u128>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, map);
+        assert_eq!(decoded.get(Parity(1999)), Some(&1999));
+    }
+}
@@ -0,0 +1,300 @@
+//! Sharding a map by hash prefix.
+//!
+//! The entries of a node are ordered by the fragment of the hash they store, so the keys whose
+//! hash starts with a given prefix form a contiguous range of entries at some level. A shard is
+//! the map holding one such range: it shares the subtrees of the range with the original map, and
+//! only the path from the root down to the range is new.
+
+use std::hash::Hash;
+use std::ops::Range;
+use std::slice;
+
+use crate::{
+    get_entries_index, union_entries, Bitmap, HAMTNode, HAMTNodeEntry, HamtConfig, HashWord,
+    NodePtr, HAMT,
+};
+
+/// Maximum number of prefix bits [`split_by_prefix`](HAMT::split_by_prefix) accepts.
+const MAX_PREFIX_BITS: u32 = 24;
+
+/// Check if the full hash of an entry's keys starts with `prefix`, a number of `bits` bits,
+/// once the fragments of `level` levels have been consumed.
+fn entry_has_prefix<K, V, C: HamtConfig>(entry: &HAMTNodeEntry<K, V, C>, level: u32, prefix: u64, bits: u32) -> bool {
+    let rest = match entry {
+        HAMTNodeEntry::Value(_, _, hash) => hash.shift(C::BITS * level),
+        // Chains are below every fragment of the hash of their keys, so nothing of it is left.
+        HAMTNodeEntry::Chained(_) => C::Hash::ZERO,
+        HAMTNodeEntry::Node(_) => unreachable!(),
+    };
+    rest.fragment(bits) as u64 == prefix
+}
+
+/// The part of the node at `level` whose keys have hashes that continue with `prefix`, a number of `bits` bits.
+fn shard_node<K: Hash + Clone, V: Clone, C: HamtConfig>(
+    node: &NodePtr<K, V, C>,
+    level: u32,
+    prefix: u64,
+    bits: u32,
+) -> Option<NodePtr<K, V, C>> {
+    if bits <= C::BITS {
+        // The prefix selects a range of fragments at this level.
+        let low = (prefix << (C::BITS - bits)) as u32;
+        let high = low + (1 << (C::BITS - bits));
+        let mut shard: HAMTNode<K, V, C> = HAMTNode {
+            presence_map: C::Bitmap::EMPTY,
+            entries: Vec::new(),
+        };
+        for frag in low..high {
+            if node.presence_map.contains(frag) {
+                shard.presence_map = shard.presence_map.with(frag);
+                shard.entries.push(node.entries[get_entries_index(node.presence_map, frag)].clone());
+            }
+        }
+        return if shard.entries.len() == node.entries.len() {
+            Some(node.clone())
+        } else if shard.entries.is_empty() {
+            None
+        } else {
+            Some(NodePtr::new(shard))
+        };
+    }
+    // The prefix selects one fragment at this level, and continues below it.
+    let rest_bits = bits - C::BITS;
+    let frag = (prefix >> rest_bits) as u32;
+    let rest = prefix & ((1 << rest_bits) - 1);
+    if !node.presence_map.contains(frag) {
+        return None;
+    }
+    let entry = match &node.entries[get_entries_index(node.presence_map, frag)] {
+        HAMTNodeEntry::Node(child) => HAMTNodeEntry::Node(shard_node(child, level + 1, rest, rest_bits)?),
+        entry if entry_has_prefix(entry, level + 1, rest, rest_bits) => entry.clone(),
+        _ => return None,
+    };
+    if node.entries.len() == 1 {
+        if let (HAMTNodeEntry::Node(old), HAMTNodeEntry::Node(new)) = (&node.entries[0], &entry) {
+            if NodePtr::ptr_eq(old, new) {
+                return Some(node.clone());
+            }
+        }
+    }
+    Some(NodePtr::new(HAMTNode {
+        presence_map: C::Bitmap::EMPTY.with(frag),
+        entries: vec![entry],
+    }))
+}
+
+impl<K, V, C> HAMT<K, V, C>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    C: HamtConfig,
+{
+    /// Split the map into `2^bits` shards, where shard `i` holds the keys whose hash starts with the
+    /// `bits` bits of `i`. The shards share their subtrees with this map.
+    ///
+    /// Panics if `bits` is greater than 24.
+    pub fn split_by_prefix(&self, bits: u32) -> Vec<Self> {
+        assert!(bits <= MAX_PREFIX_BITS, "at most {} prefix bits are supported", MAX_PREFIX_BITS);
+        (0..1u64 << bits)
+            .map(|prefix| HAMT {
+                root: shard_node(&self.root, 0, prefix, bits).unwrap_or_else(|| HAMT::with_config().root),
+            })
+            .collect()
+    }
+
+    /// Reassemble shards returned by [`split_by_prefix`](HAMT::split_by_prefix), which may have been
+    /// modified since, as long as each still only holds keys with its own prefix.
+    /// Only the top levels of the shards are merged, so this takes time proportional to the number of shards.
+    ///
+    /// If several shards hold the same key, the value of the first one is kept.
+    pub fn join_shards<I: IntoIterator<Item = Self>>(shards: I) -> Self {
+        let mut root: HAMTNode<K, V, C> = HAMTNode {
+            presence_map: C::Bitmap::EMPTY,
+            entries: Vec::new(),
+        };
+        for shard in shards {
+            if root.entries.is_empty() {
+                root.presence_map = shard.root.presence_map;
+                root.entries = shard.root.entries.clone();
+                continue;
+            }
+            let mut present = shard.root.presence_map.to_u64();
+            while present != 0 {
+                let frag = present.trailing_zeros();
+                present &= present - 1;
+                let entry = &shard.root.entries[get_entries_index(shard.root.presence_map, frag)];
+                let index = get_entries_index(root.presence_map, frag);
+                if root.presence_map.contains(frag) {
+                    root.entries[index] = union_entries(&root.entries[index], entry, 1);
+                } else {
+                    root.presence_map = root.presence_map.with(frag);
+                    root.entries.insert(index, entry.clone());
+                }
+            }
+        }
+        HAMT {
+            root: NodePtr::new(root),
+        }
+    }
+}
+
+/// Number of bits of the hash consumed by the fragments of the first `level` levels.
+fn consumed<C: HamtConfig>(level: u32) -> u32 {
+    (C::BITS * level).min(C::Hash::BITS)
+}
+
+/// A node being visited by a [`HashRange`], with its level, the hash prefix it stores, and the
+/// fragments left to visit.
+type Visit<'a, K, V, C> = (&'a HAMTNode<K, V, C>, u32, u128, u64);
+
+/// An iterator over the entries of a map whose hash is in a range,
+/// returned by [`range_by_hash`](HAMT::range_by_hash).
+pub struct HashRange<'a, K, V, C: HamtConfig> {
+    /// The range, as bits of the hash.
+    low: u128,
+    high: u128,
+    /// The nodes being visited, deepest last.
+    stack: Vec<Visit<'a, K, V, C>>,
+    /// The rest of the chain being visited.
+    chain: slice::Iter<'a, (K, V)>,
+}
+
+impl<'a, K, V, C: HamtConfig> Iterator for HashRange<'a, K, V, C> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((k, v)) = self.chain.next() {
+            return Some((k, v));
+        }
+        loop {
+            let (node, level, prefix, present) = self.stack.last_mut()?;
+            let (node, level, prefix) = (*node, *level, *prefix);
+            if *present == 0 {
+                self.stack.pop();
+                continue;
+            }
+            let frag = present.trailing_zeros();
+            *present &= *present - 1;
+            let index = get_entries_index(node.presence_map, frag);
+            // The hashes the entry can store.
+            let bits = consumed::<C>(level + 1) - consumed::<C>(level);
+            let child_prefix = (prefix << bits) | (frag >> (C::BITS - bits)) as u128;
+            let free_bits = C::Hash::BITS - consumed::<C>(level + 1);
+            let entry_low = child_prefix << free_bits;
+            let entry_high = entry_low + ((1u128 << free_bits) - 1);
+            if entry_high < self.low || entry_low >= self.high {
+                continue;
+            }
+            let inside = self.low <= entry_low && entry_high < self.high;
+            match &node.entries[index] {
+                HAMTNodeEntry::Node(child) => self.stack.push((child, level + 1, child_prefix, child.presence_map.to_u64())),
+                HAMTNodeEntry::Value(k, v, hash) => {
+                    if self.low <= hash.to_u128() && hash.to_u128() < self.high {
+                        return Some((k, v));
+                    }
+                }
+                // A chain is below every fragment of the hash of its keys, so the entry only has that hash.
+                HAMTNodeEntry::Chained(vec) => {
+                    if inside {
+                        self.chain = vec.iter();
+                        if let Some((k, v)) = self.chain.next() {
+                            return Some((k, v));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<K, V, C: HamtConfig> HAMT<K, V, C> {
+    /// Iterate over the entries whose key hashes (see [`hash_of`](HAMT::hash_of)) are in `range`,
+    /// in the order of [`iter`](HAMT::iter). Subtrees outside of the range are not visited.
+    pub fn range_by_hash(&self, range: Range<C::Hash>) -> HashRange<'_, K, V, C> {
+        HashRange {
+            low: range.start.to_u128(),
+            high: range.end.to_u128(),
+            stack: vec![(&self.root, 0, 0, self.root.presence_map.to_u64())],
+            chain: [].iter(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{build, Colliding};
+    use crate::{Config, HashWord, HAMT};
+
+    #[test]
+    fn split_and_join() {
+        let map = build(0..5000);
+        for bits in [0, 1, 3, 5, 7, 12] {
+            let shards = map.split_by_prefix(bits);
+            assert_eq!(shards.len(), 1 << bits);
+            for (prefix, shard) in shards.iter().enumerate() {
+                for (k, _) in shard.iter() {
+                    assert_eq!(map.hash_of(k).checked_shr(64 - bits).unwrap_or(0) as usize, prefix);
+                }
+            }
+            let total: usize = shards.iter().map(|shard| shard.iter().count()).sum();
+            assert_eq!(total, 5000);
+            assert_eq!(HAMT::join_shards(shards), map);
+        }
+        // A shard holding a whole subtree shares it.
+        assert!(map.split_by_prefix(0)[0].ptr_eq(&map));
+    }
+
+    #[test]
+    fn join_modified_shards() {
+        let map = build(0..2000);
+        let mut shards = map.split_by_prefix(7);
+        let mut expected = map.clone();
+        for k in 2000..2100 {
+            let prefix = map.hash_of(&k).fragment(7) as usize;
+            shards[prefix] = shards[prefix].insert(k, k);
+            expected = expected.insert(k, k);
+        }
+        // Empty a shard entirely, in another one remove the keys of one fragment at the second level.
+        let emptied: Vec<i32> = shards[3].iter().map(|(k, _)| *k).collect();
+        shards[3] = emptied.iter().fold(shards[3].clone(), |shard, k| shard.remove(*k));
+        expected = emptied.iter().fold(expected, |map, k| map.remove(*k));
+        assert_eq!(HAMT::join_shards(shards), expected);
+        assert_eq!(HAMT::<i32, i32>::join_shards(vec![]), HAMT::new());
+    }
+
+    #[test]
+    fn range_by_hash() {
+        let map = build(0..3000);
+        let hashes: Vec<u64> = (0..3000).map(|k| map.hash_of(&k)).collect();
+        let mut sorted = hashes.clone();
+        sorted.sort_unstable();
+        let (low, high) = (sorted[1000], sorted[1500]);
+        let mut found: Vec<i32> = map.range_by_hash(low..high).map(|(k, _)| *k).collect();
+        found.sort_unstable();
+        let mut expected: Vec<i32> = (0..3000).filter(|k| (low..high).contains(&hashes[*k as usize])).collect();
+        expected.sort_unstable();
+        assert_eq!(found.len(), 500);
+        assert_eq!(found, expected);
+        assert_eq!(map.range_by_hash(0..u64::MAX).count(), 3000);
+        assert_eq!(map.range_by_hash(low..low).count(), 0);
+        // Same order as `iter`.
+        let in_order: Vec<i32> = map
+            .iter()
+            .filter(|(k, _)| (low..high).contains(&map.hash_of(*k)))
+            .map(|(k, _)| *k)
+            .collect();
+        assert_eq!(map.range_by_hash(low..high).map(|(k, _)| *k).collect::<Vec<_>>(), in_order);
+    }
+
+    #[test]
+    fn shards_of_chains() {
+        let key = |id| Colliding { bucket: (id % 3) as u8, id };
+        let map = (0..30).fold(HAMT::<_, _, Config<4, u128>>::with_config(), |map, id| map.insert(key(id), id));
+        let shards = map.split_by_prefix(9);
+        let total: usize = shards.iter().map(|shard| shard.iter().count()).sum();
+        assert_eq!(total, 30);
+        assert_eq!(HAMT::join_shards(shards), map);
+        let hash = map.hash_of(&key(0));
+        assert_eq!(map.range_by_hash(hash..hash + 1).count(), 10);
+    }
+}
@@ -0,0 +1,365 @@
+//! A binary snapshot format for several versions of a map, that keeps the nodes they share shared.
+//!
+//! The writer visits the nodes of every root, and writes each distinct node once, after the nodes
+//! it points to, so that an entry pointing to a node refers to it by its index in the file. The roots
+//! are written last, as indexes too. The reader rebuilds the nodes in the same order, so maps read
+//! back share exactly the nodes the written maps shared.
+//!
+//! Layout, with integers in little endian:
+//!
+//! - header: the magic bytes `HAMTSNAP`, the format version (`u32`), the bits per level and bits of
+//!   the hash of the configuration, and the identifier of the hash function (`u8` each);
+//! - the number of nodes (`u64`), then each node: its presence map (`u64`), then for each entry a tag
+//!   byte followed by a key, value and the hash the key was inserted with (`0`, as many bytes as the
+//!   hash has), a chain length and its keys and values (`1`), or the index of a node written before
+//!   (`2`, `u64`);
+//! - the number of roots (`u64`), then the index of each root;
+//! - an FNV-1a checksum (`u64`) of everything before it.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::codec::{decode_items, decode_len, take, Codec, DecodeError};
+use crate::hasher::HASH_ID;
+use crate::{Bitmap, HAMTNode, HAMTNodeEntry, HamtConfig, HashWord, NodePtr, HAMT};
+
+const MAGIC: &[u8; 8] = b"HAMTSNAP";
+const VERSION: u32 = 3;
+
+const TAG_VALUE: u8 = 0;
+const TAG_CHAINED: u8 = 1;
+const TAG_NODE: u8 = 2;
+
+/// Why a snapshot could not be read.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// Reading the input failed.
+    Io(io::Error),
+    /// The input does not start with the magic bytes of a snapshot.
+    NotASnapshot,
+    /// The snapshot was written in a version of the format this version of the crate cannot read.
+    UnsupportedVersion(u32),
+    /// The snapshot was written by maps with another configuration: its bits per level and bits of the hash.
+    ConfigMismatch { bits: u8, hash_bits: u8 },
+    /// The keys were hashed with a hash function this version of the crate does not use, given by its identifier.
+    UnsupportedHash(u8),
+    /// The checksum does not match the contents, which were corrupted.
+    ChecksumMismatch,
+    /// A key, a value or the structure of the snapshot could not be decoded.
+    Decode(DecodeError),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "could not read snapshot: {}", e),
+            SnapshotError::NotASnapshot => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {}", version),
+            SnapshotError::ConfigMismatch { bits, hash_bits } => write!(
+                f,
+                "snapshot of maps with {} bits per level and {}-bit hashes",
+                bits, hash_bits
+            ),
+            SnapshotError::UnsupportedHash(id) => write!(f, "snapshot of keys hashed by unknown hash function {}", id),
+            SnapshotError::ChecksumMismatch => write!(f, "snapshot checksum mismatch"),
+            SnapshotError::Decode(e) => write!(f, "corrupt snapshot: {}", e),
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            SnapshotError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<DecodeError> for SnapshotError {
+    fn from(e: DecodeError) -> Self {
+        SnapshotError::Decode(e)
+    }
+}
+
+/// The 64-bit FNV-1a hash of `bytes`.
+pub(crate) fn checksum(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Append the presence map and entries of `node` to `out`, writing child nodes as the number `child` gives them.
+pub(crate) fn encode_node<K: Codec, V: Codec, C: HamtConfig>(
+    node: &HAMTNode<K, V, C>,
+    out: &mut Vec<u8>,
+    mut child: impl FnMut(&NodePtr<K, V, C>) -> u64,
+) {
+    node.presence_map.to_u64().encode(out);
+    for entry in &node.entries {
+        match entry {
+            HAMTNodeEntry::Value(k, v, hash) => {
+                out.push(TAG_VALUE);
+                k.encode(out);
+                v.encode(out);
+                out.extend_from_slice(&hash.to_u128().to_le_bytes()[..C::Hash::BITS as usize / 8]);
+            }
+            HAMTNodeEntry::Chained(vec) => {
+                out.push(TAG_CHAINED);
+                vec.len().encode(out);
+                for (k, v) in vec {
+                    k.encode(out);
+                    v.encode(out);
+                }
+            }
+            HAMTNodeEntry::Node(node) => {
+                out.push(TAG_NODE);
+                child(node).encode(out);
+            }
+        }
+    }
+}
+
+/// Decode a node written by [`encode_node`], resolving child nodes from their numbers with `child`.
+pub(crate) fn decode_node<K: Codec, V: Codec, C: HamtConfig>(
+    input: &mut &[u8],
+    mut child: impl FnMut(u64) -> Result<NodePtr<K, V, C>, DecodeError>,
+) -> Result<HAMTNode<K, V, C>, DecodeError> {
+    let presence_map = u64::decode(input)?;
+    let bitmap =
+        C::Bitmap::from_u64(presence_map).ok_or(DecodeError::Invalid("presence map has bits past the last fragment"))?;
+    let mut entries = Vec::with_capacity(presence_map.count_ones() as usize);
+    for _ in 0..presence_map.count_ones() {
+        entries.push(match take(input, 1)?[0] {
+            TAG_VALUE => {
+                let (k, v) = (K::decode(input)?, V::decode(input)?);
+                let mut hash = [0; 16];
+                hash[..C::Hash::BITS as usize / 8].copy_from_slice(take(input, C::Hash::BITS as usize / 8)?);
+                HAMTNodeEntry::Value(k, v, C::Hash::from_u128(u128::from_le_bytes(hash)).unwrap())
+            }
+            TAG_CHAINED => {
+                let len = decode_len(input)?;
+                if len < 2 {
+                    return Err(DecodeError::Invalid("chain of fewer than two entries"));
+                }
+                HAMTNodeEntry::Chained(decode_items(input, len, |input| Ok((K::decode(input)?, V::decode(input)?)))?)
+            }
+            TAG_NODE => {
+                let node = child(u64::decode(input)?)?;
+                if node.entries.is_empty() {
+                    return Err(DecodeError::Invalid("entry points to an empty node"));
+                }
+                HAMTNodeEntry::Node(node)
+            }
+            _ => return Err(DecodeError::Invalid("unknown entry tag")),
+        });
+    }
+    Ok(HAMTNode {
+        presence_map: bitmap,
+        entries,
+    })
+}
+
+/// Encodes the distinct nodes reachable from the roots, children first.
+struct Writer<'a, K, V, C: HamtConfig> {
+    indexes: HashMap<*const HAMTNode<K, V, C>, u64>,
+    nodes: Vec<&'a NodePtr<K, V, C>>,
+}
+
+impl<'a, K, V, C: HamtConfig> Writer<'a, K, V, C> {
+    /// Give an index to `node` and to the nodes under it that have none yet, and return the index of `node`.
+    fn visit(&mut self, node: &'a NodePtr<K, V, C>) -> u64 {
+        if let Some(index) = self.indexes.get(&node.as_ptr()) {
+            return *index;
+        }
+        for entry in &node.entries {
+            if let HAMTNodeEntry::Node(child) = entry {
+                self.visit(child);
+            }
+        }
+        let index = self.nodes.len() as u64;
+        self.indexes.insert(node.as_ptr(), index);
+        self.nodes.push(node);
+        index
+    }
+}
+
+/// Write the maps to `out` as a snapshot. Nodes shared by several maps, or several times by one map,
+/// are written once.
+pub fn write_snapshot<K: Codec, V: Codec, C: HamtConfig, W: Write>(maps: &[&HAMT<K, V, C>], mut out: W) -> io::Result<()> {
+    let mut writer = Writer {
+        indexes: HashMap::new(),
+        nodes: Vec::new(),
+    };
+    let roots: Vec<u64> = maps.iter().map(|map| writer.visit(&map.root)).collect();
+
+    let mut bytes = MAGIC.to_vec();
+    VERSION.encode(&mut bytes);
+    (C::BITS as u8).encode(&mut bytes);
+    (C::Hash::BITS as u8).encode(&mut bytes);
+    HASH_ID.encode(&mut bytes);
+    writer.nodes.len().encode(&mut bytes);
+    for node in &writer.nodes {
+        encode_node(node, &mut bytes, |child| writer.indexes[&child.as_ptr()]);
+    }
+    roots.len().encode(&mut bytes);
+    for root in roots {
+        root.encode(&mut bytes);
+    }
+    checksum(&bytes).encode(&mut bytes);
+    out.write_all(&bytes)
+}
+
+/// Read the maps of a snapshot written by [`write_snapshot`], in the order they were written in.
+/// The maps share the nodes the written maps shared.
+pub fn read_snapshot<K: Codec, V: Codec, C: HamtConfig, R: Read>(mut input: R) -> Result<Vec<HAMT<K, V, C>>, SnapshotError> {
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(SnapshotError::NotASnapshot);
+    }
+    let mut input = &bytes[MAGIC.len()..];
+    let version = u32::decode(&mut input)?;
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    let (bits, hash_bits) = (u8::decode(&mut input)?, u8::decode(&mut input)?);
+    if bits as u32 != C::BITS || hash_bits as u32 != C::Hash::BITS {
+        return Err(SnapshotError::ConfigMismatch { bits, hash_bits });
+    }
+    let hash_id = u8::decode(&mut input)?;
+    if hash_id != HASH_ID {
+        return Err(SnapshotError::UnsupportedHash(hash_id));
+    }
+    if input.len() < 8 {
+        return Err(DecodeError::UnexpectedEnd.into());
+    }
+    let (mut input, mut sum) = input.split_at(input.len() - 8);
+    if checksum(&bytes[..bytes.len() - 8]) != u64::decode(&mut sum)? {
+        return Err(SnapshotError::ChecksumMismatch);
+    }
+
+    let count = decode_len(&mut input)?;
+    let mut nodes: Vec<NodePtr<K, V, C>> = Vec::with_capacity(count.min(input.len() / 8));
+    for _ in 0..count {
+        let node = decode_node(&mut input, |index| {
+            // Children are written first, so a valid index also rules out cycles.
+            usize::try_from(index)
+                .ok()
+                .and_then(|index| nodes.get(index))
+                .cloned()
+                .ok_or(DecodeError::Invalid("entry points to a node not written before it"))
+        })?;
+        nodes.push(NodePtr::new(node));
+    }
+    let roots = decode_len(&mut input)?;
+    let mut maps = Vec::with_capacity(roots.min(input.len() / 8));
+    for _ in 0..roots {
+        let root = nodes
+            .get(decode_len(&mut input)?)
+            .ok_or(DecodeError::Invalid("root is not a node of the snapshot"))?;
+        maps.push(HAMT { root: root.clone() });
+    }
+    if !input.is_empty() {
+        return Err(DecodeError::Invalid("trailing bytes after the roots").into());
+    }
+    Ok(maps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, read_snapshot, write_snapshot, SnapshotError, MAGIC, TAG_CHAINED, VERSION};
+    use crate::codec::{Codec, DecodeError};
+    use crate::test_util::Colliding;
+    use crate::{Config, HAMTNodeEntry, NodePtr, HAMT};
+
+    fn versions() -> Vec<HAMT<i32, String>> {
+        let mut map = (0..1000).fold(HAMT::new(), |map, k| map.insert(k, k.to_string()));
+        (0..100)
+            .map(|i| {
+                map = map.insert(i * 7, format!("version {}", i));
+                map.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn versions_share_nodes() {
+        let versions = versions();
+        let mut one = Vec::new();
+        write_snapshot(&[&versions[0]], &mut one).unwrap();
+        let mut all = Vec::new();
+        write_snapshot(&versions.iter().collect::<Vec<_>>(), &mut all).unwrap();
+        // Each version only adds the path to one key, rather than a full copy.
+        assert!(all.len() < one.len() * 10);
+
+        let read: Vec<HAMT<i32, String>> = read_snapshot(&all[..]).unwrap();
+        assert_eq!(read, versions);
+        // Subtrees shared by consecutive versions are shared again once read.
+        for (a, b) in read.iter().zip(&read[1..]) {
+            let shared = a.root.entries.iter().zip(&b.root.entries).filter(|pair| match pair {
+                (HAMTNodeEntry::Node(x), HAMTNodeEntry::Node(y)) => NodePtr::ptr_eq(x, y),
+                _ => false,
+            });
+            assert_eq!(shared.count(), a.root.entries.len() - 1);
+        }
+    }
+
+    #[test]
+    fn chains_and_empty_maps() {
+        let key = |id| Colliding { bucket: (id % 3) as u8, id };
+        let map = (0..20).fold(HAMT::<_, _, Config<4, u128>>::with_config(), |map, id| map.insert(key(id), id));
+        let empty = HAMT::with_config();
+        let mut bytes = Vec::new();
+        write_snapshot(&[&map, &empty, &map], &mut bytes).unwrap();
+        let read: Vec<HAMT<Colliding, i32, Config<4, u128>>> = read_snapshot(&bytes[..]).unwrap();
+        assert_eq!(read, vec![map, empty.clone(), read[0].clone()]);
+        assert!(read[0].ptr_eq(&read[2]));
+    }
+
+    #[test]
+    fn decode_errors() {
+        let versions = versions();
+        let mut bytes = Vec::new();
+        write_snapshot(&[&versions[0], &versions[1]], &mut bytes).unwrap();
+        let read = |bytes: &[u8]| read_snapshot::<i32, String, crate::DefaultConfig, _>(bytes).unwrap_err();
+
+        assert!(matches!(read(b"not a snapshot"), SnapshotError::NotASnapshot));
+        let mut corrupt = bytes.clone();
+        corrupt[40] ^= 1;
+        assert!(matches!(read(&corrupt), SnapshotError::ChecksumMismatch));
+        let mut newer = bytes.clone();
+        newer[8] = 4;
+        assert!(matches!(read(&newer), SnapshotError::UnsupportedVersion(4)));
+        let mut rehashed = bytes.clone();
+        rehashed[14] = 0;
+        assert!(matches!(read(&rehashed), SnapshotError::UnsupportedHash(0)));
+        assert!(matches!(read(&bytes[..bytes.len() / 2]), SnapshotError::ChecksumMismatch));
+        assert!(matches!(read(&bytes[..12]), SnapshotError::Decode(DecodeError::UnexpectedEnd)));
+        let other = read_snapshot::<i32, String, Config<4>, _>(&bytes[..]).unwrap_err();
+        assert!(matches!(other, SnapshotError::ConfigMismatch { bits: 5, hash_bits: 64 }));
+        assert_eq!(other.to_string(), "snapshot of maps with 5 bits per level and 64-bit hashes");
+
+        // A chain of keys and values that take no bytes, with a huge length, is rejected at once.
+        let mut bytes = MAGIC.to_vec();
+        VERSION.encode(&mut bytes);
+        bytes.extend_from_slice(&[5, 64, crate::hasher::HASH_ID]);
+        1u64.encode(&mut bytes);
+        1u64.encode(&mut bytes);
+        bytes.push(TAG_CHAINED);
+        u64::MAX.encode(&mut bytes);
+        checksum(&bytes).encode(&mut bytes);
+        let empty = read_snapshot::<(), (), crate::DefaultConfig, _>(&bytes[..]).unwrap_err();
+        assert!(matches!(empty, SnapshotError::Decode(DecodeError::Invalid("too many items that take no bytes"))));
+    }
+}
@@ -0,0 +1,299 @@
+//! Entries stamped with the version that last modified them, to list what changed since a version.
+//!
+//! A [`StampedHAMT`] numbers its writes, and stores each value with the version that wrote it. A
+//! removal leaves a tombstone stamped the same way, so that it can be reported too. Each node is
+//! stamped with the highest version in its subtree, so listing the entries modified since a version
+//! skips every subtree whose stamp is not above it, and takes time proportional to the number of
+//! changes times the depth of the trie, without needing the old version of the map.
+//!
+//! Like [`Merkle`](crate::Merkle) digests, stamps are kept next to the nodes, in a table that only
+//! holds weak references to them. Nodes never change, so a stamp stays valid for as long as its
+//! node lives, and each write only stamps the nodes it copied.
+
+use std::hash::Hash;
+
+use crate::cache::NodeCache;
+use crate::{DefaultConfig, HAMTNodeEntry, HamtConfig, NodePtr, HAMT};
+
+/// A value with the version that wrote it, or a tombstone for a key removed at that version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stamped<V> {
+    pub version: u64,
+    pub value: Option<V>,
+}
+
+/// An entry modified after some version, listed by [`StampedHAMT::changed_since`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Modified<'a, K, V> {
+    /// The key was set to the value at the version.
+    Set(&'a K, &'a V, u64),
+    /// The key was removed at the version.
+    Removed(&'a K, u64),
+}
+
+/// A map whose entries and nodes are stamped with the version that last modified them.
+pub struct StampedHAMT<K, V, C: HamtConfig = DefaultConfig> {
+    map: HAMT<K, Stamped<V>, C>,
+    version: u64,
+    /// Tombstones up to this version were purged.
+    purged: u64,
+    /// Number of keys that are present, not counting tombstones.
+    len: usize,
+    /// The highest version under each node.
+    stamps: NodeCache<K, Stamped<V>, C, u64>,
+}
+
+impl<K, V> StampedHAMT<K, V> {
+    /// Construct an empty map at version 0.
+    pub fn new() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K, V, C: HamtConfig> StampedHAMT<K, V, C> {
+    /// Construct an empty map with the trie shape `C`, at version 0.
+    pub fn with_config() -> Self {
+        StampedHAMT {
+            map: HAMT::with_config(),
+            version: 0,
+            purged: 0,
+            len: 0,
+            stamps: NodeCache::new(),
+        }
+    }
+
+    /// The version of the last write.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// The map of stamped values, including tombstones.
+    pub fn stamped(&self) -> &HAMT<K, Stamped<V>, C> {
+        &self.map
+    }
+
+    /// Number of keys that are present, not counting tombstones.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if no key is present, even if some were removed.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterate over the keys that are present and their values, skipping tombstones.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.map.iter().filter_map(|(k, stamped)| stamped.value.as_ref().map(|v| (k, v)))
+    }
+
+    /// The highest version under `node`, computing the stamps of the nodes that have none yet.
+    fn stamp(&mut self, node: &NodePtr<K, Stamped<V>, C>) -> u64 {
+        if let Some(stamp) = self.stamps.get(node) {
+            return *stamp;
+        }
+        let mut stamp = 0;
+        for entry in &node.entries {
+            stamp = stamp.max(match entry {
+                HAMTNodeEntry::Value(_, stamped, _) => stamped.version,
+                HAMTNodeEntry::Chained(vec) => vec.iter().map(|(_, stamped)| stamped.version).max().unwrap_or(0),
+                HAMTNodeEntry::Node(child) => self.stamp(child),
+            });
+        }
+        self.stamps.insert(node, stamp);
+        stamp
+    }
+
+    /// Replace the map, stamping the nodes it does not share with the previous one.
+    fn replace(&mut self, map: HAMT<K, Stamped<V>, C>) {
+        self.map = map;
+        let root = self.map.root.clone();
+        self.stamp(&root);
+    }
+
+    /// List the entries modified after `version`, with the versions that modified them, in no
+    /// particular order. Return `None` if tombstones of removals after `version` were purged, in
+    /// which case the changes cannot be listed.
+    pub fn changed_since(&self, version: u64) -> Option<Vec<Modified<'_, K, V>>> {
+        if version < self.purged {
+            return None;
+        }
+        let mut changes = Vec::new();
+        self.collect_since(&self.map.root, version, &mut changes);
+        Some(changes)
+    }
+
+    fn collect_since<'a>(&self, node: &'a NodePtr<K, Stamped<V>, C>, version: u64, changes: &mut Vec<Modified<'a, K, V>>) {
+        // Every node of the map is stamped when it is written, but a node without a stamp is still
+        // searched, as it may hold changes.
+        if self.stamps.get(node).is_some_and(|stamp| *stamp <= version) {
+            return;
+        }
+        let report = |changes: &mut Vec<Modified<'a, K, V>>, k: &'a K, stamped: &'a Stamped<V>| {
+            if stamped.version > version {
+                changes.push(match &stamped.value {
+                    Some(v) => Modified::Set(k, v, stamped.version),
+                    None => Modified::Removed(k, stamped.version),
+                });
+            }
+        };
+        for entry in &node.entries {
+            match entry {
+                HAMTNodeEntry::Value(k, stamped, _) => report(changes, k, stamped),
+                HAMTNodeEntry::Chained(vec) => vec.iter().for_each(|(k, stamped)| report(changes, k, stamped)),
+                HAMTNodeEntry::Node(child) => self.collect_since(child, version, changes),
+            }
+        }
+    }
+}
+
+impl<K, V, C: HamtConfig> Default for StampedHAMT<K, V, C> {
+    fn default() -> Self {
+        Self::with_config()
+    }
+}
+
+impl<K, V, C> StampedHAMT<K, V, C>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    C: HamtConfig,
+{
+    /// Get the value of `key`, unless it is absent or removed.
+    pub fn get(&self, key: K) -> Option<&V> {
+        self.map.get(key).and_then(|stamped| stamped.value.as_ref())
+    }
+
+    /// Insert the given key and value, and return the new version.
+    pub fn insert(&mut self, key: K, value: V) -> u64 {
+        if self.get(key.clone()).is_none() {
+            self.len += 1;
+        }
+        self.version += 1;
+        let stamped = Stamped {
+            version: self.version,
+            value: Some(value),
+        };
+        self.replace(self.map.insert(key, stamped));
+        self.version
+    }
+
+    /// Remove `key`, leaving a tombstone, and return the new version. Removing a key that is absent
+    /// writes nothing, and returns the current version.
+    pub fn remove(&mut self, key: K) -> u64 {
+        if self.get(key.clone()).is_none() {
+            return self.version;
+        }
+        self.len -= 1;
+        self.version += 1;
+        let tombstone = Stamped {
+            version: self.version,
+            value: None,
+        };
+        self.replace(self.map.insert(key, tombstone));
+        self.version
+    }
+
+    /// Drop the tombstones of removals up to `version`. Changes since a version before it can no
+    /// longer be listed, as those removals would be missing.
+    pub fn purge_tombstones(&mut self, version: u64) {
+        let version = version.min(self.version);
+        let dead: Vec<K> = self
+            .map
+            .iter()
+            .filter(|(_, stamped)| stamped.value.is_none() && stamped.version <= version)
+            .map(|(k, _)| k.clone())
+            .collect();
+        let map = dead.into_iter().fold(self.map.clone(), |map, k| map.remove(k));
+        self.replace(map);
+        self.purged = self.purged.max(version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Modified, StampedHAMT};
+    use crate::cache::NodeCache;
+    use crate::test_util::Colliding;
+    use crate::{Config, HAMTNodeEntry};
+
+    #[test]
+    fn changes_since_a_version() {
+        let mut map = StampedHAMT::new();
+        for k in 0..10_000 {
+            map.insert(k, k);
+        }
+        let synced = map.version();
+        assert_eq!(map.changed_since(synced), Some(vec![]));
+        map.insert(5, -5);
+        map.remove(6);
+        assert_eq!(map.remove(-1), synced + 2);
+        map.insert(20_000, 0);
+        map.remove(20_000);
+
+        let mut changes = map.changed_since(synced).unwrap();
+        changes.sort_by_key(|change| match change {
+            Modified::Set(_, _, version) | Modified::Removed(_, version) => *version,
+        });
+        assert_eq!(
+            changes,
+            vec![
+                Modified::Set(&5, &-5, synced + 1),
+                Modified::Removed(&6, synced + 2),
+                Modified::Removed(&20_000, synced + 4),
+            ]
+        );
+        assert_eq!(map.get(6), None);
+        assert_eq!(map.get(5), Some(&-5));
+        assert_eq!(map.changed_since(0).unwrap().len(), 10_001);
+        // Tombstones are not entries of the map.
+        assert_eq!(map.len(), 9_999);
+        assert_eq!(map.iter().count(), 9_999);
+        assert!(map.iter().all(|(k, _)| *k != 6 && *k != 20_000));
+    }
+
+    #[test]
+    fn untouched_subtrees_are_pruned() {
+        let mut map = StampedHAMT::new();
+        for k in 0..10_000 {
+            map.insert(k, k);
+        }
+        let synced = map.version();
+        map.insert(1, 1);
+        // Only the subtree of the root holding the change has a stamp above the version.
+        let recent = map
+            .stamped()
+            .root
+            .entries
+            .iter()
+            .filter(|entry| match entry {
+                HAMTNodeEntry::Node(node) => *map.stamps.get(node).unwrap() > synced,
+                HAMTNodeEntry::Value(_, stamped, _) => stamped.version > synced,
+                HAMTNodeEntry::Chained(_) => unreachable!(),
+            })
+            .count();
+        assert_eq!(recent, 1);
+
+        // Nodes without stamps are searched rather than skipped.
+        map.stamps = NodeCache::new();
+        assert_eq!(map.changed_since(synced).unwrap(), vec![Modified::Set(&1, &1, synced + 1)]);
+    }
+
+    #[test]
+    fn tombstones_in_chains_and_purging() {
+        let mut map = StampedHAMT::<_, _, Config<4, u128>>::with_config();
+        for id in 0..10 {
+            map.insert(Colliding { bucket: 0, id }, id);
+        }
+        let synced = map.version();
+        map.remove(Colliding { bucket: 0, id: 3 });
+        assert_eq!(map.changed_since(synced).unwrap(), vec![Modified::Removed(&Colliding { bucket: 0, id: 3 }, 11)]);
+
+        map.insert(Colliding { bucket: 1, id: 0 }, 0);
+        map.purge_tombstones(11);
+        assert_eq!(map.changed_since(synced), None);
+        assert_eq!(map.changed_since(11).unwrap(), vec![Modified::Set(&Colliding { bucket: 1, id: 0 }, &0, 12)]);
+        assert_eq!(map.stamped().iter().count(), 10);
+        assert_eq!(map.len(), 10);
+    }
+}
@@ -0,0 +1,472 @@
+//! An embedded key-value store, that persists a map to an append-only file.
+//!
+//! Updating a map copies the nodes on the path to the updated entry and shares every other node with
+//! the previous version. A commit therefore only appends the nodes the store has not written yet,
+//! which are the copied path nodes, followed by a commit record pointing to the new root. Entries
+//! pointing to a child node refer to it by the offset of its record in the file.
+//!
+//! Layout, with integers in little endian:
+//!
+//! - header: the magic bytes `HAMTSTOR`, the format version (`u32`), the bits per level and bits of
+//!   the hash of the configuration, and the identifier of the hash function (`u8` each);
+//! - records, each a tag byte, the length of its payload (`u32`) and the payload:
+//!   - a node (`1`), encoded as in snapshots, with the offsets of its children;
+//!   - a commit (`2`): the offset of the root node (`u64`) and an FNV-1a checksum (`u64`) of the
+//!     bytes between the end of the previous commit and the start of this one.
+//!
+//! A commit that is cut short, or whose checksum does not match, ends the file: opening it recovers
+//! the last complete commit and truncates what follows.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hash;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::codec::{Codec, DecodeError};
+use crate::hasher::HASH_ID;
+use crate::snapshot::{checksum, decode_node, encode_node};
+use crate::{get_entries_index, Bitmap, DefaultConfig, HAMTNodeEntry, HamtConfig, HashWord, NodePtr, HAMT};
+
+const MAGIC: &[u8; 8] = b"HAMTSTOR";
+const VERSION: u32 = 3;
+const HEADER_LEN: u64 = 15;
+
+const RECORD_NODE: u8 = 1;
+const RECORD_COMMIT: u8 = 2;
+const RECORD_HEADER_LEN: usize = 5;
+
+/// A map persisted to an append-only file, one commit per update.
+///
+/// The store keeps the current version of the map in memory, along with the offsets in the file of
+/// its nodes, so that a commit writes only the nodes that changed.
+pub struct HamtStore<K, V, C: HamtConfig = DefaultConfig> {
+    path: PathBuf,
+    file: File,
+    /// Length of the file, which ends with the last commit.
+    len: u64,
+    map: HAMT<K, V, C>,
+    /// Offsets of the records of the nodes of `map`, by node address.
+    offsets: HashMap<usize, u64>,
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn address<K, V, C: HamtConfig>(node: &NodePtr<K, V, C>) -> usize {
+    node.as_ptr() as usize
+}
+
+fn header<C: HamtConfig>() -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    VERSION.encode(&mut bytes);
+    (C::BITS as u8).encode(&mut bytes);
+    (C::Hash::BITS as u8).encode(&mut bytes);
+    HASH_ID.encode(&mut bytes);
+    bytes
+}
+
+fn push_record(out: &mut Vec<u8>, tag: u8, payload: &[u8]) {
+    out.push(tag);
+    (payload.len() as u32).encode(out);
+    out.extend_from_slice(payload);
+}
+
+/// Rebuilds the nodes reachable from a root, from the node records of the file.
+struct Loader<'a, K, V, C: HamtConfig> {
+    bytes: &'a [u8],
+    records: HashMap<u64, Range<usize>>,
+    nodes: HashMap<u64, NodePtr<K, V, C>>,
+}
+
+impl<K: Codec, V: Codec, C: HamtConfig> Loader<'_, K, V, C> {
+    fn load(&mut self, offset: u64) -> Result<NodePtr<K, V, C>, DecodeError> {
+        if let Some(node) = self.nodes.get(&offset) {
+            return Ok(node.clone());
+        }
+        let range = self
+            .records
+            .get(&offset)
+            .ok_or(DecodeError::Invalid("entry points to a missing node"))?
+            .clone();
+        let bytes = self.bytes;
+        let mut input = &bytes[range];
+        let node = decode_node(&mut input, |child| {
+            // Children are written first, so this also rules out cycles.
+            if child >= offset {
+                return Err(DecodeError::Invalid("entry points to a node not written before it"));
+            }
+            self.load(child)
+        })?;
+        if !input.is_empty() {
+            return Err(DecodeError::Invalid("trailing bytes after a node"));
+        }
+        let node = NodePtr::new(node);
+        self.nodes.insert(offset, node.clone());
+        Ok(node)
+    }
+}
+
+impl<K: Codec, V: Codec, C: HamtConfig> HamtStore<K, V, C> {
+    /// Open the store at `path`, creating an empty one if the file does not exist.
+    ///
+    /// The map is the one of the last complete commit. Anything written after it, by a commit that was
+    /// interrupted, is truncated from the file.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        if bytes.is_empty() {
+            file.write_all(&header::<C>())?;
+            file.sync_data()?;
+            return Ok(HamtStore {
+                path,
+                file,
+                len: HEADER_LEN,
+                map: HAMT::with_config(),
+                offsets: HashMap::new(),
+            });
+        }
+        if bytes.len() < HEADER_LEN as usize || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid_data("not a store"));
+        }
+        if bytes[..HEADER_LEN as usize] != header::<C>()[..] {
+            return Err(invalid_data(
+                "store of another version, of maps with another configuration, or with keys hashed by another hash function",
+            ));
+        }
+
+        let mut records = HashMap::new();
+        let mut root = None;
+        let mut committed = HEADER_LEN as usize;
+        let mut input = &bytes[committed..];
+        while input.len() >= RECORD_HEADER_LEN {
+            let start = bytes.len() - input.len();
+            let tag = input[0];
+            let len = u32::decode(&mut &input[1..]).unwrap() as usize;
+            if input.len() - RECORD_HEADER_LEN < len {
+                break;
+            }
+            let payload = start + RECORD_HEADER_LEN..start + RECORD_HEADER_LEN + len;
+            input = &bytes[payload.end..];
+            match tag {
+                RECORD_NODE => {
+                    records.insert(start as u64, payload);
+                }
+                RECORD_COMMIT if len == 16 => {
+                    let mut commit = &bytes[payload.clone()];
+                    let offset = u64::decode(&mut commit).unwrap();
+                    if u64::decode(&mut commit).unwrap() != checksum(&bytes[committed..start]) {
+                        break;
+                    }
+                    root = Some(offset);
+                    committed = payload.end;
+                }
+                _ => break,
+            }
+        }
+        if committed < bytes.len() {
+            file.set_len(committed as u64)?;
+            file.sync_data()?;
+        }
+
+        let mut loader = Loader {
+            bytes: &bytes,
+            records,
+            nodes: HashMap::new(),
+        };
+        let map = match root {
+            Some(offset) => HAMT {
+                root: loader.load(offset).map_err(invalid_data)?,
+            },
+            None => HAMT::with_config(),
+        };
+        let offsets = loader.nodes.iter().map(|(offset, node)| (address(node), *offset)).collect();
+        Ok(HamtStore {
+            path,
+            file,
+            len: committed as u64,
+            map,
+            offsets,
+        })
+    }
+
+    /// The map of the last commit.
+    pub fn map(&self) -> &HAMT<K, V, C> {
+        &self.map
+    }
+
+    /// Path of the file of the store.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Commit `map` as the new version of the store, appending the nodes of `map` that are not in the
+    /// file yet. The commit is synced to disk before this returns.
+    pub fn commit(&mut self, map: HAMT<K, V, C>) -> io::Result<()> {
+        if NodePtr::ptr_eq(&map.root, &self.map.root) {
+            return Ok(());
+        }
+        let mut bytes = Vec::new();
+        let mut written = HashMap::new();
+        let mut kept = HashSet::new();
+        let root = self.append(&map.root, &mut bytes, &mut written, &mut kept);
+        let sum = checksum(&bytes);
+        let mut commit = Vec::with_capacity(16);
+        root.encode(&mut commit);
+        sum.encode(&mut commit);
+        push_record(&mut bytes, RECORD_COMMIT, &commit);
+
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()?;
+        self.len += bytes.len() as u64;
+
+        let old = std::mem::replace(&mut self.map, map);
+        self.forget(&old.root, Some(&self.map.root.clone()), &kept);
+        self.offsets.extend(written);
+        Ok(())
+    }
+
+    /// Append the records of `node` and of the nodes under it that are not in the file to `out`,
+    /// and return the offset of `node`. Nodes already in the file are added to `kept`.
+    fn append(
+        &self,
+        node: &NodePtr<K, V, C>,
+        out: &mut Vec<u8>,
+        written: &mut HashMap<usize, u64>,
+        kept: &mut HashSet<usize>,
+    ) -> u64 {
+        if let Some(offset) = self.offsets.get(&address(node)) {
+            kept.insert(address(node));
+            return *offset;
+        }
+        if let Some(offset) = written.get(&address(node)) {
+            return *offset;
+        }
+        let mut children = Vec::new();
+        for entry in &node.entries {
+            if let HAMTNodeEntry::Node(child) = entry {
+                children.push(self.append(child, out, written, kept));
+            }
+        }
+        let mut payload = Vec::new();
+        let mut children = children.into_iter();
+        encode_node(node, &mut payload, |_| children.next().unwrap());
+        let offset = self.len + out.len() as u64;
+        push_record(out, RECORD_NODE, &payload);
+        written.insert(address(node), offset);
+        offset
+    }
+
+    /// Forget the offsets of the nodes under `old` that are not in the new version, where `new` is the
+    /// node at the same place in the new version, if any.
+    fn forget(&mut self, old: &NodePtr<K, V, C>, new: Option<&NodePtr<K, V, C>>, kept: &HashSet<usize>) {
+        if new.is_some_and(|new| NodePtr::ptr_eq(old, new)) || kept.contains(&address(old)) {
+            return;
+        }
+        self.offsets.remove(&address(old));
+        let mut present = old.presence_map.to_u64();
+        for entry in &old.entries {
+            let frag = present.trailing_zeros();
+            present &= present - 1;
+            if let HAMTNodeEntry::Node(child) = entry {
+                let same_place = new.and_then(|new| match new.presence_map.contains(frag) {
+                    true => match &new.entries[get_entries_index(new.presence_map, frag)] {
+                        HAMTNodeEntry::Node(new_child) => Some(new_child),
+                        _ => None,
+                    },
+                    false => None,
+                });
+                self.forget(child, same_place, kept);
+            }
+        }
+    }
+
+    /// Rewrite the file with only the nodes of the current map, dropping the nodes of older versions.
+    ///
+    /// The new file is written next to the old one and renamed over it once synced, so the store is
+    /// never left without a complete commit.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let mut bytes = header::<C>();
+        let mut offsets = HashMap::new();
+        let root = compact_node(&self.map.root, &mut bytes, &mut offsets);
+        let mut commit = Vec::with_capacity(16);
+        root.encode(&mut commit);
+        checksum(&bytes[HEADER_LEN as usize..]).encode(&mut commit);
+        push_record(&mut bytes, RECORD_COMMIT, &commit);
+
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".compact");
+        let temp = self.path.with_file_name(name);
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&temp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)?;
+
+        self.file = file;
+        self.len = bytes.len() as u64;
+        self.offsets = offsets;
+        Ok(())
+    }
+}
+
+impl<K, V, C> HamtStore<K, V, C>
+where
+    K: Codec + Eq + Hash + Clone,
+    V: Codec + Clone,
+    C: HamtConfig,
+{
+    /// Insert `key` and `value`, and commit the new version.
+    pub fn insert(&mut self, key: K, value: V) -> io::Result<()> {
+        self.commit(self.map.insert(key, value))
+    }
+
+    /// Remove `key`, if it is present, and commit the new version.
+    pub fn remove(&mut self, key: K) -> io::Result<()> {
+        self.commit(self.map.remove(key))
+    }
+}
+
+/// Append the records of `node` and the nodes under it to `out`, a whole file, and return the offset of `node`.
+fn compact_node<K: Codec, V: Codec, C: HamtConfig>(
+    node: &NodePtr<K, V, C>,
+    out: &mut Vec<u8>,
+    offsets: &mut HashMap<usize, u64>,
+) -> u64 {
+    if let Some(offset) = offsets.get(&address(node)) {
+        return *offset;
+    }
+    let children: Vec<u64> = node
+        .entries
+        .iter()
+        .filter_map(|entry| match entry {
+            HAMTNodeEntry::Node(child) => Some(compact_node(child, out, offsets)),
+            _ => None,
+        })
+        .collect();
+    let mut payload = Vec::new();
+    let mut children = children.into_iter();
+    encode_node(node, &mut payload, |_| children.next().unwrap());
+    let offset = out.len() as u64;
+    push_record(out, RECORD_NODE, &payload);
+    offsets.insert(address(node), offset);
+    offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HamtStore;
+    use std::fs::{self, OpenOptions};
+    use std::io::{self, Write};
+    use std::path::PathBuf;
+
+    /// A path in the temporary directory for the test `name`, with no file at it.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hamster-store-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn commits_survive_reopening() {
+        let path = temp_path("reopen");
+        let mut store = HamtStore::<i32, String>::open(&path).unwrap();
+        assert_eq!(store.map().iter().count(), 0);
+        for k in 0..1000 {
+            store.insert(k, format!("v{}", k)).unwrap();
+        }
+        for k in (0..1000).step_by(3) {
+            store.remove(k).unwrap();
+        }
+        let map = store.map().clone();
+        drop(store);
+
+        let mut store = HamtStore::<i32, String>::open(&path).unwrap();
+        assert_eq!(store.map(), &map);
+        store.insert(0, "again".to_string()).unwrap();
+        assert_eq!(store.map().get(0), Some(&"again".to_string()));
+        drop(store);
+        assert_eq!(HamtStore::<i32, String>::open(&path).unwrap().map(), &map.insert(0, "again".to_string()));
+
+        // A store whose keys were hashed by another hash function is not opened.
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[14] = 0;
+        fs::write(&path, &bytes).unwrap();
+        let error = HamtStore::<i32, String>::open(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn commits_append_only_the_path() {
+        let path = temp_path("path");
+        let mut store = HamtStore::<u32, u32>::open(&path).unwrap();
+        store.commit((0..10_000).fold(store.map().clone(), |map, k| map.insert(k, k))).unwrap();
+        let before = fs::metadata(&path).unwrap().len();
+        store.insert(5, 6).unwrap();
+        let grown = fs::metadata(&path).unwrap().len() - before;
+        // One node per level of the path, each of at most 32 entries of 9 bytes, and the commit.
+        assert!(grown < (store.map().height() as u64 + 1) * 300, "commit of {} bytes", grown);
+        assert_eq!(HamtStore::<u32, u32>::open(&path).unwrap().map(), store.map());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_is_ignored() {
+        let path = temp_path("torn");
+        let mut store = HamtStore::<i32, i32>::open(&path).unwrap();
+        for k in 0..100 {
+            store.insert(k, k).unwrap();
+        }
+        let map = store.map().clone();
+        let len = fs::metadata(&path).unwrap().len();
+        store.insert(100, 100).unwrap();
+        drop(store);
+
+        // Cut the last commit at every length: the previous one is recovered each time.
+        let full = fs::read(&path).unwrap();
+        for cut in len as usize..full.len() {
+            fs::write(&path, &full[..cut]).unwrap();
+            let store = HamtStore::<i32, i32>::open(&path).unwrap();
+            assert_eq!(store.map(), &map);
+            assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        }
+
+        // Garbage after a complete commit is dropped too, and new commits follow the recovered one.
+        fs::write(&path, &full).unwrap();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[1, 200, 0, 0, 0, 7]).unwrap();
+        let mut store = HamtStore::<i32, i32>::open(&path).unwrap();
+        assert_eq!(store.map(), &map.insert(100, 100));
+        store.insert(101, 101).unwrap();
+        drop(store);
+        assert_eq!(HamtStore::<i32, i32>::open(&path).unwrap().map().get(101), Some(&101));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact_keeps_only_live_nodes() {
+        let path = temp_path("compact");
+        let mut store = HamtStore::<i32, i32>::open(&path).unwrap();
+        for round in 0..5 {
+            for k in 0..500 {
+                store.insert(k, k * round).unwrap();
+            }
+        }
+        let before = fs::metadata(&path).unwrap().len();
+        store.compact().unwrap();
+        let after = fs::metadata(&path).unwrap().len();
+        assert!(after * 20 < before, "{} bytes compacted to {}", before, after);
+
+        store.remove(0).unwrap();
+        let map = store.map().clone();
+        drop(store);
+        let store = HamtStore::<i32, i32>::open(&path).unwrap();
+        assert_eq!(store.map(), &map);
+        assert_eq!(store.map().get(499), Some(&(499 * 4)));
+        fs::remove_file(&path).unwrap();
+    }
+}
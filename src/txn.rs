@@ -0,0 +1,289 @@
+//! Speculative updates of a map, committed at once or discarded.
+//!
+//! A [`Txn`] keeps its writes in an overlay on top of the map it started from, which is itself a
+//! map from keys to `Some(value)` for an insertion or `None` for a removal. Reads look in the overlay
+//! first. Since the overlay is persistent, a savepoint is a copy of its root, and rolling back to it
+//! or discarding the transaction costs nothing.
+//!
+//! Committing applies all the writes in one pass over the trie: the writes are grouped by the entry
+//! they fall in at each level, so every node on their paths is copied once, instead of once per write.
+
+use std::hash::Hash;
+
+use crate::{build_entry, fragment, hash_key, Bitmap, DefaultConfig, HAMTNode, HAMTNodeEntry, HamtConfig, HashWord, NodePtr, HAMT};
+
+/// Writes to a map that are not committed yet, returned by [`HAMT::transaction`].
+pub struct Txn<K, V, C: HamtConfig = DefaultConfig> {
+    base: HAMT<K, V, C>,
+    /// The value written for each key, or `None` for a removal.
+    writes: HAMT<K, Option<V>, C>,
+    savepoints: Vec<HAMT<K, Option<V>, C>>,
+}
+
+/// A point to roll the writes of a transaction back to, returned by [`Txn::savepoint`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Savepoint(usize);
+
+/// A write to apply, with the full hash of its key.
+type Write<K, V, C> = (<C as HamtConfig>::Hash, K, Option<V>);
+
+/// Apply the writes, whose keys are distinct and fall under `node`, where `level` is the level of the node.
+fn apply_node<K, V, C>(node: &NodePtr<K, V, C>, mut writes: Vec<Write<K, V, C>>, level: u32) -> NodePtr<K, V, C>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    C: HamtConfig,
+{
+    if writes.is_empty() {
+        return node.clone();
+    }
+    let frag_at = |hash: C::Hash| fragment::<C>(hash.shift(C::BITS * level));
+    // The sort is stable, so writes keep their relative order within each entry.
+    writes.sort_by_key(|(hash, _, _)| frag_at(*hash));
+
+    let mut new_node: HAMTNode<K, V, C> = HAMTNode {
+        presence_map: C::Bitmap::EMPTY,
+        entries: Vec::with_capacity(node.entries.len() + writes.len()),
+    };
+    let mut present = node.presence_map.to_u64();
+    let mut entries = node.entries.iter();
+    let mut writes = writes.into_iter().peekable();
+    loop {
+        let next_write = writes.peek().map(|(hash, _, _)| frag_at(*hash));
+        let next_entry = (present != 0).then(|| present.trailing_zeros());
+        let frag = match (next_entry, next_write) {
+            (Some(a), Some(b)) => a.min(b),
+            (Some(frag), None) | (None, Some(frag)) => frag,
+            (None, None) => break,
+        };
+        let entry = if next_entry == Some(frag) {
+            present &= present - 1;
+            entries.next()
+        } else {
+            None
+        };
+        let mut group = Vec::new();
+        while let Some(write) = writes.next_if(|(hash, _, _)| frag_at(*hash) == frag) {
+            group.push(write);
+        }
+        let updated = match entry {
+            Some(entry) if group.is_empty() => Some(entry.clone()),
+            Some(HAMTNodeEntry::Node(child)) => {
+                let child = apply_node(child, group, level + 1);
+                (!child.entries.is_empty()).then(|| HAMTNodeEntry::Node(child))
+            }
+            entry => {
+                // Rebuild the entry from the keys it stores that were not written, and the new values.
+                // A chain only meets writes of the same hash, as it is below every fragment of it.
+                let mut items = Vec::new();
+                let mut keep = |hash: C::Hash, k: &K, v: &V| {
+                    if !group.iter().any(|(_, key, _)| key == k) {
+                        items.push((hash, k.clone(), v.clone()));
+                    }
+                };
+                match entry {
+                    Some(HAMTNodeEntry::Value(k, v, hash)) => keep(*hash, k, v),
+                    Some(HAMTNodeEntry::Chained(vec)) => vec.iter().for_each(|(k, v)| keep(group[0].0, k, v)),
+                    _ => {}
+                }
+                items.extend(group.into_iter().filter_map(|(hash, k, v)| v.map(|v| (hash, k, v))));
+                (!items.is_empty()).then(|| build_entry(items, level + 1))
+            }
+        };
+        if let Some(updated) = updated {
+            new_node.presence_map = new_node.presence_map.with(frag);
+            new_node.entries.push(updated);
+        }
+    }
+    NodePtr::new(new_node)
+}
+
+impl<K, V, C: HamtConfig> HAMT<K, V, C> {
+    /// Start a transaction on top of this map.
+    pub fn transaction(&self) -> Txn<K, V, C> {
+        Txn {
+            base: self.clone(),
+            writes: HAMT::with_config(),
+            savepoints: Vec::new(),
+        }
+    }
+}
+
+impl<K, V, C: HamtConfig> Txn<K, V, C> {
+    /// The map the transaction started from.
+    pub fn base(&self) -> &HAMT<K, V, C> {
+        &self.base
+    }
+
+    /// Discard the writes, and return the map the transaction started from.
+    pub fn rollback(self) -> HAMT<K, V, C> {
+        self.base
+    }
+
+    /// Mark the current writes, to roll back to them later. Savepoints nest: rolling back to a
+    /// savepoint, or releasing it, also releases the savepoints made after it.
+    pub fn savepoint(&mut self) -> Savepoint {
+        self.savepoints.push(self.writes.clone());
+        Savepoint(self.savepoints.len() - 1)
+    }
+
+    /// Discard the writes made since `savepoint`, and release it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the savepoint was released.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        assert!(savepoint.0 < self.savepoints.len(), "the savepoint was released");
+        self.savepoints.truncate(savepoint.0 + 1);
+        self.writes = self.savepoints.pop().unwrap();
+    }
+
+    /// Release `savepoint`, keeping the writes made since.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the savepoint was released.
+    pub fn release(&mut self, savepoint: Savepoint) {
+        assert!(savepoint.0 < self.savepoints.len(), "the savepoint was released");
+        self.savepoints.truncate(savepoint.0);
+    }
+}
+
+impl<K, V, C> Txn<K, V, C>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    C: HamtConfig,
+{
+    /// Get the value of `key`, with the writes of the transaction.
+    pub fn get(&self, key: K) -> Option<&V> {
+        match self.writes.get(key.clone()) {
+            Some(written) => written.as_ref(),
+            None => self.base.get(key),
+        }
+    }
+
+    /// Check if `key` has a value, with the writes of the transaction.
+    pub fn contains_key(&self, key: K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Write `value` for `key`.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.writes = self.writes.insert(key, Some(value));
+    }
+
+    /// Remove `key`.
+    pub fn remove(&mut self, key: K) {
+        self.writes = self.writes.insert(key, None);
+    }
+
+    /// Apply the writes to the map the transaction started from, and return the new map.
+    /// Subtrees without writes are shared with the map the transaction started from.
+    pub fn commit(self) -> HAMT<K, V, C> {
+        let writes = self
+            .writes
+            .iter()
+            .map(|(k, v)| (hash_key::<C, K>(k), k.clone(), v.clone()))
+            .collect();
+        HAMT {
+            root: apply_node(&self.base.root, writes, 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{build, Colliding};
+    use crate::{Config, HAMTNodeEntry, NodePtr, HAMT};
+
+    #[test]
+    fn commit_matches_sequential_writes() {
+        let base = build(0..5000);
+        let mut txn = base.transaction();
+        let mut expected = base.clone();
+        for k in (0..10_000).step_by(7) {
+            txn.insert(k, -k);
+            expected = expected.insert(k, -k);
+        }
+        for k in (0..10_000).step_by(5) {
+            txn.remove(k);
+            expected = expected.remove(k);
+        }
+        assert_eq!(txn.get(7), Some(&-7));
+        assert_eq!(txn.get(35), None);
+        assert_eq!(txn.get(1), Some(&1));
+        assert!(txn.contains_key(9996));
+        let committed = txn.commit();
+        assert_eq!(committed, expected);
+
+        // Colliding keys end up in chains, which are rebuilt from the writes too.
+        let base = (0..20).fold(HAMT::<_, _, Config<4, u128>>::with_config(), |map, id| {
+            map.insert(Colliding { bucket: (id % 3) as u8, id }, id)
+        });
+        let mut txn = base.transaction();
+        let mut expected = base.clone();
+        for id in 10..30 {
+            let key = Colliding { bucket: (id % 4) as u8, id };
+            txn.insert(key.clone(), -id);
+            expected = expected.insert(key, -id);
+        }
+        for id in 0..20 {
+            let key = Colliding { bucket: (id % 3) as u8, id };
+            if id % 2 == 0 {
+                txn.remove(key.clone());
+                expected = expected.remove(key);
+            }
+        }
+        assert_eq!(txn.commit(), expected);
+    }
+
+    #[test]
+    fn nested_savepoints() {
+        let base = HAMT::new().insert("a", 1);
+        let mut txn = base.transaction();
+        txn.insert("b", 2);
+        let outer = txn.savepoint();
+        txn.insert("c", 3);
+        let inner = txn.savepoint();
+        txn.remove("a");
+        assert_eq!(txn.get("a"), None);
+        txn.rollback_to(inner);
+        assert_eq!(txn.get("a"), Some(&1));
+        assert_eq!(txn.get("c"), Some(&3));
+        let again = txn.savepoint();
+        txn.insert("d", 4);
+        txn.release(again);
+        txn.rollback_to(outer);
+        assert_eq!(txn.get("c"), None);
+        assert_eq!(txn.get("d"), None);
+        assert_eq!(txn.commit(), HAMT::new().insert("a", 1).insert("b", 2));
+    }
+
+    #[test]
+    fn rollback_and_sharing() {
+        let base = build(0..5000);
+        let mut txn = base.transaction();
+        txn.insert(0, 0);
+        assert!(txn.rollback().ptr_eq(&base));
+
+        let mut txn = base.transaction();
+        txn.insert(1, -1);
+        txn.insert(2, -2);
+        let committed = txn.commit();
+        let shared = base
+            .root
+            .entries
+            .iter()
+            .zip(committed.root.entries.iter())
+            .filter(|pair| match pair {
+                (HAMTNodeEntry::Node(a), HAMTNodeEntry::Node(b)) => NodePtr::ptr_eq(a, b),
+                _ => false,
+            })
+            .count();
+        assert!(shared >= base.root.entries.len() - 2);
+        assert_eq!(committed.get(1), Some(&-1));
+        assert_eq!(committed.get(3), Some(&3));
+        assert_eq!(base.transaction().commit().get(3), Some(&3));
+    }
+}
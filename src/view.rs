@@ -0,0 +1,226 @@
+//! A read-only view of the structure of a map.
+//!
+//! The nodes of a map are private, so that the invariants of the trie are kept. [`NodeRef`] and
+//! [`EntryRef`] borrow them to let other code walk the trie, e.g. to serialize, draw or merge maps
+//! in its own way, without being able to modify it.
+
+use std::fmt;
+use std::iter::FusedIterator;
+use std::slice;
+
+use crate::{get_entries_index, Bitmap, HAMTNodeEntry, HamtConfig, NodePtr, HAMT};
+
+/// A borrowed internal node of a map, returned by [`HAMT::root_view`].
+pub struct NodeRef<'a, K, V, C: HamtConfig> {
+    node: &'a NodePtr<K, V, C>,
+    level: u32,
+}
+
+/// A borrowed entry of a node.
+pub enum EntryRef<'a, K, V, C: HamtConfig> {
+    /// A single key and value.
+    Value(&'a K, &'a V),
+    /// Keys whose hashes are equal, with their values, in the order they were inserted in.
+    Chained(&'a [(K, V)]),
+    /// A child node, one level down.
+    Node(NodeRef<'a, K, V, C>),
+}
+
+/// The identity of a node, which is the same for every map sharing the node.
+/// It is only meaningful while the node is alive: once it is dropped, another node may get the same identity.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+
+impl<'a, K, V, C: HamtConfig> NodeRef<'a, K, V, C> {
+    pub(crate) fn new(node: &'a NodePtr<K, V, C>, level: u32) -> Self {
+        NodeRef { node, level }
+    }
+
+    pub(crate) fn node(&self) -> &'a NodePtr<K, V, C> {
+        self.node
+    }
+
+    /// Depth of the node in the trie, the root being at level 0.
+    /// The entries of the node are indexed by the fragment of the hash at this level.
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    /// The presence map of the node, with a bit set for each fragment that has an entry.
+    pub fn bitmap(&self) -> C::Bitmap {
+        self.node.presence_map
+    }
+
+    /// Number of entries of the node.
+    pub fn len(&self) -> usize {
+        self.node.entries.len()
+    }
+
+    /// Check if the node has no entries, which only happens for the root of an empty map.
+    pub fn is_empty(&self) -> bool {
+        self.node.entries.is_empty()
+    }
+
+    /// The entry for `frag`, if any.
+    pub fn entry(&self, frag: u32) -> Option<EntryRef<'a, K, V, C>> {
+        let node: &'a NodePtr<K, V, C> = self.node;
+        (frag < 1 << C::BITS && node.presence_map.contains(frag))
+            .then(|| EntryRef::new(&node.entries[get_entries_index(node.presence_map, frag)], self.level))
+    }
+
+    /// Iterate over the entries of the node, in the order of their fragments.
+    pub fn children(&self) -> Children<'a, K, V, C> {
+        Children {
+            entries: self.node.entries.iter(),
+            level: self.level,
+        }
+    }
+
+    /// Check if both views are of the same node.
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        NodePtr::ptr_eq(a.node, b.node)
+    }
+
+    /// The identity of the node.
+    pub fn id(&self) -> NodeId {
+        NodeId(self.node.as_ptr() as usize)
+    }
+}
+
+impl<'a, K, V, C: HamtConfig> EntryRef<'a, K, V, C> {
+    pub(crate) fn new(entry: &'a HAMTNodeEntry<K, V, C>, level: u32) -> Self {
+        match entry {
+            HAMTNodeEntry::Value(k, v, _) => EntryRef::Value(k, v),
+            HAMTNodeEntry::Chained(vec) => EntryRef::Chained(vec),
+            HAMTNodeEntry::Node(node) => EntryRef::Node(NodeRef::new(node, level + 1)),
+        }
+    }
+}
+
+impl<K, V, C: HamtConfig> Clone for NodeRef<'_, K, V, C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V, C: HamtConfig> Copy for NodeRef<'_, K, V, C> {}
+
+impl<K, V, C: HamtConfig> Clone for EntryRef<'_, K, V, C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V, C: HamtConfig> Copy for EntryRef<'_, K, V, C> {}
+
+impl<K, V, C: HamtConfig> fmt::Debug for NodeRef<'_, K, V, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NodeRef")
+            .field("id", &self.id())
+            .field("level", &self.level)
+            .field("bitmap", &self.bitmap())
+            .finish()
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, C: HamtConfig> fmt::Debug for EntryRef<'_, K, V, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryRef::Value(k, v) => f.debug_tuple("Value").field(k).field(v).finish(),
+            EntryRef::Chained(vec) => f.debug_tuple("Chained").field(vec).finish(),
+            EntryRef::Node(node) => f.debug_tuple("Node").field(node).finish(),
+        }
+    }
+}
+
+/// An iterator over the entries of a node, returned by [`NodeRef::children`].
+pub struct Children<'a, K, V, C: HamtConfig> {
+    entries: slice::Iter<'a, HAMTNodeEntry<K, V, C>>,
+    level: u32,
+}
+
+impl<'a, K, V, C: HamtConfig> Iterator for Children<'a, K, V, C> {
+    type Item = EntryRef<'a, K, V, C>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next().map(|entry| EntryRef::new(entry, self.level))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl<K, V, C: HamtConfig> ExactSizeIterator for Children<'_, K, V, C> {}
+
+impl<K, V, C: HamtConfig> FusedIterator for Children<'_, K, V, C> {}
+
+impl<K, V, C: HamtConfig> HAMT<K, V, C> {
+    /// A read-only view of the root node of the map.
+    pub fn root_view(&self) -> NodeRef<'_, K, V, C> {
+        NodeRef::new(&self.root, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EntryRef, NodeId, NodeRef};
+    use crate::{Bitmap, DefaultConfig, HAMT};
+    use crate::test_util::build;
+    use std::collections::HashSet;
+
+    /// Count the entries under a node, and collect the identities of the nodes.
+    fn walk(node: NodeRef<'_, i32, i32, DefaultConfig>, ids: &mut HashSet<NodeId>) -> usize {
+        assert!(ids.insert(node.id()));
+        assert_eq!(node.bitmap().count() as usize, node.len());
+        node.children()
+            .map(|entry| match entry {
+                EntryRef::Value(..) => 1,
+                EntryRef::Chained(vec) => vec.len(),
+                EntryRef::Node(child) => {
+                    assert_eq!(child.level(), node.level() + 1);
+                    walk(child, ids)
+                }
+            })
+            .sum()
+    }
+
+    #[test]
+    fn walk_the_trie() {
+        let map = build(0..2000);
+        let mut ids = HashSet::new();
+        assert_eq!(walk(map.root_view(), &mut ids), 2000);
+        assert!(ids.len() > 1);
+        assert!(HAMT::<i32, i32>::new().root_view().is_empty());
+
+        let root = map.root_view();
+        let mut present = root.bitmap().to_u64();
+        for entry in root.children() {
+            let frag = present.trailing_zeros();
+            present &= present - 1;
+            match (entry, root.entry(frag).unwrap()) {
+                (EntryRef::Node(a), EntryRef::Node(b)) => assert!(NodeRef::ptr_eq(&a, &b)),
+                (entry, _) => panic!("unexpected entry {:?}", entry),
+            }
+        }
+        assert!(root.entry(u32::MAX).is_none());
+    }
+
+    #[test]
+    fn shared_nodes_have_the_same_identity() {
+        let map = build(0..2000);
+        let updated = map.insert(0, 1);
+        assert!(!NodeRef::ptr_eq(&map.root_view(), &updated.root_view()));
+        assert_ne!(map.root_view().id(), updated.root_view().id());
+        let shared = map
+            .root_view()
+            .children()
+            .zip(updated.root_view().children())
+            .filter(|pair| match pair {
+                (EntryRef::Node(a), EntryRef::Node(b)) => a.id() == b.id(),
+                _ => false,
+            })
+            .count();
+        assert_eq!(shared, map.root_view().len() - 1);
+    }
+}
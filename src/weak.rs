@@ -0,0 +1,109 @@
+//! Handles to versions of a map that do not keep them alive.
+//!
+//! A [`WeakHAMT`] holds a weak pointer to the root of a map, so the nodes of the map are freed once
+//! no map uses them, and the handle can then no longer be upgraded. This suits caches that keep old
+//! versions in case they are needed again, without pinning every node they reference.
+
+use std::fmt;
+
+use crate::config::WeakNode;
+use crate::{DefaultConfig, HamtConfig, NodePtr, SharedPointer, HAMT};
+
+/// A version of a map that does not keep it alive, returned by [`HAMT::downgrade`].
+pub struct WeakHAMT<K, V, C: HamtConfig = DefaultConfig> {
+    root: WeakNode<K, V, C>,
+}
+
+impl<K, V, C: HamtConfig> HAMT<K, V, C> {
+    /// Make a handle to this version of the map that does not keep its nodes alive.
+    pub fn downgrade(&self) -> WeakHAMT<K, V, C> {
+        WeakHAMT {
+            root: C::Pointer::downgrade(&self.root.0),
+        }
+    }
+}
+
+impl<K, V, C: HamtConfig> WeakHAMT<K, V, C> {
+    /// Get the map back, unless every map using its root was dropped.
+    pub fn upgrade(&self) -> Option<HAMT<K, V, C>> {
+        C::Pointer::upgrade(&self.root).map(|root| HAMT { root: NodePtr(root) })
+    }
+
+    /// Check if the map is still alive, so that [`upgrade`](WeakHAMT::upgrade) would succeed.
+    pub fn is_alive(&self) -> bool {
+        C::Pointer::strong_count(&self.root) > 0
+    }
+
+    /// Check if both handles point to the same version of a map, alive or not. Like
+    /// [`HAMT::ptr_eq`], this compares the roots, so equal maps built apart are different versions.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        C::Pointer::weak_ptr_eq(&self.root, &other.root)
+    }
+}
+
+// Cloning a handle only clones the weak pointer, so it places no constraint on the keys and values.
+impl<K, V, C: HamtConfig> Clone for WeakHAMT<K, V, C> {
+    fn clone(&self) -> Self {
+        WeakHAMT { root: self.root.clone() }
+    }
+}
+
+impl<K, V, C: HamtConfig> fmt::Debug for WeakHAMT<K, V, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WeakHAMT").field("alive", &self.is_alive()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::HAMT;
+    use crate::test_util::build;
+    use std::rc::Rc;
+
+    #[test]
+    fn upgrade_while_alive() {
+        let map = build(0..1000);
+        let weak = map.downgrade();
+        assert!(weak.is_alive());
+        let upgraded = weak.upgrade().unwrap();
+        assert!(upgraded.ptr_eq(&map));
+        assert_eq!(upgraded.get(7), Some(&7));
+        drop(map);
+        // The upgraded map keeps the version alive on its own.
+        assert!(weak.upgrade().is_some());
+        drop(upgraded);
+        assert!(!weak.is_alive());
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn weak_handles_do_not_keep_values_alive() {
+        let value = Rc::new(0);
+        let old = Rc::downgrade(&value);
+        let map = HAMT::new().insert(0, value).insert(1, Rc::new(1));
+        let weak = map.downgrade();
+        // A newer version shares the nodes it did not copy, but not the replaced value.
+        let newer = map.insert(0, Rc::new(2));
+        drop(map);
+        assert!(weak.upgrade().is_none());
+        assert!(old.upgrade().is_none());
+        assert_eq!(**newer.get(1).unwrap(), 1);
+    }
+
+    #[test]
+    fn same_version() {
+        let map = HAMT::new().insert("a", 1);
+        let same = map.clone();
+        let other = map.insert("b", 2);
+        let equal = HAMT::new().insert("a", 1);
+        assert!(map.downgrade().ptr_eq(&same.downgrade()));
+        assert!(!map.downgrade().ptr_eq(&other.downgrade()));
+        assert!(!map.downgrade().ptr_eq(&equal.downgrade()));
+
+        // Handles still compare after the version is dropped.
+        let (a, b) = (other.downgrade(), other.downgrade());
+        drop(other);
+        assert!(a.ptr_eq(&b.clone()));
+        assert!(!a.ptr_eq(&map.downgrade()));
+    }
+}
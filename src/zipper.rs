@@ -0,0 +1,306 @@
+//! Navigating and editing the nodes of a map in place.
+//!
+//! A [`Zipper`] focuses on one node of the trie, and remembers the path from the root to it.
+//! Edits at the focus only copy the nodes below it: the nodes on the path are rebuilt once, when
+//! the zipper moves up past them or is closed, and only if something below them was modified.
+
+use std::hash::Hash;
+
+use crate::view::{EntryRef, NodeRef};
+use crate::{
+    get_entries_index, hash_key, insert_at_node, pool, remove_at_node, Bitmap, DefaultConfig, HAMTNode, HAMTNodeEntry,
+    HamtConfig, HashWord, NodePtr, HAMT,
+};
+
+/// A node on the path from the root to the focus, and the fragment of the entry leading down from it.
+struct Frame<K, V, C: HamtConfig> {
+    node: NodePtr<K, V, C>,
+    modified: bool,
+    frag: u32,
+}
+
+/// A cursor over the nodes of a [`HAMT`](HAMT), returned by [`HAMT::zipper`], that can move between
+/// them and edit the subtree under the focus. [`close`](Zipper::close) returns the edited map.
+pub struct Zipper<K, V, C: HamtConfig = DefaultConfig> {
+    /// The nodes from the root down to the parent of the focus.
+    path: Vec<Frame<K, V, C>>,
+    /// The subtree under the focus, as a map whose root is the focus.
+    focus: HAMT<K, V, C>,
+    /// Whether the subtree under the focus differs from the one in the map.
+    modified: bool,
+}
+
+impl<K, V, C: HamtConfig> Zipper<K, V, C> {
+    /// Depth of the focus in the trie, the root being at level 0.
+    pub fn level(&self) -> u32 {
+        self.path.len() as u32
+    }
+
+    /// The fragments of the entries leading from the root to the focus.
+    pub fn path(&self) -> Vec<u32> {
+        self.path.iter().map(|frame| frame.frag).collect()
+    }
+
+    /// A read-only view of the focus.
+    pub fn focus(&self) -> NodeRef<'_, K, V, C> {
+        NodeRef::new(&self.focus.root, self.level())
+    }
+
+    /// The presence map of the focus, with a bit set for each fragment that has an entry.
+    pub fn presence_map(&self) -> C::Bitmap {
+        self.focus.root.presence_map
+    }
+
+    /// The entry of the focus for `frag`, if any.
+    pub fn entry(&self, frag: u32) -> Option<EntryRef<'_, K, V, C>> {
+        self.focus().entry(frag)
+    }
+
+    /// The entries of the focus with their fragments, in order.
+    pub fn entries(&self) -> impl Iterator<Item = (u32, EntryRef<'_, K, V, C>)> + '_ {
+        let mut present = self.focus.root.presence_map.to_u64();
+        self.focus().children().map(move |entry| {
+            let frag = present.trailing_zeros();
+            present &= present - 1;
+            (frag, entry)
+        })
+    }
+}
+
+impl<K: Clone, V: Clone, C: HamtConfig> Zipper<K, V, C> {
+    /// Move the focus down to the child node of the entry for `frag`.
+    /// Returns `false`, and stays in place, if that entry is not a node.
+    pub fn down(&mut self, frag: u32) -> bool {
+        let child = match entry_at(&self.focus.root, frag) {
+            Some(HAMTNodeEntry::Node(child)) => child.clone(),
+            _ => return false,
+        };
+        let parent = std::mem::replace(&mut self.focus.root, child);
+        self.path.push(Frame {
+            node: parent,
+            modified: self.modified,
+            frag,
+        });
+        self.modified = false;
+        true
+    }
+
+    /// Move the focus up to its parent, writing the edits made below into it.
+    /// Returns `false` at the root.
+    pub fn up(&mut self) -> bool {
+        let frame = match self.path.pop() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let child = std::mem::replace(&mut self.focus.root, frame.node);
+        if self.modified {
+            self.focus.root = NodePtr::new(replace_child(&self.focus.root, frame.frag, child));
+        } else {
+            self.modified = frame.modified;
+        }
+        true
+    }
+
+    /// Move the focus across to the sibling node under the entry for `frag` of the parent.
+    /// Returns `false`, and stays in place, at the root or if that entry is not a node.
+    pub fn sibling(&mut self, frag: u32) -> bool {
+        match self.path.last() {
+            Some(parent) if matches!(entry_at(&parent.node, frag), Some(HAMTNodeEntry::Node(_))) => {
+                self.up();
+                self.down(frag)
+            }
+            _ => false,
+        }
+    }
+
+    /// Move the focus across to the next sibling node, in fragment order.
+    /// Returns `false`, and stays in place, if there is none.
+    pub fn next_sibling(&mut self) -> bool {
+        match self.path.last() {
+            Some(frame) => (frame.frag + 1..1 << C::BITS).any(|frag| self.sibling(frag)),
+            None => false,
+        }
+    }
+
+    /// Move the focus across to the previous sibling node, in fragment order.
+    /// Returns `false`, and stays in place, if there is none.
+    pub fn prev_sibling(&mut self) -> bool {
+        match self.path.last() {
+            Some(frame) => (0..frame.frag).rev().any(|frag| self.sibling(frag)),
+            None => false,
+        }
+    }
+
+    /// Move up to the root and return the edited map. Only the nodes on the paths to edits are rebuilt.
+    pub fn close(mut self) -> HAMT<K, V, C> {
+        while self.up() {}
+        self.focus
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, C: HamtConfig> Zipper<K, V, C> {
+    /// Get the value of `key` in the subtree under the focus.
+    pub fn get(&self, key: K) -> Option<&V> {
+        // Looking up from the focus only depends on the fragments left in the hash.
+        let hash = self.local_hash(&key)?;
+        self.focus.get_hashed(hash, |k| *k == key).map(|(_, v)| v)
+    }
+
+    /// Insert `key` in the subtree under the focus. Only the nodes below the focus are copied.
+    ///
+    /// Panics if the hash of `key` does not lead from the root to the focus.
+    pub fn insert(&mut self, key: K, value: V) {
+        let hash = self.full_hash(&key).expect("the key does not belong under the focus");
+        self.focus.root = NodePtr::new(insert_at_node(&self.focus.root, key, hash, value, self.level()));
+        self.modified = true;
+    }
+
+    /// Remove `key` from the subtree under the focus, if it is there.
+    /// Only the nodes below the focus are copied.
+    pub fn remove(&mut self, key: K) {
+        if let Some(hash) = self.full_hash(&key) {
+            let focus = remove_at_node(self.focus.root.clone(), &|k: &K| *k == key, hash, self.level());
+            if !NodePtr::ptr_eq(&focus, &self.focus.root) {
+                self.focus.root = focus;
+                self.modified = true;
+            }
+        }
+    }
+
+    /// The hash of `key` with the fragments of the path to the focus consumed,
+    /// or `None` if the key cannot be stored under the focus.
+    fn local_hash(&self, key: &K) -> Option<C::Hash> {
+        self.full_hash(key).map(|hash| hash.shift(C::BITS * self.level()))
+    }
+
+    /// The hash of `key`, or `None` if its fragments do not lead from the root to the focus.
+    fn full_hash(&self, key: &K) -> Option<C::Hash> {
+        let hash = hash_key::<C, K>(key);
+        let mut rest = hash;
+        for frame in &self.path {
+            if rest.fragment(C::BITS) != frame.frag {
+                return None;
+            }
+            rest = rest.shift(C::BITS);
+        }
+        Some(hash)
+    }
+}
+
+/// The entry of `node` for `frag`, if any.
+fn entry_at<K, V, C: HamtConfig>(node: &HAMTNode<K, V, C>, frag: u32) -> Option<&HAMTNodeEntry<K, V, C>> {
+    (frag < 1 << C::BITS && node.presence_map.contains(frag))
+        .then(|| &node.entries[get_entries_index(node.presence_map, frag)])
+}
+
+/// Copy `node`, with the entry for `frag` pointing to `child`, or removed if `child` is empty.
+fn replace_child<K, V, C: HamtConfig>(node: &HAMTNode<K, V, C>, frag: u32, child: NodePtr<K, V, C>) -> HAMTNode<K, V, C>
+where
+    K: Clone,
+    V: Clone,
+{
+    let index = get_entries_index(node.presence_map, frag);
+    if child.presence_map == C::Bitmap::EMPTY {
+        HAMTNode {
+            presence_map: node.presence_map.without(frag),
+            entries: pool::copy_entries_without(&node.entries, index),
+        }
+    } else {
+        let mut entries = pool::copy_entries(&node.entries, 0);
+        entries[index] = HAMTNodeEntry::Node(child);
+        HAMTNode {
+            presence_map: node.presence_map,
+            entries,
+        }
+    }
+}
+
+impl<K, V, C: HamtConfig> HAMT<K, V, C> {
+    /// Focus a [`Zipper`] on the root of the map.
+    pub fn zipper(&self) -> Zipper<K, V, C> {
+        Zipper {
+            path: Vec::new(),
+            focus: self.clone(),
+            modified: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::build;
+    use crate::view::EntryRef;
+    use crate::{Bitmap, HAMTNodeEntry, HashWord, NodePtr};
+
+    #[test]
+    fn navigate() {
+        let map = build(0..3000);
+        let mut zipper = map.zipper();
+        assert!(!zipper.up());
+        assert!(!zipper.next_sibling());
+        assert_eq!(zipper.presence_map(), map.root.presence_map);
+        let frags: Vec<u32> = zipper.entries().map(|(frag, _)| frag).collect();
+        assert_eq!(frags.len(), map.root.entries.len());
+        assert!(zipper.entries().all(|(_, entry)| matches!(entry, EntryRef::Node(_))));
+
+        assert!(zipper.down(frags[0]));
+        assert_eq!((zipper.level(), zipper.path()), (1, vec![frags[0]]));
+        let values = zipper.entries().filter(|(_, entry)| matches!(entry, EntryRef::Value(..))).count();
+        assert!(zipper.entries().count() > values);
+        let mut visited = vec![zipper.path()[0]];
+        while zipper.next_sibling() {
+            visited.push(zipper.path()[0]);
+        }
+        assert_eq!(visited, frags);
+        assert!(zipper.prev_sibling());
+        assert_eq!(zipper.path(), vec![frags[frags.len() - 2]]);
+        assert!(zipper.up());
+        assert!(!zipper.down(u32::MAX));
+        assert!(zipper.close().ptr_eq(&map));
+    }
+
+    #[test]
+    fn edit_under_focus() {
+        let map = build(0..3000);
+        let frag = map.hash_of(&0).fragment(5);
+        let mut zipper = map.zipper();
+        assert!(zipper.down(frag));
+        let mut expected = map.clone();
+        for k in (3000..6000).filter(|k| map.hash_of(k).fragment(5) == frag) {
+            zipper.insert(k, -k);
+            expected = expected.insert(k, -k);
+        }
+        for k in (0..3000).step_by(3) {
+            zipper.remove(k);
+            if map.hash_of(&k).fragment(5) == frag {
+                expected = expected.remove(k);
+            }
+        }
+        let kept = (1..3000).find(|k| k % 3 != 0 && map.hash_of(k).fragment(5) == frag).unwrap();
+        assert_eq!(zipper.get(0), None);
+        assert_eq!(zipper.get(kept), Some(&kept));
+        let edited = zipper.close();
+        assert_eq!(edited, expected);
+        // Only the entry that was edited under was rebuilt.
+        let edited_index = map.root.presence_map.count_below(frag);
+        for (index, (old, new)) in map.root.entries.iter().zip(edited.root.entries.iter()).enumerate() {
+            match (old, new) {
+                (HAMTNodeEntry::Node(old), HAMTNodeEntry::Node(new)) => {
+                    assert_eq!(NodePtr::ptr_eq(old, new), index != edited_index)
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "does not belong under the focus")]
+    fn insert_outside_focus() {
+        let map = build(0..100);
+        let frag = map.hash_of(&0).fragment(5);
+        let mut zipper = map.zipper();
+        assert!(zipper.down(frag));
+        let other = (1..).find(|k| map.hash_of(k).fragment(5) != frag).unwrap();
+        zipper.insert(other, 0);
+    }
+}